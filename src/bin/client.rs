@@ -1,42 +1,185 @@
-use rache::common_enums::{Request, Response};
-use rmp_serde::{Deserializer, Serializer};
-use serde::{Deserialize, Serialize};
-use structopt::StructOpt;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
-use tokio::net::TcpStream;
+use clap::{Parser, Subcommand};
+use rache::client::{Client, Transaction};
+use rache::codec::MAX_FRAME_SIZE;
+use rache::common_enums::{CompactionStrategy, Expected, Expiry, Request, Response};
+use rache::{Error, Options};
 use std::io::{self, Write};
+use std::time::Duration;
 
-#[derive(StructOpt, Debug)]
-#[structopt(name = "client")]
+/// REPL client for the rache key-value store
+#[derive(Parser, Debug)]
+#[command(name = "client", version)]
+struct Args {
+    /// Address of the rache server
+    #[arg(long, env = "RACHE_ADDR", default_value = "127.0.0.1:6666")]
+    addr: String,
+
+    /// Maximum size in bytes of a request or response frame
+    #[arg(long, env = "RACHE_MAX_FRAME_SIZE", default_value_t = MAX_FRAME_SIZE)]
+    max_frame_size: usize,
+
+    /// Seconds to wait for a response before giving up
+    #[arg(long, env = "RACHE_TIMEOUT", default_value_t = 30)]
+    timeout: u64,
+
+    /// Times a failed read, write, delete or scan is retried
+    #[arg(long, env = "RACHE_RETRIES", default_value_t = 2)]
+    retries: usize,
+
+    /// Log filter, e.g. `info` (defaults to `RUST_LOG`)
+    #[arg(long, env = "RACHE_LOG_LEVEL")]
+    log_level: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "client", no_binary_name = true)]
 struct Opt {
-    #[structopt(subcommand)]
+    #[command(subcommand)]
     cmd: Command,
 }
 
-#[derive(StructOpt, Debug)]
+#[derive(Subcommand, Debug)]
 enum Command {
-    Read { key: String },
-    Write { key: String, value: String },
-    Delete { key: String },
+    Read {
+        key: String,
+        /// Also show the version of the value
+        #[arg(long)]
+        versioned: bool,
+    },
+    /// Read several keys at once
+    Mget {
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+    Write {
+        key: String,
+        value: String,
+        /// Milliseconds after which the key expires
+        #[arg(long)]
+        ttl_ms: Option<u64>,
+    },
+    Delete {
+        key: String,
+    },
+    /// Delete every key in `start..end`
+    DeleteRange {
+        start: String,
+        end: String,
+    },
+    /// Add `delta` (default 1) to the integer value of a key
+    Incr {
+        key: String,
+        #[arg(allow_hyphen_values = true, default_value_t = 1)]
+        delta: i64,
+    },
+    /// Record a merge operand for a key
+    Merge {
+        key: String,
+        operand: String,
+    },
+    /// Expire a key in `ttl_ms` milliseconds
+    Expire {
+        key: String,
+        ttl_ms: u64,
+    },
+    /// Remove the expiry of a key
+    Persist {
+        key: String,
+    },
+    /// Show the remaining time to live of a key
+    Ttl {
+        key: String,
+    },
+    /// Write `--new`, or delete the key without it, only if the key holds
+    /// `--value` or `--version`, or is absent without either
+    Cas {
+        key: String,
+        #[arg(long, conflicts_with = "version")]
+        value: Option<String>,
+        #[arg(long)]
+        version: Option<u64>,
+        #[arg(long)]
+        new: Option<String>,
+    },
+    Scan {
+        #[arg(long)]
+        prefix: Option<String>,
+        #[arg(long)]
+        start: Option<String>,
+        #[arg(long)]
+        end: Option<String>,
+        #[arg(long, default_value_t = 100)]
+        limit: usize,
+    },
+    /// Begin a transaction
+    Begin,
+    /// Read a key in the transaction
+    TxGet {
+        key: String,
+    },
+    /// Write a key in the transaction
+    TxPut {
+        key: String,
+        value: String,
+    },
+    /// Commit the transaction
+    Commit,
+    /// Discard the transaction
+    Rollback,
+    /// Act on the column family `name` from now on
+    Use {
+        name: String,
+    },
+    /// Create a column family, options not given taking their default value
+    CreateCf {
+        name: String,
+        #[arg(long)]
+        write_buffer_size: Option<usize>,
+        #[arg(long)]
+        compaction_strategy: Option<CompactionStrategy>,
+    },
+    /// Drop a column family and every key it holds
+    DropCf {
+        name: String,
+    },
+    /// List the column families
+    ListCf,
 }
 
 impl Opt {
-    fn from_input(input: &str) -> Result<Self, structopt::clap::Error> {
-        let mut args = vec!["client"];
-        args.extend(input.split_whitespace());
-        Self::from_iter_safe(args)
+    fn from_input(input: &str) -> Result<Self, clap::Error> {
+        Self::try_parse_from(input.split_whitespace())
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut stream = TcpStream::connect("127.0.0.1:6666").await.unwrap();
+    let args = Args::parse();
+
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(level) = &args.log_level {
+        logger.parse_filters(level);
+    }
+    logger.init();
+
+    // A transaction holds on to a connection of its own
+    let base = Client::builder(&args.addr)
+        .max_connections(2)
+        .max_frame_size(args.max_frame_size)
+        .request_timeout(Duration::from_secs(args.timeout))
+        .max_retries(args.retries)
+        .connect()
+        .await?;
+    let mut client = base.clone();
+    let mut transaction: Option<Transaction> = None;
     loop {
         print!("Enter command: ");
         io::stdout().flush().unwrap();
 
         let mut input = String::new();
-        io::stdin().read_line(&mut input).unwrap();
+        if io::stdin().read_line(&mut input)? == 0 {
+            break;
+        }
         let opt = match Opt::from_input(input.trim()) {
             Ok(opt) => opt,
             Err(e) => {
@@ -45,22 +188,181 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         };
 
-        let command = match opt.cmd {
-            Command::Read { key } => Request::Read { key },
-            Command::Write { key, value } => Request::Write { key, value },
-            Command::Delete { key } => Request::Delete { key },
+        let result = match opt.cmd {
+            Command::Read { key, versioned } if versioned => client
+                .get_versioned(&key)
+                .await
+                .map(|value| println!("Response: {:?}", value)),
+            Command::Read { key, .. } => client
+                .get(&key)
+                .await
+                .map(|value| println!("Response: {:?}", value)),
+            Command::Mget { keys } => {
+                let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+                client
+                    .multi_get(&keys)
+                    .await
+                    .map(|values| println!("Response: {:?}", values))
+            }
+            Command::Write { key, value, ttl_ms } => match ttl_ms {
+                Some(ttl_ms) => {
+                    client
+                        .put_with_expiry(&key, &value, Expiry::In(ttl_ms))
+                        .await
+                }
+                None => client.put(&key, &value).await,
+            }
+            .map(|_| println!("Response: None")),
+            Command::Delete { key } => client
+                .delete(&key)
+                .await
+                .map(|_| println!("Response: None")),
+            Command::DeleteRange { start, end } => client
+                .delete_range(&start, &end)
+                .await
+                .map(|_| println!("Response: None")),
+            Command::Incr { key, delta } => client
+                .increment(&key, delta)
+                .await
+                .map(|value| println!("Response: {}", value)),
+            Command::Merge { key, operand } => client
+                .merge(&key, &operand)
+                .await
+                .map(|_| println!("Response: None")),
+            Command::Expire { key, ttl_ms } => client
+                .expire(&key, Expiry::In(ttl_ms))
+                .await
+                .map(|updated| println!("Response: {}", updated)),
+            Command::Persist { key } => client
+                .persist(&key)
+                .await
+                .map(|updated| println!("Response: {}", updated)),
+            Command::Ttl { key } => client
+                .ttl(&key)
+                .await
+                .map(|ttl| println!("Response: {:?}", ttl)),
+            Command::Cas {
+                key,
+                value,
+                version,
+                new,
+            } => {
+                let expected = match (value, version) {
+                    (Some(value), _) => Some(Expected::Value(value)),
+                    (None, Some(version)) => Some(Expected::Version(version)),
+                    (None, None) => None,
+                };
+                client
+                    .compare_and_swap(&key, expected, new.as_deref())
+                    .await
+                    .map(|outcome| println!("Response: {:?}", outcome))
+            }
+            Command::Scan {
+                prefix,
+                start,
+                end,
+                limit,
+            } => {
+                let request = Request::Scan {
+                    start,
+                    end,
+                    prefix,
+                    limit,
+                    cf: client.column_family_name().map(str::to_string),
+                };
+                client
+                    .execute(request)
+                    .await
+                    .map(|response| match response {
+                        Response::Entries(entries) => {
+                            for (key, value) in entries {
+                                println!("{} = {}", key, value);
+                            }
+                        }
+                        other => println!("Response: {:?}", other),
+                    })
+            }
+            Command::Begin if transaction.is_some() => Err(Error::InvalidArgument(
+                "a transaction is already in progress".to_string(),
+            )),
+            Command::Begin => client.begin().await.map(|begun| {
+                transaction = Some(begun);
+                println!("Response: None");
+            }),
+            Command::TxGet { key } => match transaction.as_mut() {
+                Some(transaction) => transaction
+                    .get(&key)
+                    .await
+                    .map(|value| println!("Response: {:?}", value)),
+                None => Err(no_transaction()),
+            },
+            Command::TxPut { key, value } => match transaction.as_mut() {
+                Some(transaction) => transaction
+                    .put(&key, &value)
+                    .await
+                    .map(|_| println!("Response: None")),
+                None => Err(no_transaction()),
+            },
+            Command::Commit => match transaction.take() {
+                Some(transaction) => transaction
+                    .commit()
+                    .await
+                    .map(|_| println!("Response: None")),
+                None => Err(no_transaction()),
+            },
+            Command::Rollback => match transaction.take() {
+                Some(transaction) => transaction
+                    .rollback()
+                    .await
+                    .map(|_| println!("Response: None")),
+                None => Err(no_transaction()),
+            },
+            Command::Use { name } => {
+                client = base.column_family(&name);
+                println!("Using column family {}", name);
+                Ok(())
+            }
+            Command::CreateCf {
+                name,
+                write_buffer_size,
+                compaction_strategy,
+            } => {
+                let options = if write_buffer_size.is_none() && compaction_strategy.is_none() {
+                    Ok(None)
+                } else {
+                    let mut builder = Options::builder();
+                    if let Some(bytes) = write_buffer_size {
+                        builder = builder.write_buffer_size(bytes);
+                    }
+                    if let Some(strategy) = compaction_strategy {
+                        builder = builder.compaction_strategy(strategy);
+                    }
+                    builder.build().map(Some)
+                };
+                match options {
+                    Ok(options) => client
+                        .create_column_family(&name, options)
+                        .await
+                        .map(|_| println!("Response: None")),
+                    Err(e) => Err(e),
+                }
+            }
+            Command::DropCf { name } => client
+                .drop_column_family(&name)
+                .await
+                .map(|_| println!("Response: None")),
+            Command::ListCf => client
+                .list_column_families()
+                .await
+                .map(|names| println!("Response: {:?}", names)),
         };
-
-        let mut buf = Vec::new();
-        command.serialize(&mut Serializer::new(&mut buf)).unwrap();
-        stream.write_all(&buf).await.unwrap();
-        stream.flush().await.unwrap();
-
-        let mut reader = BufReader::new(&mut stream);
-        let mut response_buf = Vec::new();
-        reader.read_buf(&mut response_buf).await.unwrap();
-        let mut de = Deserializer::new(&response_buf[..]);
-        let response: Response = Deserialize::deserialize(&mut de).unwrap();
-        println!("Response: {:?}", response);
+        if let Err(e) = result {
+            eprintln!("Error ({:?}): {}", e.code(), e);
+        }
     }
+    Ok(())
+}
+
+fn no_transaction() -> Error {
+    Error::InvalidArgument("no transaction in progress, run begin first".to_string())
 }
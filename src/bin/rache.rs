@@ -1,13 +1,267 @@
-use rache::{server::Server, storage::LSMTree};
-use env_logger;
+use clap::Parser;
+use log::{info, warn};
+use rache::{
+    codec::{ReadTimeouts, MAX_FRAME_SIZE},
+    common_enums::{CompactionStrategy, EvictionPolicy, SyncPolicy},
+    server::{Server, ServerOptions},
+    storage::builtin_merge_operator,
+    CloseOptions, Db, Options,
+};
+use std::{path::PathBuf, time::Duration};
+
+/// Rache key-value store server
+///
+/// Engine options are read from the `--config` TOML file, then overridden by
+/// environment variables and command-line flags.
+#[derive(Parser, Debug)]
+#[command(name = "rache", version)]
+struct Args {
+    /// Address to listen on
+    #[arg(long, env = "RACHE_ADDR", default_value = "127.0.0.1:6666")]
+    addr: String,
+
+    /// Also accept Redis clients (RESP2/RESP3) on this address
+    #[arg(long, env = "RACHE_RESP_ADDR")]
+    resp_addr: Option<String>,
+
+    /// Also accept memcached clients (text protocol) on this address
+    #[arg(long, env = "RACHE_MEMCACHED_ADDR")]
+    memcached_addr: Option<String>,
+
+    /// Also serve the HTTP/JSON gateway on this address
+    #[arg(long, env = "RACHE_HTTP_ADDR")]
+    http_addr: Option<String>,
+
+    /// Directory holding the Wal, SSTables and OPTIONS file
+    #[arg(long, env = "RACHE_DATA_DIR", default_value = ".")]
+    data_dir: PathBuf,
+
+    /// TOML file with engine options
+    #[arg(long, env = "RACHE_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Maximum size in bytes of a request or response frame
+    #[arg(long, env = "RACHE_MAX_FRAME_SIZE", default_value_t = MAX_FRAME_SIZE)]
+    max_frame_size: usize,
+
+    /// Maximum number of client connections open at once
+    #[arg(long, env = "RACHE_MAX_CONNECTIONS", default_value_t = 1024)]
+    max_connections: usize,
+
+    /// Maximum number of pending requests per connection before reading pauses
+    #[arg(long, env = "RACHE_MAX_IN_FLIGHT", default_value_t = 128)]
+    max_in_flight: usize,
+
+    /// Seconds a connection may stay idle before it is closed, 0 to never close it
+    #[arg(long, env = "RACHE_IDLE_TIMEOUT", default_value_t = 300)]
+    idle_timeout: u64,
+
+    /// Seconds allowed to receive a request once it started, 0 to wait forever
+    #[arg(long, env = "RACHE_READ_TIMEOUT", default_value_t = 30)]
+    read_timeout: u64,
+
+    /// Flush the MemTable to an SSTable on shutdown
+    #[arg(long, env = "RACHE_FLUSH_ON_SHUTDOWN")]
+    flush_on_shutdown: bool,
+
+    /// Seconds to wait for connections to drain on shutdown
+    #[arg(long, env = "RACHE_SHUTDOWN_TIMEOUT", default_value_t = 30)]
+    shutdown_timeout: u64,
+
+    /// Log filter, e.g. `info` or `rache=debug` (defaults to `RUST_LOG`)
+    #[arg(long, env = "RACHE_LOG_LEVEL")]
+    log_level: Option<String>,
+
+    /// Bytes buffered in the MemTable before a flush
+    #[arg(long, env = "RACHE_WRITE_BUFFER_SIZE")]
+    write_buffer_size: Option<usize>,
+
+    /// Number of level 0 SSTables that triggers a compaction
+    #[arg(long, env = "RACHE_LEVEL0_COMPACTION_TRIGGER")]
+    level0_compaction_trigger: Option<usize>,
+
+    /// Growth of the compaction trigger from one level to the next
+    #[arg(long, env = "RACHE_LEVEL_SIZE_MULTIPLIER")]
+    level_size_multiplier: Option<usize>,
+
+    /// Maximum number of levels
+    #[arg(long, env = "RACHE_MAX_LEVELS")]
+    max_levels: Option<usize>,
+
+    /// Bloom filter bits per key
+    #[arg(long, env = "RACHE_BLOOM_BITS_PER_KEY")]
+    bloom_bits_per_key: Option<usize>,
+
+    /// Bytes per SSTable data block
+    #[arg(long, env = "RACHE_BLOCK_SIZE")]
+    block_size: Option<usize>,
+
+    /// Bytes of block cache, 0 disables it
+    #[arg(long, env = "RACHE_BLOCK_CACHE_SIZE")]
+    block_cache_size: Option<usize>,
+
+    /// `never` or `always` fsync the Wal
+    #[arg(long, env = "RACHE_SYNC_POLICY")]
+    sync_policy: Option<SyncPolicy>,
+
+    /// `level-based` or `size-tiered`
+    #[arg(long, env = "RACHE_COMPACTION_STRATEGY")]
+    compaction_strategy: Option<CompactionStrategy>,
+
+    /// Approximate bytes the keys and values may take, 0 for no limit
+    #[arg(long, env = "RACHE_MAX_MEMORY")]
+    max_memory: Option<usize>,
+
+    /// `noeviction`, `allkeys-lru`, `allkeys-lfu` or `volatile-ttl`
+    #[arg(long, env = "RACHE_EVICTION_POLICY")]
+    eviction_policy: Option<EvictionPolicy>,
+
+    /// Milliseconds a transaction or batch waits for a row lock
+    #[arg(long, env = "RACHE_LOCK_TIMEOUT_MS")]
+    lock_timeout_ms: Option<u64>,
+
+    /// Merge operator folding `Merge` operands: `i64-add`, `string-append` or `max`
+    #[arg(long, env = "RACHE_MERGE_OPERATOR")]
+    merge_operator: Option<String>,
+
+    /// Key order, fixed when the database is created: `bytewise`,
+    /// `reverse-bytewise` or `decimal-u64`
+    #[arg(long, env = "RACHE_COMPARATOR")]
+    comparator: Option<String>,
+}
+
+impl Args {
+    /// Build the engine options from the config file and overrides
+    fn options(&self) -> Result<Options, rache::Error> {
+        let options = match &self.config {
+            Some(path) => Options::from_file(path)?,
+            None => Options::default(),
+        };
+        let mut builder = options.to_builder();
+        if let Some(v) = self.write_buffer_size {
+            builder = builder.write_buffer_size(v);
+        }
+        if let Some(v) = self.level0_compaction_trigger {
+            builder = builder.level0_compaction_trigger(v);
+        }
+        if let Some(v) = self.level_size_multiplier {
+            builder = builder.level_size_multiplier(v);
+        }
+        if let Some(v) = self.max_levels {
+            builder = builder.max_levels(v);
+        }
+        if let Some(v) = self.bloom_bits_per_key {
+            builder = builder.bloom_bits_per_key(v);
+        }
+        if let Some(v) = self.block_size {
+            builder = builder.block_size(v);
+        }
+        if let Some(v) = self.block_cache_size {
+            builder = builder.block_cache_size(v);
+        }
+        if let Some(v) = self.sync_policy {
+            builder = builder.sync_policy(v);
+        }
+        if let Some(v) = self.compaction_strategy {
+            builder = builder.compaction_strategy(v);
+        }
+        if let Some(v) = self.max_memory {
+            builder = builder.max_memory(v);
+        }
+        if let Some(v) = self.eviction_policy {
+            builder = builder.eviction_policy(v);
+        }
+        if let Some(v) = self.lock_timeout_ms {
+            builder = builder.lock_timeout_ms(v);
+        }
+        if let Some(v) = &self.comparator {
+            builder = builder.comparator(v);
+        }
+        builder.build()
+    }
+
+    /// Build the limits applied to client connections
+    fn server_options(&self) -> ServerOptions {
+        let seconds = |s: u64| (s > 0).then(|| Duration::from_secs(s));
+        ServerOptions {
+            max_frame_size: self.max_frame_size,
+            max_connections: self.max_connections,
+            max_in_flight_requests: self.max_in_flight.max(1),
+            timeouts: ReadTimeouts {
+                idle: seconds(self.idle_timeout),
+                read: seconds(self.read_timeout),
+            },
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init();
+    let args = Args::parse();
 
-    let lsm_tree = LSMTree::new("wal.log", "sstables", 3, 2)?;
-    let server = Server::new(lsm_tree);
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(level) = &args.log_level {
+        logger.parse_filters(level);
+    }
+    logger.init();
+
+    let db = Db::open(&args.data_dir, args.options()?)?;
+    if let Some(name) = &args.merge_operator {
+        db.set_merge_operator(builtin_merge_operator(name)?)?;
+    }
+    let server = Server::with_options(db.clone(), args.server_options());
+
+    let resp = async {
+        match &args.resp_addr {
+            Some(addr) => server.run_resp(addr).await,
+            None => Ok(()),
+        }
+    };
+    let http = async {
+        match &args.http_addr {
+            Some(addr) => server.run_http(addr).await,
+            None => Ok(()),
+        }
+    };
+    let memcached = async {
+        match &args.memcached_addr {
+            Some(addr) => server.run_memcached(addr).await,
+            None => Ok(()),
+        }
+    };
+    let signal = async {
+        shutdown_signal().await?;
+        server.shutdown();
+        Ok(())
+    };
+    tokio::try_join!(server.run(&args.addr), resp, memcached, http, signal)?;
+
+    let timeout = Duration::from_secs(args.shutdown_timeout);
+    if tokio::time::timeout(timeout, server.drained())
+        .await
+        .is_err()
+    {
+        warn!("Connections still open after {:?}, closing anyway", timeout);
+    }
+    db.close_with_options(&CloseOptions {
+        flush_memtable: args.flush_on_shutdown,
+    })?;
+    info!("Shut down cleanly");
+    Ok(())
+}
 
-    server.run("127.0.0.1:6666").await;
+/// Resolves on SIGINT or SIGTERM
+async fn shutdown_signal() -> Result<(), rache::Error> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
     Ok(())
-}
\ No newline at end of file
+}
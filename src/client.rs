@@ -0,0 +1,800 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::{debug, warn};
+use tokio::{
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::timeout,
+};
+
+use crate::{
+    codec::{write_frame, FrameReader, MAX_FRAME_SIZE},
+    common_enums::{
+        CasOutcome, Expected, Expiry, Request, RequestEnvelope, Response, ResponseEnvelope,
+        TimeToLive, Versioned,
+    },
+    error::Error,
+    storage::Options,
+};
+
+/// Async client of a rache server speaking the native protocol.
+///
+/// Requests are sent over a pool of connections, each carrying one request
+/// at a time. Idempotent requests failing with a transient error are
+/// retried on a fresh connection. A `Client` is cheap to clone and every
+/// clone shares the pool.
+///
+/// Requests act on the default column family, or on the one named by
+/// [`Client::column_family`].
+///
+/// ```no_run
+/// # async fn example() -> rache::Result<()> {
+/// let client = rache::client::Client::connect("127.0.0.1:6666").await?;
+/// client.put("greeting", "hello").await?;
+/// assert_eq!(client.get("greeting").await?.as_deref(), Some("hello"));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Client {
+    inner: Arc<Inner>,
+    /// Column family of the requests, the default one if `None`
+    cf: Option<String>,
+}
+
+struct Inner {
+    addr: String,
+    options: ClientOptions,
+    idle: Mutex<Vec<Connection>>,
+    /// Bounds the number of open connections
+    permits: Arc<Semaphore>,
+}
+
+/// Tunables of a [`Client`]
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// Maximum number of open connections
+    pub max_connections: usize,
+    /// Time allowed to establish a connection
+    pub connect_timeout: Duration,
+    /// Time allowed for a request to be answered, including the wait for a
+    /// free connection
+    pub request_timeout: Duration,
+    /// Number of times an idempotent request is retried after a transient error
+    pub max_retries: usize,
+    /// Delay before the first retry, doubled on every further retry
+    pub retry_backoff: Duration,
+    /// Maximum size in bytes of a request or response frame
+    pub max_frame_size: usize,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            max_connections: 8,
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(30),
+            max_retries: 2,
+            retry_backoff: Duration::from_millis(50),
+            max_frame_size: MAX_FRAME_SIZE,
+        }
+    }
+}
+
+/// Builder for a [`Client`]
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    addr: String,
+    options: ClientOptions,
+}
+
+impl ClientBuilder {
+    pub fn max_connections(mut self, connections: usize) -> Self {
+        self.options.max_connections = connections;
+        self
+    }
+
+    pub fn connect_timeout(mut self, duration: Duration) -> Self {
+        self.options.connect_timeout = duration;
+        self
+    }
+
+    pub fn request_timeout(mut self, duration: Duration) -> Self {
+        self.options.request_timeout = duration;
+        self
+    }
+
+    pub fn max_retries(mut self, retries: usize) -> Self {
+        self.options.max_retries = retries;
+        self
+    }
+
+    pub fn retry_backoff(mut self, duration: Duration) -> Self {
+        self.options.retry_backoff = duration;
+        self
+    }
+
+    pub fn max_frame_size(mut self, bytes: usize) -> Self {
+        self.options.max_frame_size = bytes;
+        self
+    }
+
+    /// Connect to the server, failing early if it cannot be reached
+    pub async fn connect(self) -> Result<Client, Error> {
+        if self.options.max_connections == 0 {
+            return Err(Error::InvalidArgument(
+                "max_connections must be positive".to_string(),
+            ));
+        }
+        let connection = Connection::open(&self.addr, &self.options).await?;
+        Ok(Client {
+            inner: Arc::new(Inner {
+                permits: Arc::new(Semaphore::new(self.options.max_connections)),
+                idle: Mutex::new(vec![connection]),
+                addr: self.addr,
+                options: self.options,
+            }),
+            cf: None,
+        })
+    }
+}
+
+impl Client {
+    /// Connect to the server at `addr` with the default options
+    pub async fn connect(addr: &str) -> Result<Client, Error> {
+        Self::builder(addr).connect().await
+    }
+
+    /// Start building a client of the server at `addr`
+    pub fn builder(addr: &str) -> ClientBuilder {
+        ClientBuilder {
+            addr: addr.to_string(),
+            options: ClientOptions::default(),
+        }
+    }
+
+    pub fn options(&self) -> &ClientOptions {
+        &self.inner.options
+    }
+
+    /// A client sharing this client's pool whose requests act on the column
+    /// family `name`. Requests fail with [`Error::NotFound`] if it does not
+    /// exist.
+    pub fn column_family(&self, name: &str) -> Client {
+        Client {
+            inner: Arc::clone(&self.inner),
+            cf: Some(name.to_string()),
+        }
+    }
+
+    /// Column family of the requests, `None` for the default one
+    pub fn column_family_name(&self) -> Option<&str> {
+        self.cf.as_deref()
+    }
+
+    /// Create a column family, tuned by `options` or else by the options
+    /// of the server's database. Not retried, creating it again fails.
+    pub async fn create_column_family(
+        &self,
+        name: &str,
+        options: Option<Options>,
+    ) -> Result<(), Error> {
+        let request = Request::CreateColumnFamily {
+            name: name.to_string(),
+            options,
+        };
+        self.execute(request).await.map(|_| ())
+    }
+
+    /// Drop a column family and every key it holds
+    pub async fn drop_column_family(&self, name: &str) -> Result<(), Error> {
+        let request = Request::DropColumnFamily {
+            name: name.to_string(),
+        };
+        self.execute(request).await.map(|_| ())
+    }
+
+    /// Names of the column families, the default one included
+    pub async fn list_column_families(&self) -> Result<Vec<String>, Error> {
+        match self.execute(Request::ListColumnFamilies).await? {
+            Response::Names(names) => Ok(names),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Read the value of a key
+    pub async fn get(&self, key: &str) -> Result<Option<String>, Error> {
+        let request = Request::Read {
+            key: key.to_string(),
+            cf: self.cf.clone(),
+        };
+        match self.execute(request).await? {
+            Response::Success(value) => Ok(value),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Read the values of several keys in a single round trip, in the order
+    /// of `keys`
+    pub async fn multi_get(&self, keys: &[&str]) -> Result<Vec<Option<String>>, Error> {
+        let request = Request::MultiGet {
+            keys: keys.iter().map(|key| key.to_string()).collect(),
+            cf: self.cf.clone(),
+        };
+        match self.execute(request).await? {
+            Response::Values(values) => Ok(values),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Read the value of a key and the version of the write that stored it
+    pub async fn get_versioned(&self, key: &str) -> Result<Option<Versioned>, Error> {
+        let request = Request::ReadVersioned {
+            key: key.to_string(),
+            cf: self.cf.clone(),
+        };
+        match self.execute(request).await? {
+            Response::Versioned(value) => Ok(value),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Write the value of a key
+    pub async fn put(&self, key: &str, value: &str) -> Result<(), Error> {
+        let request = Request::Write {
+            key: key.to_string(),
+            value: value.to_string(),
+            expiry: None,
+            cf: self.cf.clone(),
+        };
+        self.execute(request).await.map(|_| ())
+    }
+
+    /// Write the value of a key, which expires at `expiry`
+    pub async fn put_with_expiry(
+        &self,
+        key: &str,
+        value: &str,
+        expiry: Expiry,
+    ) -> Result<(), Error> {
+        let request = Request::Write {
+            key: key.to_string(),
+            value: value.to_string(),
+            expiry: Some(expiry),
+            cf: self.cf.clone(),
+        };
+        self.execute(request).await.map(|_| ())
+    }
+
+    /// Write `new`, or delete the key if `None`, only if the key matches
+    /// `expected`, or is absent if `None`. Not retried, as a retry of a
+    /// swap that went through reports a conflict.
+    pub async fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<Expected>,
+        new: Option<&str>,
+    ) -> Result<CasOutcome, Error> {
+        let request = Request::CompareAndSwap {
+            key: key.to_string(),
+            expected,
+            new: new.map(str::to_string),
+            cf: self.cf.clone(),
+        };
+        match self.execute(request).await? {
+            Response::CompareAndSwap(outcome) => Ok(outcome),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Set the value of a key only if the key does not exist
+    pub async fn put_if_absent(&self, key: &str, value: &str) -> Result<CasOutcome, Error> {
+        self.compare_and_swap(key, None, Some(value)).await
+    }
+
+    /// Delete a key only if it holds `value`
+    pub async fn delete_if_equal(&self, key: &str, value: &str) -> Result<CasOutcome, Error> {
+        let expected = Expected::Value(value.to_string());
+        self.compare_and_swap(key, Some(expected), None).await
+    }
+
+    /// Set the expiry of a key, returning whether the key exists
+    pub async fn expire(&self, key: &str, expiry: Expiry) -> Result<bool, Error> {
+        let request = Request::Expire {
+            key: key.to_string(),
+            expiry,
+            cf: self.cf.clone(),
+        };
+        self.updated_request(request).await
+    }
+
+    /// Remove the expiry of a key, returning whether the key had one
+    pub async fn persist(&self, key: &str) -> Result<bool, Error> {
+        let request = Request::Persist {
+            key: key.to_string(),
+            cf: self.cf.clone(),
+        };
+        self.updated_request(request).await
+    }
+
+    /// Get the remaining time to live of a key
+    pub async fn ttl(&self, key: &str) -> Result<TimeToLive, Error> {
+        let request = Request::Ttl {
+            key: key.to_string(),
+            cf: self.cf.clone(),
+        };
+        match self.execute(request).await? {
+            Response::Ttl(ttl) => Ok(ttl),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    async fn updated_request(&self, request: Request) -> Result<bool, Error> {
+        match self.execute(request).await? {
+            Response::Updated(updated) => Ok(updated),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Delete every key in `start..end`
+    pub async fn delete_range(&self, start: &str, end: &str) -> Result<(), Error> {
+        let request = Request::DeleteRange {
+            start: start.to_string(),
+            end: end.to_string(),
+            cf: self.cf.clone(),
+        };
+        self.execute(request).await.map(|_| ())
+    }
+
+    /// Add `delta` to the integer value of a key, a missing key counting as
+    /// 0, and return the new value. Not retried, an increment applied twice
+    /// differs.
+    pub async fn increment(&self, key: &str, delta: i64) -> Result<i64, Error> {
+        let request = Request::Incr {
+            key: key.to_string(),
+            delta,
+            cf: self.cf.clone(),
+        };
+        match self.execute(request).await? {
+            Response::Integer(value) => Ok(value),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Delete a key
+    pub async fn delete(&self, key: &str) -> Result<(), Error> {
+        let request = Request::Delete {
+            key: key.to_string(),
+            cf: self.cf.clone(),
+        };
+        self.execute(request).await.map(|_| ())
+    }
+
+    /// Record a merge operand for a key, folded onto its value by the
+    /// server's merge operator. Not retried, a merge applied twice differs.
+    pub async fn merge(&self, key: &str, operand: &str) -> Result<(), Error> {
+        let request = Request::Merge {
+            key: key.to_string(),
+            operand: operand.to_string(),
+            cf: self.cf.clone(),
+        };
+        self.execute(request).await.map(|_| ())
+    }
+
+    /// Scan at most `limit` pairs with `start <= key < end`, in key order
+    pub async fn scan(
+        &self,
+        start: Option<&str>,
+        end: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>, Error> {
+        self.scan_request(Request::Scan {
+            start: start.map(str::to_string),
+            end: end.map(str::to_string),
+            prefix: None,
+            limit,
+            cf: self.cf.clone(),
+        })
+        .await
+    }
+
+    /// Scan at most `limit` pairs whose key starts with `prefix`, in key order
+    pub async fn scan_prefix(
+        &self,
+        prefix: &str,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>, Error> {
+        self.scan_request(Request::Scan {
+            start: None,
+            end: None,
+            prefix: Some(prefix.to_string()),
+            limit,
+            cf: self.cf.clone(),
+        })
+        .await
+    }
+
+    async fn scan_request(&self, request: Request) -> Result<Vec<(String, String)>, Error> {
+        match self.execute(request).await? {
+            Response::Entries(entries) => Ok(entries),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Execute several requests in order in a single round trip.
+    /// Every request gets its own result.
+    pub async fn batch(
+        &self,
+        requests: Vec<Request>,
+    ) -> Result<Vec<Result<Response, Error>>, Error> {
+        match self.execute(Request::Batch { requests }).await? {
+            Response::Batch(responses) => Ok(responses
+                .into_iter()
+                .map(|response| match response {
+                    Response::Error { code, message } => Err(Error::from_wire(code, message)),
+                    response => Ok(response),
+                })
+                .collect()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Begin an optimistic transaction on a connection of the pool, which
+    /// the transaction holds on to until it is committed or rolled back
+    pub async fn begin(&self) -> Result<Transaction, Error> {
+        let request_timeout = self.inner.options.request_timeout;
+        let (permit, connection) = timeout(request_timeout, async {
+            let permit = Arc::clone(&self.inner.permits)
+                .acquire_owned()
+                .await
+                .map_err(|_| Error::ShuttingDown)?;
+            Ok::<_, Error>((permit, self.checkout().await?))
+        })
+        .await
+        .map_err(|_| Error::Timeout(format!("no connection within {:?}", request_timeout)))??;
+        let mut transaction = Transaction {
+            client: self.clone(),
+            connection,
+            _permit: permit,
+        };
+        let request = Request::TxBegin {
+            cf: self.cf.clone(),
+        };
+        transaction.send(request).await?;
+        Ok(transaction)
+    }
+
+    /// Execute a request, retrying it if it is idempotent and fails with a
+    /// transient error. Error responses are returned as `Err`.
+    pub async fn execute(&self, request: Request) -> Result<Response, Error> {
+        let options = &self.inner.options;
+        let retries = if request.is_idempotent() {
+            options.max_retries
+        } else {
+            0
+        };
+        let mut backoff = options.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            match self.execute_once(&request).await {
+                Err(e) if e.is_transient() && attempt < retries => {
+                    attempt += 1;
+                    warn!("Request failed ({}), retry {} of {}", e, attempt, retries);
+                    tokio::time::sleep(backoff).await;
+                    backoff = backoff.saturating_mul(2);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn execute_once(&self, request: &Request) -> Result<Response, Error> {
+        let request_timeout = self.inner.options.request_timeout;
+        let response = timeout(request_timeout, async {
+            let _permit = self
+                .inner
+                .permits
+                .acquire()
+                .await
+                .map_err(|_| Error::ShuttingDown)?;
+            let mut connection = self.checkout().await?;
+            // A connection failing mid-request is dropped rather than reused,
+            // as is one abandoned by the timeout
+            let response = connection.send(request, &self.inner.options).await?;
+            self.checkin(connection);
+            Ok::<_, Error>(response)
+        })
+        .await
+        .map_err(|_| Error::Timeout(format!("no response within {:?}", request_timeout)))??;
+
+        match response {
+            Response::Error { code, message } => Err(Error::from_wire(code, message)),
+            response => Ok(response),
+        }
+    }
+
+    async fn checkout(&self) -> Result<Connection, Error> {
+        let idle = self
+            .inner
+            .idle
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .pop();
+        match idle {
+            Some(connection) => Ok(connection),
+            None => Connection::open(&self.inner.addr, &self.inner.options).await,
+        }
+    }
+
+    fn checkin(&self, connection: Connection) {
+        self.inner
+            .idle
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(connection);
+    }
+}
+
+/// An optimistic transaction bound to a connection of a [`Client`].
+///
+/// Reads see a snapshot taken when the transaction began, along with its
+/// own writes, which are buffered by the server until the commit. Dropping
+/// the transaction closes its connection, which rolls it back.
+pub struct Transaction {
+    client: Client,
+    connection: Connection,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Transaction {
+    /// Read the value of a key
+    pub async fn get(&mut self, key: &str) -> Result<Option<String>, Error> {
+        let request = Request::TxGet {
+            key: key.to_string(),
+        };
+        match self.send(request).await? {
+            Response::Success(value) => Ok(value),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Set the value of a key on commit. An empty value deletes the key.
+    pub async fn put(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        let request = Request::TxPut {
+            key: key.to_string(),
+            value: value.to_string(),
+        };
+        self.send(request).await.map(|_| ())
+    }
+
+    /// Delete a key on commit
+    pub async fn delete(&mut self, key: &str) -> Result<(), Error> {
+        self.put(key, "").await
+    }
+
+    /// Apply the writes of the transaction. Fails with [`Error::Conflict`],
+    /// writing nothing, if a key it read was modified since it began.
+    pub async fn commit(self) -> Result<(), Error> {
+        self.finish(Request::TxCommit).await
+    }
+
+    /// Discard the writes of the transaction
+    pub async fn rollback(self) -> Result<(), Error> {
+        self.finish(Request::TxRollback).await
+    }
+
+    /// End the transaction, returning its connection to the pool
+    async fn finish(mut self, request: Request) -> Result<(), Error> {
+        // A connection failing mid-request is dropped rather than reused
+        let response = self.exchange(request).await?;
+        self.client.checkin(self.connection);
+        match response {
+            Response::Error { code, message } => Err(Error::from_wire(code, message)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Send a request, error responses are returned as `Err`
+    async fn send(&mut self, request: Request) -> Result<Response, Error> {
+        match self.exchange(request).await? {
+            Response::Error { code, message } => Err(Error::from_wire(code, message)),
+            response => Ok(response),
+        }
+    }
+
+    async fn exchange(&mut self, request: Request) -> Result<Response, Error> {
+        let options = &self.client.inner.options;
+        timeout(
+            options.request_timeout,
+            self.connection.send(&request, options),
+        )
+        .await
+        .map_err(|_| Error::Timeout(format!("no response within {:?}", options.request_timeout)))?
+    }
+}
+
+/// A connection of the pool
+struct Connection {
+    frames: FrameReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    next_id: u64,
+}
+
+impl Connection {
+    async fn open(addr: &str, options: &ClientOptions) -> Result<Self, Error> {
+        let socket = timeout(options.connect_timeout, TcpStream::connect(addr))
+            .await
+            .map_err(|_| Error::Timeout(format!("connecting to {}", addr)))??;
+        socket.set_nodelay(true)?;
+        debug!("Connected to {}", addr);
+        let (reader, writer) = socket.into_split();
+        Ok(Connection {
+            frames: FrameReader::new(reader, options.max_frame_size),
+            writer,
+            next_id: 0,
+        })
+    }
+
+    /// Send a request and wait for its response
+    async fn send(
+        &mut self,
+        request: &Request,
+        options: &ClientOptions,
+    ) -> Result<Response, Error> {
+        self.next_id += 1;
+        let envelope = RequestEnvelope {
+            id: self.next_id,
+            request: request.clone(),
+        };
+        write_frame(&mut self.writer, &envelope, options.max_frame_size).await?;
+        match self.frames.read_message::<ResponseEnvelope>().await? {
+            Some(ResponseEnvelope { id, response }) if id == self.next_id => Ok(response),
+            // Responses to malformed frames carry id 0
+            Some(ResponseEnvelope {
+                id: 0,
+                response: Response::Error { code, message },
+            }) => Err(Error::from_wire(code, message)),
+            Some(ResponseEnvelope { id, .. }) => Err(Error::ProtocolError(format!(
+                "response for request {} while waiting for {}",
+                id, self.next_id
+            ))),
+            None => Err(Error::IoError(std::io::Error::new(
+                std::io::ErrorKind::ConnectionReset,
+                "server closed the connection",
+            ))),
+        }
+    }
+}
+
+fn unexpected(response: Response) -> Error {
+    Error::ProtocolError(format!("unexpected response {:?}", response))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// What the scripted server does with a request
+    enum Action {
+        Respond(Response),
+        /// Close the connection without responding
+        Close,
+        /// Never respond
+        Hang,
+    }
+
+    /// A server answering requests with the next action of `script`,
+    /// closing connections once it runs out. Returns its address and the
+    /// number of connections it accepted.
+    async fn scripted_server(script: Vec<Action>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let script = Arc::new(Mutex::new(VecDeque::from(script)));
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&accepted);
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let script = Arc::clone(&script);
+                tokio::spawn(async move {
+                    let (reader, mut writer) = socket.into_split();
+                    let mut frames = FrameReader::new(reader, MAX_FRAME_SIZE);
+                    while let Ok(Some(envelope)) = frames.read_message::<RequestEnvelope>().await {
+                        let action = script.lock().unwrap().pop_front();
+                        match action {
+                            Some(Action::Respond(response)) => {
+                                let envelope = ResponseEnvelope {
+                                    id: envelope.id,
+                                    response,
+                                };
+                                write_frame(&mut writer, &envelope, MAX_FRAME_SIZE)
+                                    .await
+                                    .unwrap();
+                            }
+                            Some(Action::Hang) => std::future::pending::<()>().await,
+                            Some(Action::Close) | None => return,
+                        }
+                    }
+                });
+            }
+        });
+        (addr, accepted)
+    }
+
+    #[tokio::test]
+    async fn idempotent_requests_are_retried_on_a_fresh_connection() {
+        let value = Response::Success(Some("v".to_string()));
+        let (addr, accepted) = scripted_server(vec![Action::Close, Action::Respond(value)]).await;
+        let client = Client::builder(&addr)
+            .retry_backoff(Duration::from_millis(1))
+            .connect()
+            .await
+            .unwrap();
+        assert_eq!(client.get("key").await.unwrap().as_deref(), Some("v"));
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+        // Increments are not retried, the script runs out after one close
+        let (addr, _) =
+            scripted_server(vec![Action::Close, Action::Respond(Response::Integer(1))]).await;
+        let client = Client::connect(&addr).await.unwrap();
+        let result = client.increment("counter", 1).await;
+        assert!(matches!(result, Err(Error::IoError(_))), "{:?}", result);
+        assert_eq!(client.increment("counter", 1).await.unwrap(), 1);
+
+        // Retries give up after `max_retries`
+        let (addr, accepted) = scripted_server(vec![]).await;
+        let client = Client::builder(&addr)
+            .max_retries(2)
+            .retry_backoff(Duration::from_millis(1))
+            .connect()
+            .await
+            .unwrap();
+        let result = client.get("key").await;
+        assert!(matches!(result, Err(Error::IoError(_))), "{:?}", result);
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn requests_time_out_waiting_for_a_response_or_a_connection() {
+        let (addr, accepted) = scripted_server(vec![
+            Action::Hang,
+            Action::Respond(Response::Success(None)),
+            Action::Respond(Response::Success(None)),
+        ])
+        .await;
+        let client = Client::builder(&addr)
+            .max_connections(1)
+            .max_retries(0)
+            .request_timeout(Duration::from_millis(100))
+            .connect()
+            .await
+            .unwrap();
+        let result = client.get("key").await;
+        assert!(matches!(result, Err(Error::Timeout(_))), "{:?}", result);
+
+        // The abandoned connection is replaced, then reused
+        client.put("key", "value").await.unwrap();
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+        // A transaction holds the only connection until it ends
+        let transaction = client.begin().await.unwrap();
+        let result = client.get("key").await;
+        assert!(matches!(result, Err(Error::Timeout(_))), "{:?}", result);
+        drop(transaction);
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+}
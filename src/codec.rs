@@ -0,0 +1,271 @@
+use rmp_serde::{Deserializer, Serializer};
+use serde::{de::DeserializeOwned, Serialize};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::Instant,
+};
+
+use crate::error::Error;
+
+/// Default maximum size of a frame payload
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Size of the frame header: payload length as a big-endian u32
+const FRAME_HEADER_SIZE: usize = 4;
+
+/// Encode a message into a length-prefixed MessagePack frame
+pub fn encode_frame<T: Serialize>(message: &T, max_frame_size: usize) -> Result<Vec<u8>, Error> {
+    let mut frame = vec![0u8; FRAME_HEADER_SIZE];
+    message.serialize(&mut Serializer::new(&mut frame))?;
+    let len = frame.len() - FRAME_HEADER_SIZE;
+    if len > max_frame_size {
+        return Err(Error::InvalidArgument(format!(
+            "message of {} bytes exceeds the maximum frame size of {} bytes",
+            len, max_frame_size
+        )));
+    }
+    frame[..FRAME_HEADER_SIZE].copy_from_slice(&(len as u32).to_be_bytes());
+    Ok(frame)
+}
+
+/// Decode the payload of a frame
+pub fn decode_payload<T: DeserializeOwned>(payload: &[u8]) -> Result<T, Error> {
+    let mut de = Deserializer::new(payload);
+    Ok(T::deserialize(&mut de)?)
+}
+
+/// Write a message as a single frame
+pub async fn write_frame<W, T>(
+    writer: &mut W,
+    message: &T,
+    max_frame_size: usize,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let frame = encode_frame(message, max_frame_size)?;
+    writer.write_all(&frame).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Limits on how long a peer may take to send its messages
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReadTimeouts {
+    /// Time allowed between two messages, `None` waits forever
+    pub idle: Option<Duration>,
+    /// Time allowed to receive a whole message once its first bytes
+    /// arrived, `None` waits forever
+    pub read: Option<Duration>,
+}
+
+/// Applies [`ReadTimeouts`] to the reads of a message reader
+#[derive(Debug, Default)]
+pub(crate) struct ReadDeadline {
+    timeouts: ReadTimeouts,
+    /// When the first bytes of the pending message were seen
+    message_start: Option<Instant>,
+}
+
+impl ReadDeadline {
+    pub(crate) fn new(timeouts: ReadTimeouts) -> Self {
+        ReadDeadline {
+            timeouts,
+            message_start: None,
+        }
+    }
+
+    /// Forget the message that was just received
+    pub(crate) fn message_done(&mut self) {
+        self.message_start = None;
+    }
+
+    /// Read more bytes into `buffer`. `pending` tells whether part of a
+    /// message is already buffered.
+    pub(crate) async fn read_buf<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+        buffer: &mut Vec<u8>,
+        pending: bool,
+    ) -> Result<usize, Error> {
+        let deadline = if pending {
+            let start = *self.message_start.get_or_insert_with(Instant::now);
+            self.timeouts
+                .read
+                .map(|t| (start + t, format!("message not received within {:?}", t)))
+        } else {
+            self.message_start = None;
+            self.timeouts
+                .idle
+                .map(|t| (Instant::now() + t, format!("idle for {:?}", t)))
+        };
+        match deadline {
+            None => Ok(reader.read_buf(buffer).await?),
+            Some((deadline, msg)) => tokio::time::timeout_at(deadline, reader.read_buf(buffer))
+                .await
+                .map_err(|_| Error::Timeout(msg))?
+                .map_err(Error::from),
+        }
+    }
+}
+
+/// Reads length-prefixed frames from a stream.
+///
+/// Bytes read past the end of a frame are kept for the next one, so a frame
+/// may span several reads and a read may hold several frames.
+pub struct FrameReader<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    max_frame_size: usize,
+    deadline: ReadDeadline,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(reader: R, max_frame_size: usize) -> Self {
+        FrameReader {
+            reader,
+            buffer: Vec::new(),
+            max_frame_size,
+            deadline: ReadDeadline::default(),
+        }
+    }
+
+    /// Fail reads that take longer than `timeouts`
+    pub fn with_timeouts(mut self, timeouts: ReadTimeouts) -> Self {
+        self.deadline = ReadDeadline::new(timeouts);
+        self
+    }
+
+    /// Read the payload of the next frame.
+    /// Returns `None` when the stream is closed between two frames.
+    pub async fn read_frame(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        loop {
+            if let Some(payload) = self.take_frame()? {
+                self.deadline.message_done();
+                return Ok(Some(payload));
+            }
+            let pending = !self.buffer.is_empty();
+            if self
+                .deadline
+                .read_buf(&mut self.reader, &mut self.buffer, pending)
+                .await?
+                == 0
+            {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Err(Error::ProtocolError(format!(
+                    "connection closed in the middle of a frame ({} bytes pending)",
+                    self.buffer.len()
+                )));
+            }
+        }
+    }
+
+    /// Read and decode the next frame
+    pub async fn read_message<T: DeserializeOwned>(&mut self) -> Result<Option<T>, Error> {
+        match self.read_frame().await? {
+            Some(payload) => decode_payload(&payload).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Split the first complete frame off the buffer
+    fn take_frame(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        if self.buffer.len() < FRAME_HEADER_SIZE {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes(self.buffer[..FRAME_HEADER_SIZE].try_into().unwrap()) as usize;
+        if len > self.max_frame_size {
+            return Err(Error::ProtocolError(format!(
+                "frame of {} bytes exceeds the maximum frame size of {} bytes",
+                len, self.max_frame_size
+            )));
+        }
+        if self.buffer.len() < FRAME_HEADER_SIZE + len {
+            self.buffer
+                .reserve(FRAME_HEADER_SIZE + len - self.buffer.len());
+            return Ok(None);
+        }
+        let payload = self.buffer[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + len].to_vec();
+        self.buffer.drain(..FRAME_HEADER_SIZE + len);
+        Ok(Some(payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(messages: &[&str]) -> Vec<u8> {
+        messages
+            .iter()
+            .flat_map(|message| encode_frame(message, MAX_FRAME_SIZE).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn reads_several_frames_from_one_read() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client
+            .write_all(&frames(&["a", "bc", "def"]))
+            .await
+            .unwrap();
+        drop(client);
+
+        let mut reader = FrameReader::new(server, MAX_FRAME_SIZE);
+        for expected in ["a", "bc", "def"] {
+            let message: String = reader.read_message().await.unwrap().unwrap();
+            assert_eq!(message, expected);
+        }
+        assert!(reader.read_frame().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn reads_frames_split_across_reads() {
+        // A 3 byte pipe splits headers and payloads over several reads
+        let (mut client, server) = tokio::io::duplex(3);
+        let long = "x".repeat(100);
+        let bytes = frames(&[&long, "y"]);
+        tokio::spawn(async move { client.write_all(&bytes).await.unwrap() });
+
+        let mut reader = FrameReader::new(server, MAX_FRAME_SIZE);
+        let message: String = reader.read_message().await.unwrap().unwrap();
+        assert_eq!(message, long);
+        let message: String = reader.read_message().await.unwrap().unwrap();
+        assert_eq!(message, "y");
+        assert!(reader.read_frame().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_and_truncated_frames() {
+        let message = "x".repeat(100);
+        let result = encode_frame(&message, 10);
+        assert!(matches!(result, Err(Error::InvalidArgument(_))));
+
+        // Rejected from the header, before the payload is buffered
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(&(1u32 << 30).to_be_bytes()).await.unwrap();
+        let mut reader = FrameReader::new(server, MAX_FRAME_SIZE);
+        let result = reader.read_frame().await;
+        assert!(
+            matches!(result, Err(Error::ProtocolError(_))),
+            "{:?}",
+            result
+        );
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let frame = frames(&["abc"]);
+        client.write_all(&frame[..frame.len() - 1]).await.unwrap();
+        drop(client);
+        let mut reader = FrameReader::new(server, MAX_FRAME_SIZE);
+        let result = reader.read_frame().await;
+        assert!(
+            matches!(result, Err(Error::ProtocolError(_))),
+            "{:?}",
+            result
+        );
+    }
+}
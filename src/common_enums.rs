@@ -1,20 +1,419 @@
 use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+use crate::storage::Options;
+
+/// Requests of the native protocol. Requests on keys act on the column
+/// family named by their `cf`, the default one if `None`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub enum Request {
-    Read { key: String },
-    Write { key: String, value: String },
-    Delete { key: String },
+    Read {
+        key: String,
+        #[serde(default)]
+        cf: Option<String>,
+    },
+    /// Read several keys at once, answered with `Response::Values` in the
+    /// order of `keys`
+    MultiGet {
+        keys: Vec<String>,
+        #[serde(default)]
+        cf: Option<String>,
+    },
+    /// Set the value of a key, expiring it at `expiry` if set
+    Write {
+        key: String,
+        value: String,
+        #[serde(default)]
+        expiry: Option<Expiry>,
+        #[serde(default)]
+        cf: Option<String>,
+    },
+    Delete {
+        key: String,
+        #[serde(default)]
+        cf: Option<String>,
+    },
+    /// Delete every key in `start..end`. Like scans, range deletions wait
+    /// for the earlier requests of a connection, and the later ones wait
+    /// for them.
+    DeleteRange {
+        start: String,
+        end: String,
+        #[serde(default)]
+        cf: Option<String>,
+    },
+    /// Record a merge operand, folded onto the value of the key by the
+    /// server's merge operator
+    Merge {
+        key: String,
+        operand: String,
+        #[serde(default)]
+        cf: Option<String>,
+    },
+    /// Add `delta` to the integer value of a key, a missing key counting as
+    /// 0. Answered with `Response::Integer` holding the new value.
+    Incr {
+        key: String,
+        delta: i64,
+        #[serde(default)]
+        cf: Option<String>,
+    },
+    /// Set the expiry of an existing key, answered with `Response::Updated`
+    Expire {
+        key: String,
+        expiry: Expiry,
+        #[serde(default)]
+        cf: Option<String>,
+    },
+    /// Remove the expiry of a key, answered with `Response::Updated`
+    Persist {
+        key: String,
+        #[serde(default)]
+        cf: Option<String>,
+    },
+    /// Get the remaining time to live of a key, answered with `Response::Ttl`
+    Ttl {
+        key: String,
+        #[serde(default)]
+        cf: Option<String>,
+    },
+    /// Read a value and its version, answered with `Response::Versioned`
+    ReadVersioned {
+        key: String,
+        #[serde(default)]
+        cf: Option<String>,
+    },
+    /// Write `new`, or delete the key if `None`, only if the key matches
+    /// `expected`, or is absent if `None`. Answered with
+    /// `Response::CompareAndSwap`.
+    CompareAndSwap {
+        key: String,
+        expected: Option<Expected>,
+        new: Option<String>,
+        #[serde(default)]
+        cf: Option<String>,
+    },
+    /// Key-value pairs with keys in `start..end` starting with `prefix`
+    Scan {
+        start: Option<String>,
+        end: Option<String>,
+        prefix: Option<String>,
+        limit: usize,
+        #[serde(default)]
+        cf: Option<String>,
+    },
+    /// Requests executed one after the other, answered with `Response::Batch`
+    Batch { requests: Vec<Request> },
+    /// Begin an optimistic transaction bound to the connection, reading
+    /// from a snapshot taken now. Only native connections hold transactions,
+    /// which read and write a single column family.
+    TxBegin {
+        #[serde(default)]
+        cf: Option<String>,
+    },
+    /// Read a key in the transaction of the connection
+    TxGet { key: String },
+    /// Buffer a write in the transaction of the connection. An empty value
+    /// deletes the key.
+    TxPut { key: String, value: String },
+    /// Apply the writes of the transaction of the connection, failing with
+    /// `ErrorCode::Conflict` if a key it read was modified since it began
+    TxCommit,
+    /// Discard the transaction of the connection
+    TxRollback,
+    /// Create a column family, tuned by `options` or else by the options
+    /// of the database
+    CreateColumnFamily {
+        name: String,
+        #[serde(default)]
+        options: Option<Options>,
+    },
+    /// Drop a column family and every key it holds
+    DropColumnFamily { name: String },
+    /// Names of the column families, answered with `Response::Names`
+    ListColumnFamilies,
+}
+
+impl Request {
+    /// Keys the request touches. Requests sharing a key are executed in the
+    /// order they were sent on a connection. Barriers, see
+    /// [`Request::is_barrier`], touch no listed key.
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            // Keys of different column families are not told apart, which
+            // only orders a few more requests than needed
+            Request::Read { key, .. }
+            | Request::Write { key, .. }
+            | Request::Delete { key, .. }
+            | Request::Merge { key, .. }
+            | Request::Incr { key, .. }
+            | Request::Expire { key, .. }
+            | Request::Persist { key, .. }
+            | Request::Ttl { key, .. }
+            | Request::ReadVersioned { key, .. }
+            | Request::CompareAndSwap { key, .. } => vec![key.as_str()],
+            Request::MultiGet { keys, .. } => keys.iter().map(String::as_str).collect(),
+            Request::Scan { .. }
+            | Request::DeleteRange { .. }
+            | Request::CreateColumnFamily { .. }
+            | Request::DropColumnFamily { .. }
+            | Request::ListColumnFamilies => Vec::new(),
+            // Transaction requests are ordered with each other through the
+            // empty key, which no other request may use
+            Request::TxBegin { .. }
+            | Request::TxGet { .. }
+            | Request::TxPut { .. }
+            | Request::TxCommit
+            | Request::TxRollback => vec![""],
+            Request::Batch { requests } => {
+                // A batch may touch a key several times, list it once
+                let mut keys: Vec<&str> = requests.iter().flat_map(Request::keys).collect();
+                keys.sort_unstable();
+                keys.dedup();
+                keys
+            }
+        }
+    }
+
+    /// Whether the request is ordered with every request of its connection,
+    /// as it touches keys it cannot list: it waits for the earlier requests,
+    /// and the later ones wait for it
+    pub fn is_barrier(&self) -> bool {
+        match self {
+            Request::Scan { .. }
+            | Request::DeleteRange { .. }
+            | Request::CreateColumnFamily { .. }
+            | Request::DropColumnFamily { .. }
+            | Request::ListColumnFamilies => true,
+            Request::Batch { requests } => requests.iter().any(Request::is_barrier),
+            _ => false,
+        }
+    }
+
+    /// Whether executing the request twice has the same effect as executing
+    /// it once, so that it can be retried safely
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            Request::Read { .. }
+            | Request::MultiGet { .. }
+            | Request::Write { .. }
+            | Request::Delete { .. }
+            | Request::DeleteRange { .. }
+            | Request::Expire { .. }
+            | Request::Persist { .. }
+            | Request::Ttl { .. }
+            | Request::ReadVersioned { .. }
+            | Request::Scan { .. }
+            | Request::ListColumnFamilies => true,
+            // Creating or dropping a column family again fails
+            Request::CreateColumnFamily { .. } | Request::DropColumnFamily { .. } => false,
+            // A retried swap that went through the first time reports a
+            // conflict, a retried merge or increment is applied twice
+            Request::CompareAndSwap { .. } | Request::Merge { .. } | Request::Incr { .. } => false,
+            // Transactions are bound to the connection that began them
+            Request::TxBegin { .. }
+            | Request::TxGet { .. }
+            | Request::TxPut { .. }
+            | Request::TxCommit
+            | Request::TxRollback => false,
+            Request::Batch { requests } => requests.iter().all(Request::is_idempotent),
+        }
+    }
+}
+
+/// When a key expires
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Expiry {
+    /// Milliseconds after the request is executed
+    In(u64),
+    /// Unix time in milliseconds
+    At(u64),
+}
+
+impl Expiry {
+    /// Unix time in milliseconds at which the key expires, given the current
+    /// Unix time in milliseconds
+    pub fn deadline(self, now: u64) -> u64 {
+        match self {
+            Expiry::In(millis) => now.saturating_add(millis),
+            Expiry::At(at) => at,
+        }
+    }
+}
+
+/// Remaining time to live of a key
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TimeToLive {
+    /// The key does not exist
+    Missing,
+    /// The key never expires
+    Persistent,
+    /// The key expires in that many milliseconds
+    Remaining(u64),
+}
+
+/// What a compare-and-swap expects the current value of a key to be
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub enum Expected {
+    /// The key holds this value
+    Value(String),
+    /// The key was last written by this version
+    Version(u64),
+}
+
+/// A value and the version of the write that stored it
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct Versioned {
+    pub value: String,
+    pub version: u64,
+}
+
+/// Outcome of a compare-and-swap
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub enum CasOutcome {
+    /// The write was applied, with this version unless it deleted the key
+    Swapped { version: Option<u64> },
+    /// The key did not match, it currently holds `current`
+    Conflict { current: Option<Versioned> },
+}
+
+/// A request tagged with a client-chosen id
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct RequestEnvelope {
+    pub id: u64,
+    pub request: Request,
+}
+
+/// A response tagged with the id of the request it answers
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ResponseEnvelope {
+    pub id: u64,
+    pub response: Response,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum Response {
     Success(Option<String>),
-    Error(String),
+    /// Key-value pairs returned by a scan, in key order
+    Entries(Vec<(String, String)>),
+    /// Values of the keys of a `Request::MultiGet`, in the same order
+    Values(Vec<Option<String>>),
+    /// One response per request of a batch
+    Batch(Vec<Response>),
+    /// Whether the request changed the key
+    Updated(bool),
+    Ttl(TimeToLive),
+    Versioned(Option<Versioned>),
+    CompareAndSwap(CasOutcome),
+    Integer(i64),
+    /// Names of the column families
+    Names(Vec<String>),
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+/// Machine readable error codes carried by `Response::Error`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum ErrorCode {
+    NotFound,
+    Corruption,
+    InvalidArgument,
+    Busy,
+    IoError,
+    ShuttingDown,
+    ProtocolError,
+    Timeout,
+    OutOfMemory,
+    Conflict,
+    Overflow,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
 pub enum CompactionStrategy {
-   SizeTiered,
-   LevelBased, 
-}
\ No newline at end of file
+    SizeTiered,
+    LevelBased,
+}
+
+/// When the Wal is synced to stable storage
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum SyncPolicy {
+    /// Flush every append to the OS without waiting for the disk
+    Never,
+    /// fsync after every append
+    Always,
+}
+/// Keys evicted once the memory limit is reached, as Redis'
+/// `maxmemory-policy`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum EvictionPolicy {
+    /// Refuse writes needing more memory
+    NoEviction,
+    /// Evict the least recently used keys
+    AllKeysLru,
+    /// Evict the least frequently used keys
+    AllKeysLfu,
+    /// Evict the keys with an expiry that expire first
+    VolatileTtl,
+}
+
+impl FromStr for CompactionStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().replace(['-', '_'], "").as_str() {
+            "sizetiered" => Ok(CompactionStrategy::SizeTiered),
+            "levelbased" => Ok(CompactionStrategy::LevelBased),
+            _ => Err(format!(
+                "unknown compaction strategy '{}', expected 'size-tiered' or 'level-based'",
+                s
+            )),
+        }
+    }
+}
+
+impl FromStr for SyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "never" => Ok(SyncPolicy::Never),
+            "always" => Ok(SyncPolicy::Always),
+            _ => Err(format!(
+                "unknown sync policy '{}', expected 'never' or 'always'",
+                s
+            )),
+        }
+    }
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().replace(['-', '_'], "").as_str() {
+            "noeviction" => Ok(EvictionPolicy::NoEviction),
+            "allkeyslru" => Ok(EvictionPolicy::AllKeysLru),
+            "allkeyslfu" => Ok(EvictionPolicy::AllKeysLfu),
+            "volatilettl" => Ok(EvictionPolicy::VolatileTtl),
+            _ => Err(format!(
+                "unknown eviction policy '{}', expected 'noeviction', 'allkeys-lru', \
+                 'allkeys-lfu' or 'volatile-ttl'",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            EvictionPolicy::NoEviction => "noeviction",
+            EvictionPolicy::AllKeysLru => "allkeys-lru",
+            EvictionPolicy::AllKeysLfu => "allkeys-lfu",
+            EvictionPolicy::VolatileTtl => "volatile-ttl",
+        };
+        f.write_str(name)
+    }
+}
@@ -0,0 +1,536 @@
+use std::{
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    time::Duration,
+};
+
+use log::info;
+
+use crate::{
+    common_enums::{CasOutcome, Expected, Expiry, TimeToLive, Versioned},
+    error::Error,
+    storage::{
+        now_millis, qualified_key, BatchLocks, CloseOptions, CompactionFilter, Comparator, LSMTree,
+        LockManager, LockMode, MemoryStats, MergeOperator, Options, ReadOptions, Snapshot,
+        StoredValue, Transaction, DEFAULT_COLUMN_FAMILY,
+    },
+};
+
+/// An embedded rache database.
+///
+/// `Db` is the API for using rache in-process, the servers are built on top
+/// of it. It is cheap to clone and every clone shares the same LSM Tree.
+/// Reads run concurrently, writes are serialized.
+///
+/// A handle reads and writes a single column family, the default one unless
+/// it was returned by [`Db::column_family`].
+///
+/// ```no_run
+/// # fn example() -> rache::Result<()> {
+/// let db = rache::Db::open("/tmp/rache", rache::Options::default())?;
+/// db.put("greeting", "hello")?;
+/// assert_eq!(db.get("greeting")?.as_deref(), Some("hello"));
+/// db.close()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Db {
+    inner: Arc<Inner>,
+    /// Column family read and written through this handle
+    cf: Arc<str>,
+}
+
+struct Inner {
+    path: PathBuf,
+    lsm_tree: RwLock<LSMTree>,
+    /// Row locks, waited for without holding the LSM Tree
+    lock_manager: Arc<LockManager>,
+    lock_timeout: Duration,
+    closed: AtomicBool,
+}
+
+/// Writes applied together by [`Db::batch`], to the column family of the
+/// handle unless another one is named
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    entries: Vec<(Option<String>, String, StoredValue)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the value of a key. An empty value deletes the key.
+    pub fn put(&mut self, key: &str, value: &str) -> &mut Self {
+        let value = StoredValue::new(value.to_string());
+        self.entries.push((None, key.to_string(), value));
+        self
+    }
+
+    /// Set the value of a key of the column family `cf`
+    pub fn put_cf(&mut self, cf: &str, key: &str, value: &str) -> &mut Self {
+        let value = StoredValue::new(value.to_string());
+        self.entries
+            .push((Some(cf.to_string()), key.to_string(), value));
+        self
+    }
+
+    /// Set the value of a key expiring at `expiry`, counted from now
+    pub fn put_with_expiry(&mut self, key: &str, value: &str, expiry: Expiry) -> &mut Self {
+        let expires_at = expiry.deadline(now_millis());
+        let value = StoredValue::with_expiry(value.to_string(), Some(expires_at));
+        self.entries.push((None, key.to_string(), value));
+        self
+    }
+
+    /// Delete a key
+    pub fn delete(&mut self, key: &str) -> &mut Self {
+        // An empty value is a tombstone
+        self.entries
+            .push((None, key.to_string(), StoredValue::default()));
+        self
+    }
+
+    /// Delete a key of the column family `cf`
+    pub fn delete_cf(&mut self, cf: &str, key: &str) -> &mut Self {
+        let entry = (
+            Some(cf.to_string()),
+            key.to_string(),
+            StoredValue::default(),
+        );
+        self.entries.push(entry);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl Db {
+    /// Open the database stored in `path`, creating it if allowed by `options`
+    pub fn open<P: AsRef<Path>>(path: P, options: Options) -> Result<Db, Error> {
+        let path = path.as_ref();
+        Self::from_lsm_tree(path, LSMTree::open(path, options)?)
+    }
+
+    /// Open the database stored in `path` with the keys ordered by a custom
+    /// comparator, which must be given every time the database is opened
+    pub fn open_with_comparator<P: AsRef<Path>>(
+        path: P,
+        options: Options,
+        comparator: Arc<dyn Comparator>,
+    ) -> Result<Db, Error> {
+        let path = path.as_ref();
+        Self::from_lsm_tree(
+            path,
+            LSMTree::open_with_comparator(path, options, comparator)?,
+        )
+    }
+
+    fn from_lsm_tree(path: &Path, lsm_tree: LSMTree) -> Result<Db, Error> {
+        Ok(Db {
+            inner: Arc::new(Inner {
+                path: path.to_path_buf(),
+                lock_manager: Arc::clone(lsm_tree.lock_manager()),
+                lock_timeout: Duration::from_millis(lsm_tree.options().lock_timeout_ms),
+                lsm_tree: RwLock::new(lsm_tree),
+                closed: AtomicBool::new(false),
+            }),
+            cf: Arc::from(DEFAULT_COLUMN_FAMILY),
+        })
+    }
+
+    /// A handle on the column family `name` of the database, sharing this
+    /// handle's LSM Tree
+    pub fn column_family(&self, name: &str) -> Result<Db, Error> {
+        self.read_lock()?.column_family_options(name)?;
+        Ok(Db {
+            inner: Arc::clone(&self.inner),
+            cf: Arc::from(name),
+        })
+    }
+
+    /// Name of the column family read and written through this handle
+    pub fn column_family_name(&self) -> &str {
+        &self.cf
+    }
+
+    /// Create a column family tuned by `options`. Its MemTable, compaction
+    /// and SSTable options are taken from `options`, the others are shared
+    /// with the database.
+    pub fn create_column_family(&self, name: &str, options: Options) -> Result<(), Error> {
+        self.write_lock()?.create_column_family(name, options)
+    }
+
+    /// Drop a column family and every key it holds
+    pub fn drop_column_family(&self, name: &str) -> Result<(), Error> {
+        self.write_lock()?.drop_column_family(name)
+    }
+
+    /// Names of the column families, the default one included
+    pub fn list_column_families(&self) -> Result<Vec<String>, Error> {
+        Ok(self.read_lock()?.list_column_families())
+    }
+
+    /// Directory the database is stored in
+    pub fn path(&self) -> &Path {
+        &self.inner.path
+    }
+
+    /// Options of the column family of the handle, which for the default
+    /// one are the options the database was opened with
+    pub fn options(&self) -> Result<Options, Error> {
+        Ok(self.read_lock()?.column_family_options(&self.cf)?.clone())
+    }
+
+    /// Whether the database was closed cleanly before being opened.
+    /// Otherwise its recent writes were recovered from the Wal.
+    pub fn clean_shutdown(&self) -> Result<bool, Error> {
+        Ok(self.read_lock()?.clean_shutdown())
+    }
+
+    /// Memory accounting, if the options set a `max_memory`
+    pub fn memory_stats(&self) -> Result<Option<MemoryStats>, Error> {
+        Ok(self.read_lock()?.memory_stats())
+    }
+
+    /// Register the operator folding merge operands onto values
+    pub fn set_merge_operator(&self, operator: Arc<dyn MergeOperator>) -> Result<(), Error> {
+        self.write_lock()?.set_merge_operator(operator);
+        Ok(())
+    }
+
+    /// Register the filter keeping, removing or rewriting the values that
+    /// survive compactions
+    pub fn set_compaction_filter(&self, filter: Arc<dyn CompactionFilter>) -> Result<(), Error> {
+        self.write_lock()?.set_compaction_filter(filter);
+        Ok(())
+    }
+
+    /// Close the database with the default [`CloseOptions`]
+    pub fn close(&self) -> Result<(), Error> {
+        self.close_with_options(&CloseOptions::default())
+    }
+
+    /// Wait for running operations, sync the Wal to disk and record the
+    /// clean shutdown. Any further operation on this handle or its clones
+    /// fails with [`Error::ShuttingDown`]. Closing twice does nothing.
+    pub fn close_with_options(&self, options: &CloseOptions) -> Result<(), Error> {
+        let mut lsm_tree = self
+            .inner
+            .lsm_tree
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        if self.inner.closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        info!("Closing database in {:?}", self.inner.path);
+        lsm_tree.close(options.flush_memtable)
+    }
+
+    /// Whether [`Db::close`] was called
+    pub fn is_closed(&self) -> bool {
+        self.inner.closed.load(Ordering::SeqCst)
+    }
+
+    /// Read the value of a key
+    pub fn get(&self, key: &str) -> Result<Option<String>, Error> {
+        self.read_lock()?.read(&self.cf, key)
+    }
+
+    /// Read the value of a key with explicit read options
+    pub fn get_with_options(
+        &self,
+        key: &str,
+        options: &ReadOptions,
+    ) -> Result<Option<String>, Error> {
+        self.read_lock()?.read_with_options(&self.cf, key, options)
+    }
+
+    /// Read the values of several keys at once, in the order of `keys`
+    pub fn multi_get(&self, keys: &[&str]) -> Result<Vec<Option<String>>, Error> {
+        self.read_lock()?.multi_get(&self.cf, keys)
+    }
+
+    /// Read the value of a key and the version of the write that stored it
+    pub fn get_versioned(&self, key: &str) -> Result<Option<Versioned>, Error> {
+        let stored = self
+            .read_lock()?
+            .read_stored(&self.cf, key, &ReadOptions::default())?;
+        Ok(stored.map(|stored| Versioned {
+            value: stored.value,
+            version: stored.version,
+        }))
+    }
+
+    /// Set the value of a key. An empty value deletes the key.
+    pub fn put(&self, key: &str, value: &str) -> Result<(), Error> {
+        let _locks = self.lock_keys(&[key])?;
+        self.write_lock()?
+            .write(&self.cf, key.to_string(), value.to_string())
+    }
+
+    /// Set the value of a key, which is deleted once `expiry` is reached
+    pub fn put_with_expiry(&self, key: &str, value: &str, expiry: Expiry) -> Result<(), Error> {
+        let expires_at = expiry.deadline(now_millis());
+        let value = StoredValue::with_expiry(value.to_string(), Some(expires_at));
+        let _locks = self.lock_keys(&[key])?;
+        self.write_lock()?
+            .write_stored(&self.cf, key.to_string(), value)
+    }
+
+    /// Record a merge operand for a key, folded onto its value by the
+    /// operator set with [`Db::set_merge_operator`]
+    pub fn merge(&self, key: &str, operand: &str) -> Result<(), Error> {
+        let _locks = self.lock_keys(&[key])?;
+        self.write_lock()?
+            .merge(&self.cf, key.to_string(), operand.to_string())
+    }
+
+    /// Delete every key in `start..end` at once
+    pub fn delete_range(&self, start: &str, end: &str) -> Result<(), Error> {
+        let _locks =
+            self.inner
+                .lock_manager
+                .lock_range(&self.cf, start, end, self.inner.lock_timeout)?;
+        self.write_lock()?
+            .delete_range(&self.cf, start.to_string(), end.to_string())
+    }
+
+    /// Add `delta` to the integer value of a key, a missing key counting as
+    /// 0, and return the new value. The key keeps its expiry.
+    pub fn increment(&self, key: &str, delta: i64) -> Result<i64, Error> {
+        let _locks = self.lock_keys(&[key])?;
+        self.write_lock()?
+            .increment(&self.cf, key.to_string(), delta)
+    }
+
+    /// Delete a key
+    pub fn delete(&self, key: &str) -> Result<(), Error> {
+        let _locks = self.lock_keys(&[key])?;
+        self.write_lock()?
+            .write(&self.cf, key.to_string(), String::new())
+    }
+
+    /// Write `new`, or delete the key if `None`, only if the key matches
+    /// `expected`, or is absent if `None`. On conflict the current value of
+    /// the key is returned and nothing is written.
+    pub fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<Expected>,
+        new: Option<&str>,
+    ) -> Result<CasOutcome, Error> {
+        let new = new.map(|value| StoredValue::new(value.to_string()));
+        let _locks = self.lock_keys(&[key])?;
+        self.write_lock()?
+            .compare_and_swap(&self.cf, key.to_string(), expected.as_ref(), new)
+    }
+
+    /// Set the value of a key only if the key does not exist
+    pub fn put_if_absent(&self, key: &str, value: &str) -> Result<CasOutcome, Error> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// Delete a key only if it holds `value`
+    pub fn delete_if_equal(&self, key: &str, value: &str) -> Result<CasOutcome, Error> {
+        self.compare_and_swap(key, Some(Expected::Value(value.to_string())), None)
+    }
+
+    /// Set the expiry of a key, returning whether the key exists
+    pub fn expire(&self, key: &str, expiry: Expiry) -> Result<bool, Error> {
+        let _locks = self.lock_keys(&[key])?;
+        let mut lsm_tree = self.write_lock()?;
+        let Some(mut stored) = lsm_tree.read_stored(&self.cf, key, &ReadOptions::default())? else {
+            return Ok(false);
+        };
+        stored.expires_at = Some(expiry.deadline(now_millis()));
+        lsm_tree.write_stored(&self.cf, key.to_string(), stored)?;
+        Ok(true)
+    }
+
+    /// Remove the expiry of a key, returning whether the key had one
+    pub fn persist(&self, key: &str) -> Result<bool, Error> {
+        let _locks = self.lock_keys(&[key])?;
+        let mut lsm_tree = self.write_lock()?;
+        let stored = lsm_tree.read_stored(&self.cf, key, &ReadOptions::default())?;
+        let Some(mut stored) = stored.filter(|s| s.expires_at.is_some()) else {
+            return Ok(false);
+        };
+        stored.expires_at = None;
+        lsm_tree.write_stored(&self.cf, key.to_string(), stored)?;
+        Ok(true)
+    }
+
+    /// Get the remaining time to live of a key
+    pub fn ttl(&self, key: &str) -> Result<TimeToLive, Error> {
+        let stored = self
+            .read_lock()?
+            .read_stored(&self.cf, key, &ReadOptions::default())?;
+        Ok(match stored {
+            None => TimeToLive::Missing,
+            Some(StoredValue {
+                expires_at: None, ..
+            }) => TimeToLive::Persistent,
+            Some(StoredValue {
+                expires_at: Some(at),
+                ..
+            }) => TimeToLive::Remaining(at.saturating_sub(now_millis())),
+        })
+    }
+
+    /// Apply several writes in order. Every write is validated before the
+    /// first one is applied, and no reader sees the batch half applied.
+    /// The batch waits for the keys locked by transactions.
+    pub fn batch(&self, batch: WriteBatch) -> Result<(), Error> {
+        let entries: Vec<_> = batch
+            .entries
+            .into_iter()
+            .map(|(cf, key, value)| (cf.unwrap_or_else(|| self.cf.to_string()), key, value))
+            .collect();
+        let keys = entries
+            .iter()
+            .map(|(cf, key, _)| qualified_key(cf, key))
+            .collect();
+        let _locks = self
+            .inner
+            .lock_manager
+            .lock_batch(keys, self.inner.lock_timeout)?;
+        self.write_lock()?.write_batch(entries)
+    }
+
+    /// Get at most `limit` key-value pairs with keys in `start..end`, in key order
+    pub fn scan(
+        &self,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>, Error> {
+        self.read_lock()?.scan(&self.cf, start, end, limit)
+    }
+
+    /// Get at most `limit` key-value pairs whose key starts with `prefix`
+    pub fn scan_prefix(&self, prefix: &str, limit: usize) -> Result<Vec<(String, String)>, Error> {
+        self.read_lock()?.scan_prefix(&self.cf, prefix, limit)
+    }
+
+    /// Get at most `limit` key-value pairs whose key starts with `prefix`
+    /// and is in `start..end`, in key order
+    pub fn scan_prefix_range(
+        &self,
+        prefix: &str,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>, Error> {
+        self.read_lock()?
+            .scan_prefix_range(&self.cf, prefix, start, end, limit)
+    }
+
+    /// Take a consistent read-only view of the database
+    pub fn snapshot(&self) -> Result<Snapshot, Error> {
+        self.read_lock()?.snapshot(&self.cf)
+    }
+
+    /// Begin an optimistic transaction reading from a snapshot taken now.
+    /// Dropping the transaction rolls it back.
+    pub fn begin(&self) -> Result<Transaction, Error> {
+        self.read_lock()?.begin(&self.cf)
+    }
+
+    /// Begin a pessimistic transaction, which waits for an exclusive lock
+    /// on every key it writes. Suits contended keys, where optimistic
+    /// transactions would keep conflicting.
+    pub fn begin_pessimistic(&self) -> Result<Transaction, Error> {
+        self.read_lock()?.begin_pessimistic(&self.cf)
+    }
+
+    /// Lock a key for the rest of a transaction, then read its latest
+    /// value. Fails with [`Error::Busy`] if waiting for the lock would
+    /// deadlock, and with [`Error::Timeout`] once `lock_timeout_ms` elapses.
+    pub fn get_for_update(
+        &self,
+        transaction: &mut Transaction,
+        key: &str,
+        mode: LockMode,
+    ) -> Result<Option<String>, Error> {
+        // Waited for first, a waiting reader would hold off the writers
+        transaction.lock(key, mode)?;
+        self.read_lock()?.get_for_update(transaction, key, mode)
+    }
+
+    /// Apply the writes of a transaction atomically and release its locks.
+    /// Fails with [`Error::Conflict`], writing nothing, if a key the
+    /// transaction read was modified since it read it.
+    pub fn commit(&self, mut transaction: Transaction) -> Result<(), Error> {
+        transaction.lock_writes()?;
+        self.write_lock()?.commit(transaction)
+    }
+
+    /// Flush the MemTable to an SSTable and sync the Wal
+    pub fn flush(&self) -> Result<(), Error> {
+        let mut lsm_tree = self.write_lock()?;
+        lsm_tree.flush()?;
+        lsm_tree.sync()
+    }
+
+    /// Compact the SSTables holding keys in `start..end`, dropping
+    /// overwritten values and deleted keys
+    pub fn compact_range(&self, start: Bound<&str>, end: Bound<&str>) -> Result<(), Error> {
+        self.write_lock()?.compact_range(&self.cf, start, end)
+    }
+
+    /// Lock the LSM Tree for reading
+    pub(crate) fn read_lock(&self) -> Result<RwLockReadGuard<'_, LSMTree>, Error> {
+        let lsm_tree = self
+            .inner
+            .lsm_tree
+            .read()
+            .unwrap_or_else(|e| e.into_inner());
+        // Checked under the lock so that nothing runs after `close`
+        self.check_open()?;
+        Ok(lsm_tree)
+    }
+
+    /// Exclusively lock `keys` of this column family, waiting for the
+    /// transactions holding them up to `lock_timeout_ms`. Taken before
+    /// [`Db::write_lock`], as transactions wait for row locks without it.
+    pub(crate) fn lock_keys(&self, keys: &[&str]) -> Result<BatchLocks, Error> {
+        let keys = keys
+            .iter()
+            .map(|key| qualified_key(&self.cf, key))
+            .collect();
+        self.inner
+            .lock_manager
+            .lock_batch(keys, self.inner.lock_timeout)
+    }
+
+    /// Lock the LSM Tree for writing
+    pub(crate) fn write_lock(&self) -> Result<RwLockWriteGuard<'_, LSMTree>, Error> {
+        let lsm_tree = self
+            .inner
+            .lsm_tree
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        self.check_open()?;
+        Ok(lsm_tree)
+    }
+
+    fn check_open(&self) -> Result<(), Error> {
+        if self.is_closed() {
+            return Err(Error::ShuttingDown);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+use std::fmt;
+
+use crate::common_enums::{ErrorCode, Response};
+
+/// Errors returned by the storage engine and the server
+#[derive(Debug)]
+pub enum Error {
+    /// The requested key or resource does not exist
+    NotFound(String),
+    /// On-disk data failed an integrity check
+    Corruption(String),
+    /// The caller supplied an argument the engine cannot accept
+    InvalidArgument(String),
+    /// The resource is temporarily unavailable, the caller may retry
+    Busy(String),
+    /// Underlying I/O failure
+    IoError(std::io::Error),
+    /// The engine is shutting down and no longer accepts requests
+    ShuttingDown,
+    /// A peer sent a message that does not follow the wire protocol
+    ProtocolError(String),
+    /// An operation did not complete in time
+    Timeout(String),
+    /// A write needs more memory than the limit allows and nothing can be evicted
+    OutOfMemory(String),
+    /// A transaction read a key that was modified before it committed
+    Conflict(String),
+    /// An arithmetic operation overflowed the range of its integer
+    Overflow(String),
+}
+
+/// Result type used across the crate
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Code identifying the kind of error on the wire
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::NotFound(_) => ErrorCode::NotFound,
+            Error::Corruption(_) => ErrorCode::Corruption,
+            Error::InvalidArgument(_) => ErrorCode::InvalidArgument,
+            Error::Busy(_) => ErrorCode::Busy,
+            Error::IoError(_) => ErrorCode::IoError,
+            Error::ShuttingDown => ErrorCode::ShuttingDown,
+            Error::ProtocolError(_) => ErrorCode::ProtocolError,
+            Error::Timeout(_) => ErrorCode::Timeout,
+            Error::OutOfMemory(_) => ErrorCode::OutOfMemory,
+            Error::Conflict(_) => ErrorCode::Conflict,
+            Error::Overflow(_) => ErrorCode::Overflow,
+        }
+    }
+
+    /// Rebuild an error received on the wire
+    pub fn from_wire(code: ErrorCode, message: String) -> Self {
+        // The message is the Display of the remote error, drop its prefix
+        let detail = |prefix: &str| {
+            message
+                .strip_prefix(prefix)
+                .and_then(|m| m.strip_prefix(": "))
+                .unwrap_or(&message)
+                .to_string()
+        };
+        match code {
+            ErrorCode::NotFound => Error::NotFound(detail("Not found")),
+            ErrorCode::Corruption => Error::Corruption(detail("Corruption")),
+            ErrorCode::InvalidArgument => Error::InvalidArgument(detail("Invalid argument")),
+            ErrorCode::Busy => Error::Busy(detail("Busy")),
+            ErrorCode::IoError => Error::IoError(std::io::Error::other(detail("IO error"))),
+            ErrorCode::ShuttingDown => Error::ShuttingDown,
+            ErrorCode::ProtocolError => Error::ProtocolError(detail("Protocol error")),
+            ErrorCode::Timeout => Error::Timeout(detail("Timeout")),
+            ErrorCode::OutOfMemory => Error::OutOfMemory(detail("Out of memory")),
+            ErrorCode::Conflict => Error::Conflict(detail("Conflict")),
+            ErrorCode::Overflow => Error::Overflow(detail("Overflow")),
+        }
+    }
+
+    /// Whether the operation may succeed if retried
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Error::Busy(_) | Error::IoError(_) | Error::Timeout(_))
+    }
+}
+
+impl From<Error> for Response {
+    fn from(e: Error) -> Self {
+        Response::Error {
+            code: e.code(),
+            message: e.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound(msg) => write!(f, "Not found: {}", msg),
+            Error::Corruption(msg) => write!(f, "Corruption: {}", msg),
+            Error::InvalidArgument(msg) => write!(f, "Invalid argument: {}", msg),
+            Error::Busy(msg) => write!(f, "Busy: {}", msg),
+            Error::IoError(e) => write!(f, "IO error: {}", e),
+            Error::ShuttingDown => write!(f, "Shutting down"),
+            Error::ProtocolError(msg) => write!(f, "Protocol error: {}", msg),
+            Error::Timeout(msg) => write!(f, "Timeout: {}", msg),
+            Error::OutOfMemory(msg) => write!(f, "Out of memory: {}", msg),
+            Error::Conflict(msg) => write!(f, "Conflict: {}", msg),
+            Error::Overflow(msg) => write!(f, "Overflow: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IoError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::IoError(e)
+    }
+}
+
+impl From<rmp_serde::decode::Error> for Error {
+    fn from(e: rmp_serde::decode::Error) -> Self {
+        Error::ProtocolError(e.to_string())
+    }
+}
+
+impl From<rmp_serde::encode::Error> for Error {
+    fn from(e: rmp_serde::encode::Error) -> Self {
+        Error::ProtocolError(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_round_trip_through_responses() {
+        let errors = [
+            Error::NotFound("key".to_string()),
+            Error::Corruption("bad block".to_string()),
+            Error::InvalidArgument("key contains ':'".to_string()),
+            Error::Busy("compacting".to_string()),
+            Error::IoError(std::io::Error::other("disk full")),
+            Error::ShuttingDown,
+            Error::ProtocolError("unexpected frame".to_string()),
+            Error::Timeout("lock".to_string()),
+            Error::OutOfMemory("nothing to evict".to_string()),
+            Error::Conflict("balance".to_string()),
+            Error::Overflow("counter".to_string()),
+        ];
+        for error in errors {
+            let response = Response::from(Error::from_wire(error.code(), error.to_string()));
+            let Response::Error { code, message } = response else {
+                panic!("{} was not sent as an error", error);
+            };
+            assert_eq!(code, error.code());
+            // The message is not prefixed twice on its way back
+            assert_eq!(message, error.to_string());
+            let received = Error::from_wire(code, message);
+            assert_eq!(received.is_transient(), error.is_transient(), "{}", error);
+        }
+    }
+
+    #[test]
+    fn only_temporary_failures_are_transient() {
+        assert!(Error::Busy(String::new()).is_transient());
+        assert!(Error::Timeout(String::new()).is_transient());
+        assert!(Error::IoError(std::io::Error::other("reset")).is_transient());
+        assert!(!Error::NotFound(String::new()).is_transient());
+        assert!(!Error::Conflict(String::new()).is_transient());
+        assert!(!Error::ShuttingDown.is_transient());
+    }
+}
@@ -0,0 +1,292 @@
+use axum::{
+    extract::{DefaultBodyLimit, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response as HttpResponse},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    common_enums::{ErrorCode, Expiry, Request, Response},
+    error::Error,
+    server::Server,
+};
+
+/// Number of pairs returned by a scan without a `limit`
+const DEFAULT_SCAN_LIMIT: usize = 100;
+
+/// Maximum number of pairs returned by a single scan
+const MAX_SCAN_LIMIT: usize = 10_000;
+
+/// Routes of the HTTP/JSON gateway
+///
+/// - `GET /kv/{key}`, `PUT /kv/{key}?ttl_ms=` and `DELETE /kv/{key}`
+/// - `GET /kv?prefix=&start=&end=&limit=` to scan
+/// - `POST /batch` to run several operations in one request
+///
+/// Every route takes a `cf` query parameter naming the column family to act
+/// on, and every batch operation a `cf` field.
+pub fn router(server: Server, max_body_size: usize) -> Router {
+    Router::new()
+        .route("/kv", get(scan))
+        .route("/kv/{key}", get(read).put(write).delete(delete))
+        .route("/batch", post(batch))
+        .layer(DefaultBodyLimit::max(max_body_size))
+        .with_state(server)
+}
+
+#[derive(Serialize)]
+struct Entry {
+    key: String,
+    value: String,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: ErrorCode,
+    message: String,
+}
+
+/// A failed request, answered with a status matching its error code
+struct ApiError(Error);
+
+impl From<Error> for ApiError {
+    fn from(e: Error) -> Self {
+        ApiError(e)
+    }
+}
+
+fn status(code: ErrorCode) -> StatusCode {
+    match code {
+        ErrorCode::NotFound => StatusCode::NOT_FOUND,
+        ErrorCode::InvalidArgument | ErrorCode::ProtocolError => StatusCode::BAD_REQUEST,
+        ErrorCode::Busy | ErrorCode::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+        ErrorCode::Corruption | ErrorCode::IoError => StatusCode::INTERNAL_SERVER_ERROR,
+        ErrorCode::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        ErrorCode::OutOfMemory => StatusCode::INSUFFICIENT_STORAGE,
+        ErrorCode::Conflict => StatusCode::CONFLICT,
+        ErrorCode::Overflow => StatusCode::UNPROCESSABLE_ENTITY,
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> HttpResponse {
+        let code = self.0.code();
+        let body = ErrorBody {
+            code,
+            message: self.0.to_string(),
+        };
+        (status(code), Json(serde_json::json!({ "error": body }))).into_response()
+    }
+}
+
+/// Run a request on the blocking thread pool, as the native protocol does
+async fn execute(server: Server, request: Request) -> Result<Response, ApiError> {
+    let response = tokio::task::spawn_blocking(move || server.execute(request))
+        .await
+        .map_err(|e| Error::Busy(format!("request task failed: {}", e)))??;
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct FamilyParams {
+    /// Column family, the default one if unset
+    cf: Option<String>,
+}
+
+async fn read(
+    State(server): State<Server>,
+    Path(key): Path<String>,
+    Query(params): Query<FamilyParams>,
+) -> Result<Json<Entry>, ApiError> {
+    let request = Request::Read {
+        key: key.clone(),
+        cf: params.cf,
+    };
+    match execute(server, request).await? {
+        Response::Success(Some(value)) => Ok(Json(Entry { key, value })),
+        _ => Err(Error::NotFound(format!("key {:?}", key)).into()),
+    }
+}
+
+#[derive(Deserialize)]
+struct WriteBody {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct WriteParams {
+    /// Milliseconds after which the key expires
+    ttl_ms: Option<u64>,
+    cf: Option<String>,
+}
+
+/// The value is the raw request body, or the `value` field of a JSON body.
+/// Empty values are rejected: the engine stores them as deletions.
+async fn write(
+    State(server): State<Server>,
+    Path(key): Path<String>,
+    Query(params): Query<WriteParams>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<StatusCode, ApiError> {
+    let is_json = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    let value = if is_json {
+        serde_json::from_str::<WriteBody>(&body)
+            .map_err(|e| Error::InvalidArgument(format!("invalid JSON body: {}", e)))?
+            .value
+    } else {
+        body
+    };
+    let value = non_empty(&key, value)?;
+    let expiry = params.ttl_ms.map(Expiry::In);
+    let request = Request::Write {
+        key,
+        value,
+        expiry,
+        cf: params.cf,
+    };
+    execute(server, request).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Reject the empty value of a write, which would delete the key
+fn non_empty(key: &str, value: String) -> Result<String, Error> {
+    if value.is_empty() {
+        return Err(Error::InvalidArgument(format!(
+            "empty value for key {:?}, use DELETE to delete a key",
+            key
+        )));
+    }
+    Ok(value)
+}
+
+async fn delete(
+    State(server): State<Server>,
+    Path(key): Path<String>,
+    Query(params): Query<FamilyParams>,
+) -> Result<StatusCode, ApiError> {
+    execute(server, Request::Delete { key, cf: params.cf }).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct ScanParams {
+    prefix: Option<String>,
+    start: Option<String>,
+    end: Option<String>,
+    limit: Option<usize>,
+    cf: Option<String>,
+}
+
+async fn scan(
+    State(server): State<Server>,
+    Query(params): Query<ScanParams>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let limit = params.limit.unwrap_or(DEFAULT_SCAN_LIMIT);
+    if limit > MAX_SCAN_LIMIT {
+        return Err(
+            Error::InvalidArgument(format!("limit must not exceed {}", MAX_SCAN_LIMIT)).into(),
+        );
+    }
+    let request = Request::Scan {
+        start: params.start,
+        end: params.end,
+        prefix: params.prefix,
+        limit,
+        cf: params.cf,
+    };
+    match execute(server, request).await? {
+        Response::Entries(entries) => {
+            Ok(Json(serde_json::json!({ "items": entries_json(entries) })))
+        }
+        other => Err(unexpected(other)),
+    }
+}
+
+/// One operation of a `POST /batch` body
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Operation {
+    Get {
+        key: String,
+        cf: Option<String>,
+    },
+    Put {
+        key: String,
+        value: String,
+        ttl_ms: Option<u64>,
+        cf: Option<String>,
+    },
+    Delete {
+        key: String,
+        cf: Option<String>,
+    },
+}
+
+#[derive(Deserialize)]
+struct BatchBody {
+    operations: Vec<Operation>,
+}
+
+/// Operations are executed in order. Every operation gets a result, either
+/// `{"value": ...}` or `{"error": {...}}`.
+async fn batch(
+    State(server): State<Server>,
+    Json(body): Json<BatchBody>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let requests = body
+        .operations
+        .into_iter()
+        .map(|op| match op {
+            Operation::Get { key, cf } => Ok(Request::Read { key, cf }),
+            Operation::Put {
+                key,
+                value,
+                ttl_ms,
+                cf,
+            } => Ok(Request::Write {
+                value: non_empty(&key, value)?,
+                key,
+                expiry: ttl_ms.map(Expiry::In),
+                cf,
+            }),
+            Operation::Delete { key, cf } => Ok(Request::Delete { key, cf }),
+        })
+        .collect::<Result<_, Error>>()?;
+    let responses = match execute(server, Request::Batch { requests }).await? {
+        Response::Batch(responses) => responses,
+        other => return Err(unexpected(other)),
+    };
+    let results: Vec<serde_json::Value> = responses
+        .into_iter()
+        .map(|response| match response {
+            Response::Success(value) => serde_json::json!({ "value": value }),
+            Response::Error { code, message } => {
+                serde_json::json!({ "error": ErrorBody { code, message } })
+            }
+            Response::Entries(entries) => serde_json::json!({ "items": entries_json(entries) }),
+            // Not produced by batch operations
+            _ => serde_json::json!({}),
+        })
+        .collect();
+    Ok(Json(serde_json::json!({ "results": results })))
+}
+
+fn entries_json(entries: Vec<(String, String)>) -> Vec<Entry> {
+    entries
+        .into_iter()
+        .map(|(key, value)| Entry { key, value })
+        .collect()
+}
+
+fn unexpected(response: Response) -> ApiError {
+    ApiError(Error::ProtocolError(format!(
+        "unexpected response {:?}",
+        response
+    )))
+}
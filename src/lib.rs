@@ -1,3 +1,17 @@
-pub mod storage;
+pub mod client;
+pub mod codec;
 pub mod common_enums;
-pub mod server;
\ No newline at end of file
+pub mod db;
+pub mod error;
+pub mod http;
+pub mod memcached;
+pub mod resp;
+pub mod server;
+pub mod storage;
+
+pub use db::{Db, WriteBatch};
+pub use error::{Error, Result};
+pub use storage::{
+    CloseOptions, CompactionFilter, Comparator, LockMode, MemoryStats, MergeOperator, Options,
+    ReadOptions, Snapshot, Transaction, DEFAULT_COLUMN_FAMILY,
+};
@@ -0,0 +1,513 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::io::AsyncRead;
+
+use crate::{
+    codec::{ReadDeadline, ReadTimeouts},
+    db::Db,
+    error::Error,
+    storage::{LSMTree, ReadOptions, StoredValue},
+};
+
+/// Maximum length of a key, as in memcached
+const MAX_KEY_LENGTH: usize = 250;
+
+/// Maximum length of a command line
+const MAX_LINE_LENGTH: usize = 4096;
+
+/// Relative expiration times above this many seconds are unix timestamps
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+/// How a storage command treats an existing item
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StoreMode {
+    Set,
+    Add,
+    Replace,
+    Cas(u64),
+}
+
+/// A command of the memcached text protocol
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Get {
+        keys: Vec<String>,
+        with_cas: bool,
+    },
+    Store {
+        mode: StoreMode,
+        key: String,
+        flags: u32,
+        exptime: i64,
+        data: Vec<u8>,
+        noreply: bool,
+    },
+    Delete {
+        key: String,
+        noreply: bool,
+    },
+    Arithmetic {
+        key: String,
+        delta: u64,
+        incr: bool,
+        noreply: bool,
+    },
+    Version,
+    Quit,
+    /// A malformed command, answered with the given error line
+    Invalid(String),
+}
+
+impl Command {
+    fn noreply(&self) -> bool {
+        match self {
+            Command::Store { noreply, .. }
+            | Command::Delete { noreply, .. }
+            | Command::Arithmetic { noreply, .. } => *noreply,
+            _ => false,
+        }
+    }
+}
+
+/// Reads memcached commands and their data blocks
+pub struct MemcachedReader<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    max_item_size: usize,
+    deadline: ReadDeadline,
+}
+
+impl<R: AsyncRead + Unpin> MemcachedReader<R> {
+    pub fn new(reader: R, max_item_size: usize) -> Self {
+        MemcachedReader {
+            reader,
+            buffer: Vec::new(),
+            max_item_size,
+            deadline: ReadDeadline::default(),
+        }
+    }
+
+    /// Fail reads that take longer than `timeouts`
+    pub fn with_timeouts(mut self, timeouts: ReadTimeouts) -> Self {
+        self.deadline = ReadDeadline::new(timeouts);
+        self
+    }
+
+    /// Read the next command.
+    /// Returns `None` when the stream is closed between two commands.
+    pub async fn read_command(&mut self) -> Result<Option<Command>, Error> {
+        loop {
+            if let Some((command, consumed)) = self.parse()? {
+                self.buffer.drain(..consumed);
+                self.deadline.message_done();
+                return Ok(Some(command));
+            }
+            let pending = !self.buffer.is_empty();
+            if self
+                .deadline
+                .read_buf(&mut self.reader, &mut self.buffer, pending)
+                .await?
+                == 0
+            {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Err(Error::ProtocolError(
+                    "connection closed in the middle of a command".to_string(),
+                ));
+            }
+        }
+    }
+
+    /// Parse the first complete command of the buffer, returning it with the
+    /// number of bytes it spans
+    fn parse(&self) -> Result<Option<(Command, usize)>, Error> {
+        let Some(newline) = self.buffer.iter().position(|&b| b == b'\n') else {
+            if self.buffer.len() > MAX_LINE_LENGTH {
+                return Err(Error::ProtocolError("command line too long".to_string()));
+            }
+            return Ok(None);
+        };
+        let line = String::from_utf8_lossy(&self.buffer[..newline]);
+        let line_end = newline + 1;
+        let tokens: Vec<&str> = line.split_ascii_whitespace().collect();
+        let Some((&name, args)) = tokens.split_first() else {
+            return Ok(Some((Command::Invalid("ERROR".to_string()), line_end)));
+        };
+
+        let command = match name {
+            "get" | "gets" if !args.is_empty() => Command::Get {
+                keys: args.iter().map(|k| k.to_string()).collect(),
+                with_cas: name == "gets",
+            },
+            "set" | "add" | "replace" | "cas" => {
+                return self.parse_store(name, args, line_end);
+            }
+            "delete" => match args {
+                [key] | [key, "noreply"] => Command::Delete {
+                    key: key.to_string(),
+                    noreply: args.len() == 2,
+                },
+                _ => client_error("bad command line format"),
+            },
+            "incr" | "decr" => match args {
+                [key, delta] | [key, delta, "noreply"] => match delta.parse() {
+                    Ok(delta) => Command::Arithmetic {
+                        key: key.to_string(),
+                        delta,
+                        incr: name == "incr",
+                        noreply: args.len() == 3,
+                    },
+                    Err(_) => client_error("invalid numeric delta argument"),
+                },
+                _ => client_error("bad command line format"),
+            },
+            "version" => Command::Version,
+            "quit" => Command::Quit,
+            _ => Command::Invalid("ERROR".to_string()),
+        };
+        Ok(Some((command, line_end)))
+    }
+
+    /// Parse `<cmd> <key> <flags> <exptime> <bytes> [cas unique] [noreply]`
+    /// followed by the data block
+    fn parse_store(
+        &self,
+        name: &str,
+        args: &[&str],
+        line_end: usize,
+    ) -> Result<Option<(Command, usize)>, Error> {
+        let fixed = if name == "cas" { 5 } else { 4 };
+        if args.len() < fixed
+            || args.len() > fixed + 1
+            || (args.len() == fixed + 1 && args[fixed] != "noreply")
+        {
+            return Ok(Some((client_error("bad command line format"), line_end)));
+        }
+        let parsed = (|| {
+            let flags: u32 = args[1].parse().ok()?;
+            let exptime: i64 = args[2].parse().ok()?;
+            let bytes: usize = args[3].parse().ok()?;
+            let cas = if name == "cas" {
+                Some(args[4].parse::<u64>().ok()?)
+            } else {
+                None
+            };
+            Some((flags, exptime, bytes, cas))
+        })();
+        let Some((flags, exptime, bytes, cas)) = parsed else {
+            return Ok(Some((client_error("bad command line format"), line_end)));
+        };
+        if bytes > self.max_item_size {
+            // The data block cannot be skipped safely, give up on the connection
+            return Err(Error::ProtocolError(format!(
+                "item of {} bytes exceeds the maximum item size of {} bytes",
+                bytes, self.max_item_size
+            )));
+        }
+
+        let data_end = line_end + bytes;
+        let Some(terminator) = self.buffer.get(data_end..) else {
+            return Ok(None);
+        };
+        let consumed = if terminator.starts_with(b"\r\n") {
+            data_end + 2
+        } else if terminator.starts_with(b"\n") {
+            data_end + 1
+        } else if terminator.is_empty() || terminator == b"\r" {
+            return Ok(None);
+        } else {
+            return Ok(Some((client_error("bad data chunk"), data_end)));
+        };
+
+        let mode = match (name, cas) {
+            ("add", _) => StoreMode::Add,
+            ("replace", _) => StoreMode::Replace,
+            (_, Some(cas)) => StoreMode::Cas(cas),
+            _ => StoreMode::Set,
+        };
+        let command = Command::Store {
+            mode,
+            key: args[0].to_string(),
+            flags,
+            exptime,
+            data: self.buffer[line_end..data_end].to_vec(),
+            noreply: args.len() == fixed + 1,
+        };
+        Ok(Some((command, consumed)))
+    }
+}
+
+fn client_error(msg: &str) -> Command {
+    Command::Invalid(format!("CLIENT_ERROR {}", msg))
+}
+
+/// A live memcached item. Its data is the stored value, its flags and
+/// expiration time are the metadata of the value, and its CAS unique is the
+/// version of the write that stored it.
+#[derive(Debug, Clone, PartialEq)]
+struct Item {
+    flags: u32,
+    /// Unix time in milliseconds after which the item is gone
+    expires_at: Option<u64>,
+    cas: u64,
+    data: String,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Absolute expiration time of an `exptime` argument in Unix milliseconds,
+/// `Some(None)` for an item that does not expire and `None` for an item
+/// that expires immediately
+fn expires_at(exptime: i64, now: u64) -> Option<Option<u64>> {
+    let seconds = match exptime {
+        0 => return Some(None),
+        e if e < 0 => return None,
+        e if e <= MAX_RELATIVE_EXPTIME => now + e as u64,
+        e if e as u64 <= now => return None,
+        e => e as u64,
+    };
+    Some(Some(seconds.saturating_mul(1000)))
+}
+
+/// Check that a memcached key can be stored as is in the LSM Tree
+fn engine_key(key: &str) -> Result<String, String> {
+    if key.len() > MAX_KEY_LENGTH || key.chars().any(|c| c.is_control()) {
+        return Err("CLIENT_ERROR bad command line format".to_string());
+    }
+    if key.contains(':') {
+        return Err("CLIENT_ERROR keys must not contain ':'".to_string());
+    }
+    Ok(key.to_string())
+}
+
+/// Check that the data of an item can be stored as is in the LSM Tree,
+/// where an empty value would delete the key
+fn engine_value(data: Vec<u8>) -> Result<String, String> {
+    let data =
+        String::from_utf8(data).map_err(|_| "CLIENT_ERROR data must be valid UTF-8".to_string())?;
+    if data.is_empty() || data.contains('\n') {
+        return Err("CLIENT_ERROR data must not be empty nor contain newlines".to_string());
+    }
+    Ok(data)
+}
+
+fn server_error(e: Error) -> String {
+    format!("SERVER_ERROR {}", e)
+}
+
+/// Read a live item. Values written through other protocols are items
+/// without flags.
+fn lookup(lsm_tree: &LSMTree, cf: &str, key: &str) -> Result<Option<Item>, String> {
+    let stored = lsm_tree
+        .read_stored(cf, key, &ReadOptions::default())
+        .map_err(server_error)?;
+    Ok(stored.map(|stored| Item {
+        flags: stored.flags,
+        expires_at: stored.expires_at,
+        cas: stored.version,
+        data: stored.value,
+    }))
+}
+
+/// Write the data of an item, which the engine expires along with it and
+/// gives a new CAS unique
+fn store(
+    lsm_tree: &mut LSMTree,
+    cf: &str,
+    key: String,
+    flags: u32,
+    expires_at: Option<u64>,
+    data: String,
+) -> Result<(), String> {
+    let stored = StoredValue {
+        flags,
+        ..StoredValue::with_expiry(data, expires_at)
+    };
+    lsm_tree.write_stored(cf, key, stored).map_err(server_error)
+}
+
+/// Execute a command against the LSM Tree, returning the reply to send
+/// (empty for `noreply` commands)
+pub fn execute(db: &Db, command: Command) -> Vec<u8> {
+    let noreply = command.noreply();
+    let reply = match run(db, command) {
+        Ok(reply) => reply,
+        Err(line) => {
+            let mut reply = line.into_bytes();
+            reply.extend_from_slice(b"\r\n");
+            // Errors are reported even for noreply commands
+            return reply;
+        }
+    };
+    if noreply {
+        Vec::new()
+    } else {
+        reply
+    }
+}
+
+fn run(db: &Db, command: Command) -> Result<Vec<u8>, String> {
+    let now = now();
+    let cf = db.column_family_name();
+    let line = |s: &str| Ok(format!("{}\r\n", s).into_bytes());
+    match command {
+        Command::Get { keys, with_cas } => {
+            let lsm_tree = db.read_lock().map_err(server_error)?;
+            let mut reply = Vec::new();
+            for key in keys {
+                let Some(item) = lookup(&lsm_tree, cf, &engine_key(&key)?)? else {
+                    continue;
+                };
+                let header = if with_cas {
+                    format!(
+                        "VALUE {} {} {} {}\r\n",
+                        key,
+                        item.flags,
+                        item.data.len(),
+                        item.cas
+                    )
+                } else {
+                    format!("VALUE {} {} {}\r\n", key, item.flags, item.data.len())
+                };
+                reply.extend_from_slice(header.as_bytes());
+                reply.extend_from_slice(item.data.as_bytes());
+                reply.extend_from_slice(b"\r\n");
+            }
+            reply.extend_from_slice(b"END\r\n");
+            Ok(reply)
+        }
+        Command::Store {
+            mode,
+            key,
+            flags,
+            exptime,
+            data,
+            ..
+        } => {
+            let key = engine_key(&key)?;
+            let data = engine_value(data)?;
+            let _locks = db.lock_keys(&[&key]).map_err(server_error)?;
+            let mut lsm_tree = db.write_lock().map_err(server_error)?;
+            let current = lookup(&lsm_tree, cf, &key)?;
+            let outcome = match (mode, &current) {
+                (StoreMode::Add, Some(_)) => "NOT_STORED",
+                (StoreMode::Replace, None) => "NOT_STORED",
+                (StoreMode::Cas(_), None) => "NOT_FOUND",
+                (StoreMode::Cas(cas), Some(item)) if item.cas != cas => "EXISTS",
+                _ => "STORED",
+            };
+            if outcome == "STORED" {
+                match expires_at(exptime, now) {
+                    Some(expires_at) => store(&mut lsm_tree, cf, key, flags, expires_at, data)?,
+                    // Storing an already expired item deletes the key
+                    None => lsm_tree
+                        .write(cf, key, String::new())
+                        .map_err(server_error)?,
+                }
+            }
+            line(outcome)
+        }
+        Command::Delete { key, .. } => {
+            let key = engine_key(&key)?;
+            let _locks = db.lock_keys(&[&key]).map_err(server_error)?;
+            let mut lsm_tree = db.write_lock().map_err(server_error)?;
+            if lookup(&lsm_tree, cf, &key)?.is_none() {
+                return line("NOT_FOUND");
+            }
+            lsm_tree
+                .write(cf, key, String::new())
+                .map_err(server_error)?;
+            line("DELETED")
+        }
+        Command::Arithmetic {
+            key, delta, incr, ..
+        } => {
+            let key = engine_key(&key)?;
+            let _locks = db.lock_keys(&[&key]).map_err(server_error)?;
+            let mut lsm_tree = db.write_lock().map_err(server_error)?;
+            let Some(item) = lookup(&lsm_tree, cf, &key)? else {
+                return line("NOT_FOUND");
+            };
+            let current: u64 = item
+                .data
+                .trim()
+                .parse()
+                .map_err(|_| "CLIENT_ERROR cannot increment or decrement non-numeric value")?;
+            // incr wraps around at 64 bits, decr stops at 0
+            let value = if incr {
+                current.wrapping_add(delta)
+            } else {
+                current.saturating_sub(delta)
+            };
+            let data = value.to_string();
+            store(&mut lsm_tree, cf, key, item.flags, item.expires_at, data)?;
+            line(&value.to_string())
+        }
+        Command::Version => line(&format!("VERSION rache-{}", env!("CARGO_PKG_VERSION"))),
+        Command::Quit => Ok(Vec::new()),
+        Command::Invalid(error) => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::test_util::TestDb;
+
+    fn store_command(mode: StoreMode, key: &str, flags: u32, data: &str) -> Command {
+        Command::Store {
+            mode,
+            key: key.to_string(),
+            flags,
+            exptime: 0,
+            data: data.as_bytes().to_vec(),
+            noreply: false,
+        }
+    }
+
+    #[test]
+    fn items_are_stored_verbatim() {
+        let db = TestDb::open("memcached-items");
+
+        let reply = execute(&db, store_command(StoreMode::Set, "greeting", 42, "hello"));
+        assert_eq!(reply, b"STORED\r\n");
+        let version = db.get_versioned("greeting").unwrap().unwrap();
+        assert_eq!(version.value, "hello");
+
+        let gets = Command::Get {
+            keys: vec!["greeting".to_string()],
+            with_cas: true,
+        };
+        let expected = format!(
+            "VALUE greeting 42 5 {}\r\nhello\r\nEND\r\n",
+            version.version
+        );
+        assert_eq!(execute(&db, gets), expected.as_bytes());
+
+        let stale = StoreMode::Cas(version.version + 1);
+        assert_eq!(
+            execute(&db, store_command(stale, "greeting", 0, "bye")),
+            b"EXISTS\r\n"
+        );
+        let current = StoreMode::Cas(version.version);
+        assert_eq!(
+            execute(&db, store_command(current, "greeting", 0, "bye")),
+            b"STORED\r\n"
+        );
+        assert_eq!(db.get("greeting").unwrap().as_deref(), Some("bye"));
+    }
+
+    #[test]
+    fn keys_and_data_the_engine_cannot_store_are_rejected() {
+        let db = TestDb::open("memcached-rejected");
+        for (key, data) in [("user:1", "x"), ("empty", ""), ("lines", "a\nb")] {
+            let reply = execute(&db, store_command(StoreMode::Set, key, 0, data));
+            assert!(reply.starts_with(b"CLIENT_ERROR"), "{:?}", key);
+        }
+    }
+}
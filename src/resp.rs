@@ -0,0 +1,796 @@
+use std::ops::Bound;
+
+use tokio::io::AsyncRead;
+
+use crate::{
+    codec::{ReadDeadline, ReadTimeouts},
+    common_enums::{Expiry, TimeToLive},
+    db::{Db, WriteBatch},
+    error::Error,
+    storage::{now_millis, ReadOptions, StoredValue},
+};
+
+/// Maximum length of an inline command line
+const MAX_INLINE_SIZE: usize = 64 * 1024;
+
+/// Maximum number of arguments of a command
+const MAX_ARGUMENTS: usize = 1024 * 1024;
+
+/// Default number of keys examined by a SCAN call
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Arguments of a parsed command and the number of bytes it spans
+type Parsed = (Vec<Vec<u8>>, usize);
+
+/// A value of the Redis serialization protocol
+#[derive(Debug, Clone, PartialEq)]
+pub enum RespValue {
+    SimpleString(String),
+    Error(String),
+    Integer(i64),
+    BulkString(Vec<u8>),
+    Null,
+    Array(Vec<RespValue>),
+    Map(Vec<(RespValue, RespValue)>),
+}
+
+impl RespValue {
+    fn ok() -> Self {
+        RespValue::SimpleString("OK".to_string())
+    }
+
+    fn error(msg: impl Into<String>) -> Self {
+        RespValue::Error(msg.into())
+    }
+
+    fn bulk(value: impl Into<Vec<u8>>) -> Self {
+        RespValue::BulkString(value.into())
+    }
+
+    /// Bulk string for a present value, null otherwise
+    fn optional(value: Option<String>) -> Self {
+        value.map_or(RespValue::Null, RespValue::bulk)
+    }
+
+    /// Append the encoding of the value for the given protocol version (2 or 3)
+    pub fn encode(&self, out: &mut Vec<u8>, protocol: u8) {
+        match self {
+            RespValue::SimpleString(s) => {
+                out.extend_from_slice(format!("+{}\r\n", s).as_bytes());
+            }
+            RespValue::Error(s) => {
+                out.extend_from_slice(format!("-{}\r\n", s).as_bytes());
+            }
+            RespValue::Integer(i) => {
+                out.extend_from_slice(format!(":{}\r\n", i).as_bytes());
+            }
+            RespValue::BulkString(bytes) => {
+                out.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+                out.extend_from_slice(bytes);
+                out.extend_from_slice(b"\r\n");
+            }
+            RespValue::Null if protocol >= 3 => out.extend_from_slice(b"_\r\n"),
+            RespValue::Null => out.extend_from_slice(b"$-1\r\n"),
+            RespValue::Array(values) => {
+                out.extend_from_slice(format!("*{}\r\n", values.len()).as_bytes());
+                for value in values {
+                    value.encode(out, protocol);
+                }
+            }
+            RespValue::Map(pairs) => {
+                if protocol >= 3 {
+                    out.extend_from_slice(format!("%{}\r\n", pairs.len()).as_bytes());
+                } else {
+                    out.extend_from_slice(format!("*{}\r\n", pairs.len() * 2).as_bytes());
+                }
+                for (key, value) in pairs {
+                    key.encode(out, protocol);
+                    value.encode(out, protocol);
+                }
+            }
+        }
+    }
+}
+
+/// Reads commands sent as RESP arrays of bulk strings or as inline commands
+pub struct RespReader<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    max_bulk_size: usize,
+    deadline: ReadDeadline,
+}
+
+impl<R: AsyncRead + Unpin> RespReader<R> {
+    pub fn new(reader: R, max_bulk_size: usize) -> Self {
+        RespReader {
+            reader,
+            buffer: Vec::new(),
+            max_bulk_size,
+            deadline: ReadDeadline::default(),
+        }
+    }
+
+    /// Fail reads that take longer than `timeouts`
+    pub fn with_timeouts(mut self, timeouts: ReadTimeouts) -> Self {
+        self.deadline = ReadDeadline::new(timeouts);
+        self
+    }
+
+    /// Read the arguments of the next command.
+    /// Returns `None` when the stream is closed between two commands.
+    pub async fn read_command(&mut self) -> Result<Option<Vec<Vec<u8>>>, Error> {
+        loop {
+            if let Some((args, consumed)) = self.parse()? {
+                self.buffer.drain(..consumed);
+                self.deadline.message_done();
+                if args.is_empty() {
+                    continue;
+                }
+                return Ok(Some(args));
+            }
+            let pending = !self.buffer.iter().all(u8::is_ascii_whitespace);
+            if self
+                .deadline
+                .read_buf(&mut self.reader, &mut self.buffer, pending)
+                .await?
+                == 0
+            {
+                if self.buffer.iter().all(u8::is_ascii_whitespace) {
+                    return Ok(None);
+                }
+                return Err(Error::ProtocolError(
+                    "connection closed in the middle of a command".to_string(),
+                ));
+            }
+        }
+    }
+
+    /// Parse the first complete command of the buffer, returning its
+    /// arguments and the number of bytes it spans
+    fn parse(&self) -> Result<Option<Parsed>, Error> {
+        let buf = &self.buffer[..];
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        if buf[0] != b'*' {
+            return Self::parse_inline(buf);
+        }
+
+        let Some((count, mut pos)) = parse_header(buf, 0)? else {
+            return Ok(None);
+        };
+        if count > MAX_ARGUMENTS as i64 {
+            return Err(Error::ProtocolError(format!(
+                "too many arguments: {}",
+                count
+            )));
+        }
+        let mut args = Vec::with_capacity(count.max(0) as usize);
+        for _ in 0..count.max(0) {
+            if pos >= buf.len() {
+                return Ok(None);
+            }
+            if buf[pos] != b'$' {
+                return Err(Error::ProtocolError(format!(
+                    "expected '$', got {:?}",
+                    buf[pos] as char
+                )));
+            }
+            let Some((len, start)) = parse_header(buf, pos)? else {
+                return Ok(None);
+            };
+            if len < 0 || len as usize > self.max_bulk_size {
+                return Err(Error::ProtocolError(format!(
+                    "invalid bulk length: {}",
+                    len
+                )));
+            }
+            let end = start + len as usize;
+            if buf.len() < end + 2 {
+                return Ok(None);
+            }
+            if &buf[end..end + 2] != b"\r\n" {
+                return Err(Error::ProtocolError(
+                    "bulk string not terminated by CRLF".to_string(),
+                ));
+            }
+            args.push(buf[start..end].to_vec());
+            pos = end + 2;
+        }
+        Ok(Some((args, pos)))
+    }
+
+    /// Parse a command sent as a plain line, as typed in telnet
+    fn parse_inline(buf: &[u8]) -> Result<Option<Parsed>, Error> {
+        let Some(newline) = buf.iter().position(|&b| b == b'\n') else {
+            if buf.len() > MAX_INLINE_SIZE {
+                return Err(Error::ProtocolError("inline command too long".to_string()));
+            }
+            return Ok(None);
+        };
+        let args = buf[..newline]
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        Ok(Some((args, newline + 1)))
+    }
+}
+
+/// Parse a `<type><integer>\r\n` header starting at `pos`, returning the
+/// integer and the position following the header
+fn parse_header(buf: &[u8], pos: usize) -> Result<Option<(i64, usize)>, Error> {
+    let Some(cr) = buf[pos..].windows(2).position(|w| w == b"\r\n") else {
+        if buf.len() - pos > MAX_INLINE_SIZE {
+            return Err(Error::ProtocolError("header too long".to_string()));
+        }
+        return Ok(None);
+    };
+    let digits = std::str::from_utf8(&buf[pos + 1..pos + cr])
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| Error::ProtocolError("invalid length in header".to_string()))?;
+    Ok(Some((digits, pos + cr + 2)))
+}
+
+/// Per-connection state of a RESP client
+#[derive(Debug, Clone, Copy)]
+pub struct RespSession {
+    /// Negotiated protocol version, 2 until the client sends `HELLO 3`
+    pub protocol: u8,
+    /// Set once the client asked to close the connection
+    pub quit: bool,
+}
+
+impl Default for RespSession {
+    fn default() -> Self {
+        RespSession {
+            protocol: 2,
+            quit: false,
+        }
+    }
+}
+
+/// Execute a command against the LSM Tree
+pub fn execute(db: &Db, args: Vec<Vec<u8>>, session: &mut RespSession) -> RespValue {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+    let args = &args[1..];
+    let result = match name.as_str() {
+        "PING" => match args {
+            [] => Ok(RespValue::SimpleString("PONG".to_string())),
+            [message] => Ok(RespValue::bulk(message.clone())),
+            _ => Err(wrong_arity(&name)),
+        },
+        "ECHO" => match args {
+            [message] => Ok(RespValue::bulk(message.clone())),
+            _ => Err(wrong_arity(&name)),
+        },
+        "QUIT" => {
+            session.quit = true;
+            Ok(RespValue::ok())
+        }
+        "HELLO" => hello(args, session),
+        "SELECT" => match args {
+            [db] if db == b"0" => Ok(RespValue::ok()),
+            [_] => Err(RespValue::error("ERR DB index is out of range")),
+            _ => Err(wrong_arity(&name)),
+        },
+        "CLIENT" => Ok(RespValue::ok()),
+        "COMMAND" => Ok(RespValue::Array(Vec::new())),
+        "INFO" => info(db),
+        "GET" => match args {
+            [key] => get(db, key),
+            _ => Err(wrong_arity(&name)),
+        },
+        "SET" => set(db, args),
+        "DEL" | "UNLINK" if !args.is_empty() => del(db, args),
+        "EXISTS" if !args.is_empty() => exists(db, args),
+        "MGET" if !args.is_empty() => mget(db, args),
+        "MSET" if !args.is_empty() && args.len().is_multiple_of(2) => mset(db, args),
+        "SCAN" if !args.is_empty() => scan(db, args),
+        "DEL" | "UNLINK" | "EXISTS" | "MGET" | "MSET" | "SCAN" => Err(wrong_arity(&name)),
+        "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" => match args {
+            [key, amount] => expire(db, &name, key, amount),
+            _ => Err(wrong_arity(&name)),
+        },
+        "TTL" | "PTTL" => match args {
+            [key] => ttl(db, key, name == "PTTL"),
+            _ => Err(wrong_arity(&name)),
+        },
+        "PERSIST" => match args {
+            [key] => persist(db, key),
+            _ => Err(wrong_arity(&name)),
+        },
+        "INCR" | "DECR" => match args {
+            [key] => increment(db, key, if name == "INCR" { 1 } else { -1 }),
+            _ => Err(wrong_arity(&name)),
+        },
+        "INCRBY" | "DECRBY" => match args {
+            [key, delta] => integer(delta).and_then(|delta| {
+                let delta = if name == "INCRBY" {
+                    Some(delta)
+                } else {
+                    delta.checked_neg()
+                };
+                let delta =
+                    delta.ok_or_else(|| RespValue::error("ERR decrement would overflow"))?;
+                increment(db, key, delta)
+            }),
+            _ => Err(wrong_arity(&name)),
+        },
+        _ => Err(RespValue::error(format!(
+            "ERR unknown command '{}'",
+            name.to_ascii_lowercase()
+        ))),
+    };
+    result.unwrap_or_else(|e| e)
+}
+
+fn wrong_arity(name: &str) -> RespValue {
+    RespValue::error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name.to_ascii_lowercase()
+    ))
+}
+
+fn engine_error(e: Error) -> RespValue {
+    match e {
+        Error::Busy(_) => RespValue::error(format!("BUSY {}", e)),
+        Error::Overflow(_) => RespValue::error("ERR increment or decrement would overflow"),
+        Error::OutOfMemory(_) => {
+            RespValue::error("OOM command not allowed when used memory > 'maxmemory'")
+        }
+        e => RespValue::error(format!("ERR {}", e)),
+    }
+}
+
+fn utf8(arg: &[u8]) -> Result<String, RespValue> {
+    String::from_utf8(arg.to_vec())
+        .map_err(|_| RespValue::error("ERR keys and values must be valid UTF-8"))
+}
+
+fn integer(arg: &[u8]) -> Result<i64, RespValue> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|a| a.parse().ok())
+        .ok_or_else(|| RespValue::error("ERR value is not an integer or out of range"))
+}
+
+/// Unix time in milliseconds at which a key expires, given an amount of
+/// seconds (`EX`), milliseconds (`PX`), Unix seconds (`EXAT`) or Unix
+/// milliseconds (`PXAT`)
+fn expires_at(unit: &[u8], amount: i64, now: u64) -> u64 {
+    let now = now as i64;
+    let at = match unit {
+        b"EX" => now.saturating_add(amount.saturating_mul(1000)),
+        b"PX" => now.saturating_add(amount),
+        b"EXAT" => amount.saturating_mul(1000),
+        _ => amount,
+    };
+    at.max(0) as u64
+}
+
+fn hello(args: &[Vec<u8>], session: &mut RespSession) -> Result<RespValue, RespValue> {
+    if let Some(version) = args.first() {
+        match version.as_slice() {
+            b"2" => session.protocol = 2,
+            b"3" => session.protocol = 3,
+            _ => return Err(RespValue::error("NOPROTO unsupported protocol version")),
+        }
+    }
+    Ok(RespValue::Map(vec![
+        (RespValue::bulk("server"), RespValue::bulk("rache")),
+        (
+            RespValue::bulk("version"),
+            RespValue::bulk(env!("CARGO_PKG_VERSION")),
+        ),
+        (
+            RespValue::bulk("proto"),
+            RespValue::Integer(session.protocol as i64),
+        ),
+        (RespValue::bulk("id"), RespValue::Integer(0)),
+        (RespValue::bulk("mode"), RespValue::bulk("standalone")),
+        (RespValue::bulk("role"), RespValue::bulk("master")),
+        (RespValue::bulk("modules"), RespValue::Array(Vec::new())),
+    ]))
+}
+
+fn info(db: &Db) -> Result<RespValue, RespValue> {
+    let mut info = format!(
+        "# Server\r\nredis_version:7.0.0\r\nrache_version:{}\r\nredis_mode:standalone\r\n",
+        env!("CARGO_PKG_VERSION")
+    );
+    if let Some(stats) = db.memory_stats().map_err(engine_error)? {
+        info.push_str(&format!(
+            "\r\n# Memory\r\nused_memory:{}\r\nmaxmemory:{}\r\nmaxmemory_policy:{}\r\n\
+             \r\n# Stats\r\nevicted_keys:{}\r\n",
+            stats.used_memory, stats.max_memory, stats.policy, stats.evicted_keys
+        ));
+    }
+    Ok(RespValue::bulk(info))
+}
+
+fn get(db: &Db, key: &[u8]) -> Result<RespValue, RespValue> {
+    let key = utf8(key)?;
+    db.get(&key).map(RespValue::optional).map_err(engine_error)
+}
+
+fn set(db: &Db, args: &[Vec<u8>]) -> Result<RespValue, RespValue> {
+    let [key, value, flags @ ..] = args else {
+        return Err(wrong_arity("SET"));
+    };
+    let key = utf8(key)?;
+    let value = utf8(value)?;
+    let (mut nx, mut xx, mut get, mut keep_ttl) = (false, false, false, false);
+    let mut expiry = None;
+    let mut flags = flags.iter();
+    while let Some(flag) = flags.next() {
+        let flag = flag.to_ascii_uppercase();
+        match flag.as_slice() {
+            b"NX" => nx = true,
+            b"XX" => xx = true,
+            b"GET" => get = true,
+            b"KEEPTTL" => keep_ttl = true,
+            b"EX" | b"PX" | b"EXAT" | b"PXAT" if expiry.is_none() => {
+                let amount = flags
+                    .next()
+                    .ok_or_else(|| RespValue::error("ERR syntax error"))?;
+                let amount = integer(amount)?;
+                if amount <= 0 {
+                    return Err(RespValue::error("ERR invalid expire time in 'set' command"));
+                }
+                expiry = Some((flag, amount));
+            }
+            _ => return Err(RespValue::error("ERR syntax error")),
+        }
+    }
+    if (nx && xx) || (keep_ttl && expiry.is_some()) {
+        return Err(RespValue::error("ERR syntax error"));
+    }
+
+    let cf = db.column_family_name();
+    let _locks = db.lock_keys(&[&key]).map_err(engine_error)?;
+    let mut lsm_tree = db.write_lock().map_err(engine_error)?;
+    let previous = if nx || xx || get || keep_ttl {
+        lsm_tree
+            .read_stored(cf, &key, &ReadOptions::default())
+            .map_err(engine_error)?
+    } else {
+        None
+    };
+    let skip = (nx && previous.is_some()) || (xx && previous.is_none());
+    if !skip {
+        let expires_at = match expiry {
+            Some((unit, amount)) => Some(expires_at(&unit, amount, now_millis())),
+            None if keep_ttl => previous.as_ref().and_then(|p| p.expires_at),
+            None => None,
+        };
+        let stored = StoredValue::with_expiry(value, expires_at);
+        lsm_tree
+            .write_stored(cf, key, stored)
+            .map_err(engine_error)?;
+    }
+    Ok(match (get, skip) {
+        (true, _) => RespValue::optional(previous.map(|p| p.value)),
+        (false, true) => RespValue::Null,
+        (false, false) => RespValue::ok(),
+    })
+}
+
+/// `EXPIRE`, `PEXPIRE`, `EXPIREAT` and `PEXPIREAT`. A time in the past
+/// expires the key at once.
+fn expire(db: &Db, name: &str, key: &[u8], amount: &[u8]) -> Result<RespValue, RespValue> {
+    let key = utf8(key)?;
+    let unit = match name {
+        "EXPIRE" => b"EX".as_slice(),
+        "PEXPIRE" => b"PX",
+        "EXPIREAT" => b"EXAT",
+        _ => b"PXAT",
+    };
+    let at = expires_at(unit, integer(amount)?, now_millis());
+    let updated = db.expire(&key, Expiry::At(at)).map_err(engine_error)?;
+    Ok(RespValue::Integer(updated as i64))
+}
+
+/// `TTL` in seconds or `PTTL` in milliseconds: -2 for a missing key and -1
+/// for a key that does not expire
+fn ttl(db: &Db, key: &[u8], millis: bool) -> Result<RespValue, RespValue> {
+    let key = utf8(key)?;
+    let ttl = match db.ttl(&key).map_err(engine_error)? {
+        TimeToLive::Missing => -2,
+        TimeToLive::Persistent => -1,
+        TimeToLive::Remaining(ms) if millis => ms as i64,
+        TimeToLive::Remaining(ms) => ms.saturating_add(500) as i64 / 1000,
+    };
+    Ok(RespValue::Integer(ttl))
+}
+
+fn persist(db: &Db, key: &[u8]) -> Result<RespValue, RespValue> {
+    let key = utf8(key)?;
+    let updated = db.persist(&key).map_err(engine_error)?;
+    Ok(RespValue::Integer(updated as i64))
+}
+
+fn increment(db: &Db, key: &[u8], delta: i64) -> Result<RespValue, RespValue> {
+    let key = utf8(key)?;
+    let value = db.increment(&key, delta).map_err(engine_error)?;
+    Ok(RespValue::Integer(value))
+}
+
+fn del(db: &Db, keys: &[Vec<u8>]) -> Result<RespValue, RespValue> {
+    let cf = db.column_family_name();
+    let keys = keys
+        .iter()
+        .map(|key| utf8(key))
+        .collect::<Result<Vec<_>, _>>()?;
+    let locked: Vec<&str> = keys.iter().map(String::as_str).collect();
+    let _locks = db.lock_keys(&locked).map_err(engine_error)?;
+    let mut lsm_tree = db.write_lock().map_err(engine_error)?;
+    let mut deleted = 0;
+    for key in keys {
+        if lsm_tree.read(cf, &key).map_err(engine_error)?.is_some() {
+            lsm_tree
+                .write(cf, key, String::new())
+                .map_err(engine_error)?;
+            deleted += 1;
+        }
+    }
+    Ok(RespValue::Integer(deleted))
+}
+
+fn exists(db: &Db, keys: &[Vec<u8>]) -> Result<RespValue, RespValue> {
+    let cf = db.column_family_name();
+    let lsm_tree = db.read_lock().map_err(engine_error)?;
+    let mut found = 0;
+    for key in keys {
+        if lsm_tree
+            .read(cf, &utf8(key)?)
+            .map_err(engine_error)?
+            .is_some()
+        {
+            found += 1;
+        }
+    }
+    Ok(RespValue::Integer(found))
+}
+
+fn mget(db: &Db, keys: &[Vec<u8>]) -> Result<RespValue, RespValue> {
+    // Keys that are not UTF-8 cannot be stored, hence do not exist
+    let utf8_keys: Vec<&str> = keys
+        .iter()
+        .filter_map(|key| std::str::from_utf8(key).ok())
+        .collect();
+    let mut found = db.multi_get(&utf8_keys).map_err(engine_error)?.into_iter();
+    let values = keys
+        .iter()
+        .map(|key| match std::str::from_utf8(key) {
+            Ok(_) => RespValue::optional(found.next().flatten()),
+            Err(_) => RespValue::Null,
+        })
+        .collect();
+    Ok(RespValue::Array(values))
+}
+
+fn mset(db: &Db, args: &[Vec<u8>]) -> Result<RespValue, RespValue> {
+    let mut batch = WriteBatch::new();
+    for pair in args.chunks(2) {
+        batch.put(&utf8(&pair[0])?, &utf8(&pair[1])?);
+    }
+    db.batch(batch).map_err(engine_error)?;
+    Ok(RespValue::ok())
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`. The cursor is the number of
+/// keys already visited in key order.
+fn scan(db: &Db, args: &[Vec<u8>]) -> Result<RespValue, RespValue> {
+    let after = decode_cursor(&args[0]).ok_or_else(|| RespValue::error("ERR invalid cursor"))?;
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    for option in args[1..].chunks(2) {
+        match (option[0].to_ascii_uppercase().as_slice(), option.get(1)) {
+            (b"MATCH", Some(p)) => pattern = Some(p.clone()),
+            (b"COUNT", Some(c)) => {
+                count = std::str::from_utf8(c)
+                    .ok()
+                    .and_then(|c| c.parse().ok())
+                    .filter(|&c| c > 0)
+                    .ok_or_else(|| {
+                        RespValue::error("ERR value is not an integer or out of range")
+                    })?
+            }
+            _ => return Err(RespValue::error("ERR syntax error")),
+        }
+    }
+
+    let start = after.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+    let entries = db
+        .scan(start, Bound::Unbounded, count)
+        .map_err(engine_error)?;
+    let next_cursor = match entries.last() {
+        Some((last, _)) if entries.len() == count => encode_cursor(last),
+        _ => "0".to_string(),
+    };
+    let keys = entries
+        .into_iter()
+        .map(|(key, _)| key)
+        .filter(|key| {
+            pattern
+                .as_ref()
+                .is_none_or(|p| glob_match(p, key.as_bytes()))
+        })
+        .map(RespValue::bulk)
+        .collect();
+    Ok(RespValue::Array(vec![
+        RespValue::bulk(next_cursor),
+        RespValue::Array(keys),
+    ]))
+}
+
+/// Cursor resuming a `SCAN` after `key`: a `1` followed by every byte of the
+/// key as 3 decimal digits. Cursors are digits, as clients parse them as
+/// integers, and never `0`, which starts and ends iterations.
+fn encode_cursor(key: &str) -> String {
+    let mut cursor = String::with_capacity(1 + 3 * key.len());
+    cursor.push('1');
+    for byte in key.bytes() {
+        cursor.push_str(&format!("{:03}", byte));
+    }
+    cursor
+}
+
+/// Key a `SCAN` cursor resumes after, `None` inside for the `0` cursor
+fn decode_cursor(cursor: &[u8]) -> Option<Option<String>> {
+    match cursor.split_first()? {
+        (b'0', []) => Some(None),
+        (b'1', digits) if digits.len() % 3 == 0 => {
+            let bytes = digits
+                .chunks(3)
+                .map(|chunk| std::str::from_utf8(chunk).ok()?.parse::<u8>().ok())
+                .collect::<Option<Vec<u8>>>()?;
+            String::from_utf8(bytes).ok().map(Some)
+        }
+        _ => None,
+    }
+}
+
+/// Match `text` against a Redis glob pattern supporting `*`, `?`, `[...]` and
+/// `\`. A mismatch after a `*` retries with the `*` matching one more byte,
+/// from the last `*` only, so matching takes at most `pattern * text` steps.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Pattern index after the last `*` and text index it was retried at
+    let mut star = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, t));
+            continue;
+        }
+        if let Some(len) = match_one(&pattern[p..], text[t]) {
+            p += len;
+            t += 1;
+            continue;
+        }
+        let Some((star_p, star_t)) = star else {
+            return false;
+        };
+        p = star_p;
+        t = star_t + 1;
+        star = Some((star_p, t));
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+/// Length of the element starting `pattern` if it matches the byte `c`,
+/// other than `*`
+fn match_one(pattern: &[u8], c: u8) -> Option<usize> {
+    match pattern.split_first()? {
+        (b'?', _) => Some(1),
+        (b'[', rest) => {
+            let close = rest.iter().skip(1).position(|&b| b == b']')? + 1;
+            let (class, negate) = match rest[..close].split_first() {
+                Some((b'^', class)) => (class, true),
+                _ => (&rest[..close], false),
+            };
+            let mut matched = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    matched |= (class[i]..=class[i + 2]).contains(&c);
+                    i += 3;
+                } else {
+                    matched |= class[i] == c;
+                    i += 1;
+                }
+            }
+            (matched != negate).then_some(close + 2)
+        }
+        (b'\\', [escaped, ..]) => (*escaped == c).then_some(2),
+        (&p, _) => (p == c).then_some(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::test_util::TestDb;
+
+    /// One `SCAN` call with `COUNT 2`, returning the next cursor and keys
+    fn scan_page(db: &Db, cursor: &[u8]) -> (Vec<u8>, Vec<Vec<u8>>) {
+        let args = [cursor.to_vec(), b"COUNT".to_vec(), b"2".to_vec()];
+        let RespValue::Array(reply) = scan(db, &args).unwrap() else {
+            panic!("SCAN did not reply with an array");
+        };
+        match reply.as_slice() {
+            [RespValue::BulkString(cursor), RespValue::Array(keys)] => {
+                let keys = keys
+                    .iter()
+                    .map(|key| match key {
+                        RespValue::BulkString(key) => key.clone(),
+                        other => panic!("unexpected key {:?}", other),
+                    })
+                    .collect();
+                (cursor.clone(), keys)
+            }
+            other => panic!("unexpected SCAN reply {:?}", other),
+        }
+    }
+
+    #[test]
+    fn scan_resumes_after_deleted_keys() {
+        let db = TestDb::open("resp-scan");
+        for key in ["0", "a", "b", "c", "d", "e"] {
+            db.put(key, "value").unwrap();
+        }
+
+        let (cursor, keys) = scan_page(&db, b"0");
+        assert_eq!(keys, [b"0".to_vec(), b"a".to_vec()]);
+        db.delete("0").unwrap();
+        db.delete("a").unwrap();
+        let (cursor, keys) = scan_page(&db, &cursor);
+        assert_eq!(keys, [b"b".to_vec(), b"c".to_vec()]);
+        let (cursor, keys) = scan_page(&db, &cursor);
+        assert_eq!(keys, [b"d".to_vec(), b"e".to_vec()]);
+        let (cursor, keys) = scan_page(&db, &cursor);
+        assert!(keys.is_empty());
+        assert_eq!(cursor, b"0");
+    }
+
+    #[test]
+    fn glob_patterns() {
+        let matches = |pattern: &str, text: &str| glob_match(pattern.as_bytes(), text.as_bytes());
+        assert!(matches("*", ""));
+        assert!(matches("user:*", "user:42"));
+        assert!(matches("*:4?", "user:42"));
+        assert!(matches("a*b*c", "aXbYbZc"));
+        assert!(!matches("a*b*c", "aXbYbZ"));
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("h[a-c]llo", "hbllo"));
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "x"));
+        assert!(!matches("[abc", "a"));
+        assert!(!matches("?", ""));
+
+        // Exponential with backtracking into every star
+        let text = "a".repeat(1000);
+        let pattern = format!("{}b", "*a".repeat(50));
+        assert!(!matches(&pattern, &text));
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        for key in ["0", "key", "é:\u{1}"] {
+            let cursor = encode_cursor(key);
+            assert!(cursor.bytes().all(|b| b.is_ascii_digit()));
+            assert_eq!(
+                decode_cursor(cursor.as_bytes()),
+                Some(Some(key.to_string()))
+            );
+        }
+        assert_eq!(decode_cursor(b"0"), Some(None));
+        for cursor in [&b""[..], b"00", b"10", b"1256", b"2097"] {
+            assert_eq!(decode_cursor(cursor), None);
+        }
+    }
+}
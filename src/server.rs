@@ -1,90 +1,993 @@
 use crate::{
-    common_enums::{Request, Response},
-    storage::LSMTree,
+    codec::{decode_payload, encode_frame, FrameReader, ReadTimeouts, MAX_FRAME_SIZE},
+    common_enums::{Request, RequestEnvelope, Response, ResponseEnvelope},
+    db::Db,
+    error::Error,
+    http,
+    memcached::{self, Command, MemcachedReader},
+    resp::{self, RespReader, RespSession, RespValue},
+    storage::Transaction,
+};
+use log::{error, info, warn};
+use serde::{de::IgnoredAny, Deserialize};
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::SocketAddr,
+    ops::Bound,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll},
+    time::Duration,
 };
-use rmp_serde::{Deserializer, Serializer};
-use serde::{Deserialize, Serialize};
-use std::sync::{Arc, Mutex};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufReader},
-    net::{TcpListener, TcpStream},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream},
+    sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore},
+    time::{Instant, Sleep},
 };
 
+/// Resolves once the server is shutting down
+async fn stopping(shutdown: &mut watch::Receiver<bool>) {
+    // An error means the server is gone, which is as good as stopping
+    let _ = shutdown.wait_for(|&stopping| stopping).await;
+}
+
+/// Number of tracked keys above which completed requests are forgotten
+const IN_FLIGHT_PRUNE_THRESHOLD: usize = 1024;
+
+/// Pause after a failed `accept`, which usually means the process is out of
+/// file descriptors
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Time allowed to send its rejection to a connection over the limit
+const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Transaction begun on a native connection, if any
+type ConnectionTransaction = Mutex<Option<Transaction>>;
+
+/// Limits protecting the server from misbehaving clients
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    /// Maximum size of a request or response frame, also bounding RESP bulk
+    /// strings, memcached items and HTTP bodies
+    pub max_frame_size: usize,
+    /// Maximum number of client connections open at once, over all listeners
+    pub max_connections: usize,
+    /// Maximum number of requests of a native connection being executed or
+    /// waiting for their response to be sent. Reading pauses at the limit.
+    pub max_in_flight_requests: usize,
+    /// Time allowed to a client to send its requests
+    pub timeouts: ReadTimeouts,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            max_frame_size: MAX_FRAME_SIZE,
+            max_connections: 1024,
+            max_in_flight_requests: 128,
+            timeouts: ReadTimeouts {
+                idle: Some(Duration::from_secs(300)),
+                read: Some(Duration::from_secs(30)),
+            },
+        }
+    }
+}
+
+/// Network front ends of a [`Db`]
 pub struct Server {
-    pub lsm_tree: Arc<Mutex<LSMTree>>,
+    db: Db,
+    options: ServerOptions,
+    /// Permits of the connections that may still be opened
+    connections: Arc<Semaphore>,
+    /// Set once the server is shutting down. Every listener and connection
+    /// holds a receiver, so the server is drained once they are all dropped.
+    shutdown: Arc<watch::Sender<bool>>,
 }
 
 impl Server {
-    pub fn new(lsm_tree: LSMTree) -> Self {
+    pub fn new(db: Db) -> Self {
+        Self::with_options(db, ServerOptions::default())
+    }
+
+    pub fn with_options(db: Db, options: ServerOptions) -> Self {
+        let (shutdown, _) = watch::channel(false);
         Server {
-            lsm_tree: Arc::new(Mutex::new(lsm_tree)),
+            db,
+            connections: Arc::new(Semaphore::new(options.max_connections)),
+            options,
+            shutdown: Arc::new(shutdown),
         }
     }
-    
-    async fn handle_client(&self, mut socket: TcpStream) {
-        let (reader, mut writer) = socket.split();
-        let mut reader = BufReader::new(reader);
-        let mut buffer = Vec::new();
 
-        loop {
-            buffer.clear();
-            let bytes_read = reader.read_buf(&mut buffer).await.unwrap();
-            if bytes_read == 0 {
-                break;
+    pub fn options(&self) -> &ServerOptions {
+        &self.options
+    }
+
+    /// Stop accepting connections and stop reading requests. Requests
+    /// already received are executed and answered.
+    pub fn shutdown(&self) {
+        info!("Shutting down, draining connections");
+        self.shutdown.send_replace(true);
+    }
+
+    /// Wait until every listener and connection has finished
+    pub async fn drained(&self) {
+        self.shutdown.closed().await;
+    }
+
+    /// Database the server executes requests against
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    /// Set the maximum size of a request or response frame
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.options.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Execute a single request against the database. Transaction requests
+    /// are rejected, they need a native connection.
+    pub fn execute(&self, request: Request) -> Result<Response, Error> {
+        self.execute_in(request, None)
+    }
+
+    /// Execute a request of a connection, transaction requests acting on
+    /// the transaction of the connection
+    fn execute_in(
+        &self,
+        request: Request,
+        transaction: Option<&ConnectionTransaction>,
+    ) -> Result<Response, Error> {
+        match request {
+            Request::Read { key, cf } => self.family(cf)?.get(&key).map(Response::Success),
+            Request::MultiGet { keys, cf } => {
+                let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+                self.family(cf)?.multi_get(&keys).map(Response::Values)
+            }
+            Request::Write {
+                key,
+                value,
+                expiry,
+                cf,
+            } => {
+                let db = self.family(cf)?;
+                match expiry {
+                    Some(expiry) => db.put_with_expiry(&key, &value, expiry),
+                    None => db.put(&key, &value),
+                }
+                .map(|_| Response::Success(None))
+            }
+            Request::Delete { key, cf } => self
+                .family(cf)?
+                .delete(&key)
+                .map(|_| Response::Success(None)),
+            Request::DeleteRange { start, end, cf } => self
+                .family(cf)?
+                .delete_range(&start, &end)
+                .map(|_| Response::Success(None)),
+            Request::Merge { key, operand, cf } => self
+                .family(cf)?
+                .merge(&key, &operand)
+                .map(|_| Response::Success(None)),
+            Request::Incr { key, delta, cf } => self
+                .family(cf)?
+                .increment(&key, delta)
+                .map(Response::Integer),
+            Request::Expire { key, expiry, cf } => {
+                self.family(cf)?.expire(&key, expiry).map(Response::Updated)
             }
+            Request::Persist { key, cf } => self.family(cf)?.persist(&key).map(Response::Updated),
+            Request::Ttl { key, cf } => self.family(cf)?.ttl(&key).map(Response::Ttl),
+            Request::ReadVersioned { key, cf } => self
+                .family(cf)?
+                .get_versioned(&key)
+                .map(Response::Versioned),
+            Request::CompareAndSwap {
+                key,
+                expected,
+                new,
+                cf,
+            } => self
+                .family(cf)?
+                .compare_and_swap(&key, expected, new.as_deref())
+                .map(Response::CompareAndSwap),
+            Request::Scan {
+                start,
+                end,
+                prefix,
+                limit,
+                cf,
+            } => {
+                let db = self.family(cf)?;
+                let start = start.as_deref().map_or(Bound::Unbounded, Bound::Included);
+                let end = end.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+                let entries = match prefix {
+                    Some(prefix) => db.scan_prefix_range(&prefix, start, end, limit)?,
+                    None => db.scan(start, end, limit)?,
+                };
+                Ok(Response::Entries(entries))
+            }
+            Request::Batch { requests } => Ok(Response::Batch(
+                requests
+                    .into_iter()
+                    .map(|request| {
+                        self.execute_in(request, transaction)
+                            .unwrap_or_else(Response::from)
+                    })
+                    .collect(),
+            )),
+            Request::TxBegin { cf } => {
+                let mut transaction = Self::lock_transaction(transaction)?;
+                if transaction.is_some() {
+                    return Err(Error::InvalidArgument(
+                        "a transaction is already in progress".to_string(),
+                    ));
+                }
+                *transaction = Some(self.family(cf)?.begin()?);
+                Ok(Response::Success(None))
+            }
+            Request::TxGet { key } => Self::lock_transaction(transaction)?
+                .as_mut()
+                .ok_or_else(no_transaction)?
+                .get(&key)
+                .map(Response::Success),
+            Request::TxPut { key, value } => Self::lock_transaction(transaction)?
+                .as_mut()
+                .ok_or_else(no_transaction)?
+                .put(&key, &value)
+                .map(|_| Response::Success(None)),
+            Request::TxCommit => {
+                let begun = Self::lock_transaction(transaction)?.take();
+                let begun = begun.ok_or_else(no_transaction)?;
+                self.db.commit(begun).map(|_| Response::Success(None))
+            }
+            Request::TxRollback => {
+                Self::lock_transaction(transaction)?
+                    .take()
+                    .ok_or_else(no_transaction)?;
+                Ok(Response::Success(None))
+            }
+            Request::CreateColumnFamily { name, options } => {
+                let options = match options {
+                    Some(options) => options,
+                    None => self.db.options()?,
+                };
+                self.db
+                    .create_column_family(&name, options)
+                    .map(|_| Response::Success(None))
+            }
+            Request::DropColumnFamily { name } => self
+                .db
+                .drop_column_family(&name)
+                .map(|_| Response::Success(None)),
+            Request::ListColumnFamilies => self.db.list_column_families().map(Response::Names),
+        }
+    }
 
-            let mut de = Deserializer::new(&buffer[..]);
-            let command: Result<Request, _> = Deserialize::deserialize(&mut de);
-            let response = match command {
-                Ok(Request::Read { key }) => {
-                    let lsm_tree = self.lsm_tree.lock().unwrap();
-                    match lsm_tree.read(&key) {
-                        Ok(Some(value)) => Response::Success(Some(value)),
-                        Ok(None) => Response::Success(None),
-                        Err(e) => Response::Error(e.to_string()),
-                    }
+    /// Handle on the column family named by a request, the one of the
+    /// server's database if `None`
+    fn family(&self, cf: Option<String>) -> Result<Db, Error> {
+        match cf {
+            Some(cf) => self.db.column_family(&cf),
+            None => Ok(self.db.clone()),
+        }
+    }
+
+    fn lock_transaction(
+        transaction: Option<&ConnectionTransaction>,
+    ) -> Result<MutexGuard<'_, Option<Transaction>>, Error> {
+        let transaction = transaction.ok_or_else(|| {
+            Error::InvalidArgument("transactions need a native connection".to_string())
+        })?;
+        Ok(transaction.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Decode a request envelope, recovering its id when the request itself is malformed
+    fn decode_envelope(payload: &[u8]) -> (u64, Result<Request, Error>) {
+        #[derive(Deserialize)]
+        struct PartialEnvelope {
+            id: u64,
+            #[allow(dead_code)]
+            request: IgnoredAny,
+        }
+
+        match decode_payload::<RequestEnvelope>(payload) {
+            Ok(envelope) => (envelope.id, Ok(envelope.request)),
+            Err(e) => {
+                let id = decode_payload::<PartialEnvelope>(payload).map_or(0, |p| p.id);
+                (id, Err(e))
+            }
+        }
+    }
+
+    /// Serve a connection.
+    ///
+    /// Requests are executed concurrently and answered as soon as they
+    /// complete, so responses may come back out of order. Requests touching
+    /// the same key are executed in the order they were received, and
+    /// barriers, such as scans and range deletions, in order with every
+    /// request. Reading pauses while too many responses are pending.
+    async fn handle_client(
+        &self,
+        socket: TcpStream,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> Result<(), Error> {
+        let (reader, writer) = socket.into_split();
+        let mut frames = FrameReader::new(reader, self.options.max_frame_size)
+            .with_timeouts(self.options.timeouts);
+        let (response_tx, response_rx) = mpsc::unbounded_channel();
+        // A permit is held by every request until its response is written
+        let in_flight_permits = Arc::new(Semaphore::new(self.options.max_in_flight_requests));
+        let writer_task = tokio::spawn(Self::write_responses(
+            writer,
+            response_rx,
+            self.options.max_frame_size,
+        ));
+        // Completion signal of the last request received for every key
+        let mut in_flight: HashMap<String, watch::Receiver<()>> = HashMap::new();
+        // Completion signal of the last barrier received, which every later
+        // request waits for
+        let mut barrier: Option<watch::Receiver<()>> = None;
+        // Rolled back when the connection closes
+        let transaction: Arc<ConnectionTransaction> = Arc::new(Mutex::new(None));
+
+        let result = loop {
+            let frame = tokio::select! {
+                frame = frames.read_frame() => frame,
+                _ = stopping(&mut shutdown) => break Ok(()),
+            };
+            let payload = match frame {
+                Ok(Some(payload)) => payload,
+                Ok(None) => break Ok(()),
+                Err(e @ Error::ProtocolError(_)) => {
+                    // The stream cannot be resynchronised, report and hang up
+                    let response = Response::Error {
+                        code: e.code(),
+                        message: e.to_string(),
+                    };
+                    let _ = response_tx.send((ResponseEnvelope { id: 0, response }, None));
+                    break Err(e);
+                }
+                Err(e) => break Err(e),
+            };
+            let permit = match Arc::clone(&in_flight_permits).acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => break Ok(()),
+            };
+
+            let (id, request) = match Self::decode_envelope(&payload) {
+                (id, Ok(request)) => (id, request),
+                (id, Err(e)) => {
+                    warn!("Malformed request {}: {}", id, e);
+                    let envelope = ResponseEnvelope {
+                        id,
+                        response: e.into(),
+                    };
+                    let _ = response_tx.send((envelope, Some(permit)));
+                    continue;
                 }
-                Ok(Request::Write { key, value }) => {
-                    let mut lsm_tree = self.lsm_tree.lock().unwrap();
-                    match lsm_tree.write(key, value) {
-                        Ok(_) => Response::Success(None),
-                        Err(e) => Response::Error(e.to_string()),
+            };
+
+            // Wait for earlier requests on the same keys, and for the last
+            // barrier, before executing
+            if in_flight.len() > IN_FLIGHT_PRUNE_THRESHOLD {
+                in_flight.retain(|_, done| done.has_changed().is_ok());
+            }
+            if barrier
+                .as_ref()
+                .is_some_and(|done| done.has_changed().is_err())
+            {
+                barrier = None;
+            }
+            let (done_tx, done_rx) = watch::channel(());
+            let mut predecessors: Vec<_> = barrier.iter().cloned().collect();
+            if request.is_barrier() {
+                // Requests received before the last barrier are waited for
+                // through it
+                predecessors.extend(in_flight.drain().map(|(_, done)| done));
+                barrier = Some(done_rx);
+            } else {
+                for key in request.keys() {
+                    if let Some(previous) = in_flight.insert(key.to_string(), done_rx.clone()) {
+                        predecessors.push(previous);
                     }
                 }
-                Ok(Request::Delete { key }) => {
-                    let mut lsm_tree = self.lsm_tree.lock().unwrap();
-                    match lsm_tree.write(key, "".to_string()) {
-                        Ok(_) => Response::Success(None),
-                        Err(e) => Response::Error(e.to_string()),
+            }
+
+            let server = self.clone();
+            let response_tx = response_tx.clone();
+            let transaction = Arc::clone(&transaction);
+            tokio::spawn(async move {
+                for mut previous in predecessors {
+                    // Resolves with an error once the previous request dropped its sender
+                    let _ = previous.changed().await;
+                }
+                let execute = move || server.execute_in(request, Some(&transaction));
+                let result = tokio::task::spawn_blocking(execute)
+                    .await
+                    .unwrap_or_else(|e| Err(Error::Busy(format!("request task failed: {}", e))));
+                let response = match result {
+                    Ok(response) => response,
+                    Err(e) => {
+                        warn!("Request {} failed: {}", id, e);
+                        e.into()
                     }
+                };
+                drop(done_tx);
+                let _ = response_tx.send((ResponseEnvelope { id, response }, Some(permit)));
+            });
+        };
+
+        // The writer finishes once every pending response has been sent
+        drop(response_tx);
+        writer_task
+            .await
+            .map_err(|e| Error::Busy(format!("writer task failed: {}", e)))??;
+        result
+    }
+
+    /// Write responses to the client as they are produced, releasing the
+    /// permit of each request once its response is written
+    async fn write_responses(
+        mut writer: OwnedWriteHalf,
+        mut responses: mpsc::UnboundedReceiver<(ResponseEnvelope, Option<OwnedSemaphorePermit>)>,
+        max_frame_size: usize,
+    ) -> Result<(), Error> {
+        while let Some((envelope, _permit)) = responses.recv().await {
+            let frame = match encode_frame(&envelope, max_frame_size) {
+                Ok(frame) => frame,
+                Err(e) => encode_frame(
+                    &ResponseEnvelope {
+                        id: envelope.id,
+                        response: e.into(),
+                    },
+                    max_frame_size,
+                )?,
+            };
+            writer.write_all(&frame).await?;
+        }
+        Ok(())
+    }
+
+    /// Accept clients of the native protocol on `addr` until shutdown
+    pub async fn run(&self, addr: &str) -> Result<(), Error> {
+        let listener = TcpListener::bind(addr).await?;
+        println!("Server running on {}", listener.local_addr()?);
+        self.serve_native(listener).await
+    }
+
+    /// Serve clients of the native protocol on the connections of
+    /// `listener` until shutdown
+    async fn serve_native(&self, listener: TcpListener) -> Result<(), Error> {
+        let busy = Error::Busy("too many connections".to_string());
+        let reject = encode_frame(
+            &ResponseEnvelope {
+                id: 0,
+                response: busy.into(),
+            },
+            self.options.max_frame_size,
+        )?;
+        self.serve(
+            listener,
+            "native",
+            reject,
+            |server, socket, shutdown| async move { server.handle_client(socket, shutdown).await },
+        )
+        .await
+    }
+
+    /// Serve a Redis client: commands are executed in order and answered
+    /// in RESP2, or RESP3 once negotiated with `HELLO 3`
+    async fn handle_resp_client(
+        &self,
+        socket: TcpStream,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> Result<(), Error> {
+        let (reader, mut writer) = socket.into_split();
+        let mut commands = RespReader::new(reader, self.options.max_frame_size)
+            .with_timeouts(self.options.timeouts);
+        let mut session = RespSession::default();
+        let mut out = Vec::new();
+
+        while !session.quit {
+            let command = tokio::select! {
+                command = commands.read_command() => command,
+                _ = stopping(&mut shutdown) => break,
+            };
+            let args = match command {
+                Ok(Some(args)) => args,
+                Ok(None) => break,
+                Err(e @ Error::ProtocolError(_)) => {
+                    out.clear();
+                    RespValue::Error(format!("ERR {}", e)).encode(&mut out, session.protocol);
+                    writer.write_all(&out).await?;
+                    return Err(e);
                 }
-                Err(e) => Response::Error(e.to_string()),
+                Err(e) => return Err(e),
             };
 
-            let mut buf = Vec::new();
-            response.serialize(&mut Serializer::new(&mut buf)).unwrap();
-            writer.write_all(&buf).await.unwrap();
+            let server = self.clone();
+            let (reply, next_session) = tokio::task::spawn_blocking(move || {
+                let reply = resp::execute(&server.db, args, &mut session);
+                (reply, session)
+            })
+            .await
+            .map_err(|e| Error::Busy(format!("command task failed: {}", e)))?;
+            session = next_session;
+
+            out.clear();
+            reply.encode(&mut out, session.protocol);
+            writer.write_all(&out).await?;
         }
+        Ok(())
     }
 
-    pub async fn run(&self, addr: &str) {
-        let listener = TcpListener::bind(addr).await.unwrap();
-        println!("Server running on {}", addr);
+    /// Serve a memcached client: commands are executed in order
+    async fn handle_memcached_client(
+        &self,
+        socket: TcpStream,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> Result<(), Error> {
+        let (reader, mut writer) = socket.into_split();
+        let mut commands = MemcachedReader::new(reader, self.options.max_frame_size)
+            .with_timeouts(self.options.timeouts);
 
         loop {
-            let (socket, _) = listener.accept().await.unwrap();
+            let command = tokio::select! {
+                command = commands.read_command() => command,
+                _ = stopping(&mut shutdown) => break,
+            };
+            let command = match command {
+                Ok(Some(Command::Quit)) | Ok(None) => break,
+                Ok(Some(command)) => command,
+                Err(e @ Error::ProtocolError(_)) => {
+                    writer
+                        .write_all(format!("SERVER_ERROR {}\r\n", e).as_bytes())
+                        .await?;
+                    return Err(e);
+                }
+                Err(e) => return Err(e),
+            };
+
             let server = self.clone();
+            let reply =
+                tokio::task::spawn_blocking(move || memcached::execute(&server.db, command))
+                    .await
+                    .map_err(|e| Error::Busy(format!("command task failed: {}", e)))?;
+            writer.write_all(&reply).await?;
+        }
+        Ok(())
+    }
+
+    /// Accept memcached clients speaking the text protocol on `addr`
+    pub async fn run_memcached(&self, addr: &str) -> Result<(), Error> {
+        let listener = TcpListener::bind(addr).await?;
+        println!("Memcached listener running on {}", listener.local_addr()?);
+        let reject = b"SERVER_ERROR too many open connections\r\n".to_vec();
+        self.serve(
+            listener,
+            "memcached",
+            reject,
+            |server, socket, shutdown| async move {
+                server.handle_memcached_client(socket, shutdown).await
+            },
+        )
+        .await
+    }
+
+    /// Serve the HTTP/JSON gateway on `addr`
+    pub async fn run_http(&self, addr: &str) -> Result<(), Error> {
+        let listener = TcpListener::bind(addr).await?;
+        println!("HTTP gateway running on {}", listener.local_addr()?);
+        self.serve_http(listener).await
+    }
+
+    /// Serve the HTTP/JSON gateway on the connections of `listener` until
+    /// shutdown, within the connection limit and read timeouts of the server
+    async fn serve_http(&self, listener: TcpListener) -> Result<(), Error> {
+        let listener = HttpListener {
+            listener,
+            connections: Arc::clone(&self.connections),
+            timeouts: self.options.timeouts,
+        };
+        let mut shutdown = self.shutdown.subscribe();
+        axum::serve(
+            listener,
+            http::router(self.clone(), self.options.max_frame_size),
+        )
+        .with_graceful_shutdown(async move { stopping(&mut shutdown).await })
+        .await?;
+        Ok(())
+    }
+
+    /// Accept connections until the server shuts down, serving each of them
+    /// on its own task. Connections over the limit are sent `reject` and closed.
+    async fn serve<F, Fut>(
+        &self,
+        listener: TcpListener,
+        protocol: &str,
+        reject: Vec<u8>,
+        handle: F,
+    ) -> Result<(), Error>
+    where
+        F: Fn(Server, TcpStream, watch::Receiver<bool>) -> Fut,
+        Fut: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        let mut shutdown = self.shutdown.subscribe();
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = stopping(&mut shutdown) => return Ok(()),
+            };
+            let (socket, peer) = match accepted {
+                Ok(conn) => conn,
+                Err(e) => {
+                    error!("Failed to accept {} connection: {}", protocol, e);
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+            let Ok(permit) = Arc::clone(&self.connections).try_acquire_owned() else {
+                warn!(
+                    "Rejecting {} connection from {}: too many connections",
+                    protocol, peer
+                );
+                let reject = reject.clone();
+                tokio::spawn(async move {
+                    // Best effort, the socket is closed right after
+                    let mut socket = socket;
+                    let _ = tokio::time::timeout(REJECT_TIMEOUT, socket.write_all(&reject)).await;
+                });
+                continue;
+            };
+            let connection = handle(self.clone(), socket, self.shutdown.subscribe());
+            let protocol = protocol.to_string();
             tokio::spawn(async move {
-                server.handle_client(socket).await;
+                if let Err(e) = connection.await {
+                    info!("{} connection with {} closed: {}", protocol, peer, e);
+                }
+                drop(permit);
             });
         }
     }
+
+    /// Accept Redis clients speaking RESP on `addr`
+    pub async fn run_resp(&self, addr: &str) -> Result<(), Error> {
+        let listener = TcpListener::bind(addr).await?;
+        println!("RESP listener running on {}", listener.local_addr()?);
+        let reject = b"-ERR max number of clients reached\r\n".to_vec();
+        self.serve(listener, "RESP", reject, |server, socket, shutdown| async move {
+            server.handle_resp_client(socket, shutdown).await
+        })
+        .await
+    }
+}
+
+/// Listener of the HTTP gateway. Connections over the limit are answered
+/// with a 503 and closed, like in [`Server::serve`].
+struct HttpListener {
+    listener: TcpListener,
+    connections: Arc<Semaphore>,
+    timeouts: ReadTimeouts,
+}
+
+impl axum::serve::Listener for HttpListener {
+    type Io = HttpConnection;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            let (socket, peer) = match self.listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    error!("Failed to accept HTTP connection: {}", e);
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+            let Ok(permit) = Arc::clone(&self.connections).try_acquire_owned() else {
+                warn!(
+                    "Rejecting HTTP connection from {}: too many connections",
+                    peer
+                );
+                tokio::spawn(async move {
+                    // Best effort, the socket is closed right after
+                    let mut socket = socket;
+                    let reject = b"HTTP/1.1 503 Service Unavailable\r\n\
+                                   content-length: 0\r\nconnection: close\r\n\r\n";
+                    let _ = tokio::time::timeout(REJECT_TIMEOUT, socket.write_all(reject)).await;
+                });
+                continue;
+            };
+            let connection = HttpConnection {
+                socket,
+                _permit: permit,
+                timeouts: self.timeouts,
+                request_start: None,
+                deadline: None,
+            };
+            return (connection, peer);
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.listener.local_addr()
+    }
+}
+
+/// Connection of the HTTP gateway, holding its connection permit and
+/// failing the reads that exceed the read timeouts of the server. A request
+/// starts with the first bytes read after a response, and ends with the
+/// next response.
+struct HttpConnection {
+    socket: TcpStream,
+    _permit: OwnedSemaphorePermit,
+    timeouts: ReadTimeouts,
+    /// When the first bytes of the pending request were read
+    request_start: Option<Instant>,
+    /// When the pending read times out
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl HttpConnection {
+    /// When a read started now times out, `None` if it may wait forever
+    fn read_deadline(&self) -> Option<Instant> {
+        match self.request_start {
+            Some(start) => self.timeouts.read.map(|t| start + t),
+            None => self.timeouts.idle.map(|t| Instant::now() + t),
+        }
+    }
+}
+
+impl AsyncRead for HttpConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        if let Poll::Ready(result) = Pin::new(&mut self.socket).poll_read(cx, buf) {
+            self.deadline = None;
+            if buf.filled().len() > filled {
+                self.request_start.get_or_insert_with(Instant::now);
+            }
+            return Poll::Ready(result);
+        }
+        if self.deadline.is_none() {
+            self.deadline = self
+                .read_deadline()
+                .map(|at| Box::pin(tokio::time::sleep_until(at)));
+        }
+        let expired = self
+            .deadline
+            .as_mut()
+            .is_some_and(|deadline| deadline.as_mut().poll(cx).is_ready());
+        if expired {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "HTTP request not received in time",
+            )));
+        }
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for HttpConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = Pin::new(&mut self.socket).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = written {
+            if n > 0 && self.request_start.take().is_some() {
+                // A read pending across the response now waits for the next
+                // request, under the idle timeout
+                match self.read_deadline() {
+                    Some(at) => {
+                        if let Some(deadline) = self.deadline.as_mut() {
+                            deadline.as_mut().reset(at);
+                        }
+                    }
+                    None => self.deadline = None,
+                }
+            }
+        }
+        written
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.socket).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.socket).poll_shutdown(cx)
+    }
 }
 
 impl Clone for Server {
     fn clone(&self) -> Self {
         Server {
-            lsm_tree: Arc::clone(&self.lsm_tree),
+            db: self.db.clone(),
+            options: self.options.clone(),
+            connections: Arc::clone(&self.connections),
+            shutdown: Arc::clone(&self.shutdown),
         }
     }
 }
+
+fn no_transaction() -> Error {
+    Error::InvalidArgument("no transaction in progress".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::test_util::TestDb;
+
+    #[tokio::test]
+    async fn barriers_are_ordered_with_pipelined_requests() {
+        let db = TestDb::open("server-barrier");
+        let server = Server::new(db.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler = server.clone();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let shutdown = handler.shutdown.subscribe();
+            handler.handle_client(socket, shutdown).await
+        });
+
+        // The write waits for the row lock of the transaction, so that
+        // requests racing it would overtake it
+        let mut transaction = server.db().begin_pessimistic().unwrap();
+        transaction.put("key", "locked").unwrap();
+        let write = |key: &str| Request::Write {
+            key: key.to_string(),
+            value: "value".to_string(),
+            expiry: None,
+            cf: None,
+        };
+        let requests = [
+            write("key"),
+            Request::Scan {
+                start: None,
+                end: None,
+                prefix: None,
+                limit: 10,
+                cf: None,
+            },
+            Request::DeleteRange {
+                start: "a".to_string(),
+                end: "z".to_string(),
+                cf: None,
+            },
+            Request::Read {
+                key: "key".to_string(),
+                cf: None,
+            },
+            write("later"),
+        ];
+        // Sent at once, without waiting for any response
+        let mut frames = Vec::new();
+        for (id, request) in requests.into_iter().enumerate() {
+            let envelope = RequestEnvelope {
+                id: id as u64,
+                request,
+            };
+            frames.extend(encode_frame(&envelope, MAX_FRAME_SIZE).unwrap());
+        }
+        let (reader, mut writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        writer.write_all(&frames).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        drop(transaction);
+
+        let mut reader = FrameReader::new(reader, MAX_FRAME_SIZE);
+        let mut responses = HashMap::new();
+        for _ in 0..5 {
+            let envelope: ResponseEnvelope = reader.read_message().await.unwrap().unwrap();
+            responses.insert(envelope.id, envelope.response);
+        }
+        assert_eq!(responses[&0], Response::Success(None));
+        let scanned = vec![("key".to_string(), "value".to_string())];
+        assert_eq!(responses[&1], Response::Entries(scanned));
+        assert_eq!(responses[&3], Response::Success(None));
+        assert_eq!(server.db().get("key").unwrap(), None);
+        assert_eq!(server.db().get("later").unwrap().as_deref(), Some("value"));
+    }
+
+    #[tokio::test]
+    async fn http_connections_share_the_limit_and_timeouts() {
+        use tokio::io::AsyncReadExt;
+
+        let db = TestDb::open("server-http-limit");
+        let options = ServerOptions {
+            max_connections: 1,
+            timeouts: ReadTimeouts {
+                idle: Some(Duration::from_millis(200)),
+                read: Some(Duration::from_millis(200)),
+            },
+            ..ServerOptions::default()
+        };
+        let server = Server::with_options(db.clone(), options);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let gateway = server.clone();
+        tokio::spawn(async move { gateway.serve_http(listener).await });
+
+        // A silent client holds the only connection until it times out
+        let mut silent = TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut rejected = TcpStream::connect(addr).await.unwrap();
+        let mut reply = String::new();
+        rejected.read_to_string(&mut reply).await.unwrap();
+        assert!(reply.starts_with("HTTP/1.1 503"), "{:?}", reply);
+        let mut rest = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), silent.read_to_end(&mut rest));
+        assert!(read.await.is_ok(), "idle connection was not closed");
+        drop(silent);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // Kept alive after its response, until it idles out
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let request = b"GET /kv/missing HTTP/1.1\r\nhost: rache\r\n\r\n";
+        client.write_all(request).await.unwrap();
+        let mut reply = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut reply));
+        assert!(read.await.is_ok(), "idle connection was not closed");
+        let reply = String::from_utf8(reply).unwrap();
+        assert!(reply.starts_with("HTTP/1.1 404"), "{:?}", reply);
+    }
+
+    #[tokio::test]
+    async fn shutdown_answers_received_requests_then_drains() {
+        let db = TestDb::open("server-shutdown");
+        let server = Server::new(db.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let native = server.clone();
+        let serving = tokio::spawn(async move { native.serve_native(listener).await });
+
+        // The write waits for the row lock of the transaction, so that it is
+        // still running when the server shuts down
+        let mut transaction = db.begin_pessimistic().unwrap();
+        transaction.put("key", "locked").unwrap();
+        let envelope = RequestEnvelope {
+            id: 1,
+            request: Request::Write {
+                key: "key".to_string(),
+                value: "value".to_string(),
+                expiry: None,
+                cf: None,
+            },
+        };
+        let (reader, mut writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let frame = encode_frame(&envelope, MAX_FRAME_SIZE).unwrap();
+        writer.write_all(&frame).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        server.shutdown();
+        serving.await.unwrap().unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
+        let drained = tokio::time::timeout(Duration::from_millis(100), server.drained()).await;
+        assert!(drained.is_err(), "drained with a request in flight");
+
+        drop(transaction);
+        let mut reader = FrameReader::new(reader, MAX_FRAME_SIZE);
+        let envelope: ResponseEnvelope = reader.read_message().await.unwrap().unwrap();
+        assert_eq!(
+            (envelope.id, envelope.response),
+            (1, Response::Success(None))
+        );
+        // No further request is read, the connection is closed
+        assert!(reader.read_frame().await.unwrap().is_none());
+        tokio::time::timeout(Duration::from_secs(5), server.drained())
+            .await
+            .unwrap();
+        assert_eq!(db.get("key").unwrap().as_deref(), Some("value"));
+    }
+}
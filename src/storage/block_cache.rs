@@ -0,0 +1,83 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// A block is identified by the id of its SSTable and its offset in the file
+type BlockKey = (u64, u64);
+
+/// LRU cache of verified SSTable data blocks
+pub(super) struct BlockCache {
+    capacity: usize,
+    next_file_id: AtomicU64,
+    inner: Mutex<CacheInner>,
+}
+
+#[derive(Default)]
+struct CacheInner {
+    blocks: HashMap<BlockKey, (Arc<Vec<u8>>, u64)>,
+    /// Blocks ordered from least to most recently used
+    lru: BTreeMap<u64, BlockKey>,
+    tick: u64,
+    usage: usize,
+}
+
+impl BlockCache {
+    /// Create a cache holding at most `capacity` bytes of blocks
+    pub fn new(capacity: usize) -> Self {
+        BlockCache {
+            capacity,
+            next_file_id: AtomicU64::new(0),
+            inner: Mutex::new(CacheInner::default()),
+        }
+    }
+
+    /// Allocate an id for a newly loaded SSTable
+    pub fn new_file_id(&self) -> u64 {
+        self.next_file_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Get a block, marking it as recently used
+    pub fn get(&self, file_id: u64, offset: u64) -> Option<Arc<Vec<u8>>> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.tick += 1;
+        let tick = inner.tick;
+        let (block, last_used) = inner.blocks.get_mut(&(file_id, offset))?;
+        let block = Arc::clone(block);
+        let previous = std::mem::replace(last_used, tick);
+        inner.lru.remove(&previous);
+        inner.lru.insert(tick, (file_id, offset));
+        Some(block)
+    }
+
+    /// Insert a block, evicting the least recently used ones when full
+    pub fn insert(&self, file_id: u64, offset: u64, block: Arc<Vec<u8>>) {
+        if block.len() > self.capacity {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.usage += block.len();
+        if let Some((old, last_used)) = inner.blocks.insert((file_id, offset), (block, tick)) {
+            inner.usage -= old.len();
+            inner.lru.remove(&last_used);
+        }
+        inner.lru.insert(tick, (file_id, offset));
+
+        while inner.usage > self.capacity {
+            let Some((_, key)) = inner.lru.pop_first() else {
+                break;
+            };
+            if let Some((evicted, _)) = inner.blocks.remove(&key) {
+                inner.usage -= evicted.len();
+            }
+        }
+    }
+}
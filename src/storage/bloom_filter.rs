@@ -3,13 +3,19 @@ use std::hash::Hasher;
 pub(super) struct BloomFilter {
     pub bit_array: Vec<bool>,
     pub size: usize,
+    pub num_hashes: u64,
 }
 
 impl BloomFilter {
-    pub fn new(size: usize) -> Self {
+    /// Create a Bloom filter sized for `num_keys` keys at `bits_per_key` bits each
+    pub fn with_bits_per_key(num_keys: usize, bits_per_key: usize) -> Self {
+        let size = (num_keys * bits_per_key).max(64);
+        // ln(2) * bits per key minimises the false positive rate
+        let num_hashes = ((bits_per_key as f64) * 0.69).round().clamp(1.0, 30.0) as u64;
         BloomFilter {
             bit_array: vec![false; size],
             size,
+            num_hashes,
         }
     }
 
@@ -21,14 +27,14 @@ impl BloomFilter {
     }
 
     pub fn insert(&mut self, key: &str) {
-        for i in 0..3 {
+        for i in 0..self.num_hashes {
             let index = self.hash(key, i);
             self.bit_array[index] = true;
         }
     }
 
     pub fn might_contain(&self, key: &str) -> bool {
-        for i in 0..3 {
+        for i in 0..self.num_hashes {
             let index = self.hash(key, i);
             if !self.bit_array[index] {
                 return false;
@@ -36,4 +42,4 @@ impl BloomFilter {
         }
         true
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,588 @@
+use std::{
+    fs,
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use log::{info, warn};
+
+use super::{
+    comparator::in_range, mem_table::MemTable, ss_table::PARTIAL_SUFFIX, BlockCache,
+    CompactionContext, CompactionFilter, Comparator, MergeOperator, Options, SSTable, StoredValue,
+};
+use crate::common_enums::CompactionStrategy;
+use crate::error::Error;
+
+/// Name of the column family every database has, which cannot be dropped
+pub const DEFAULT_COLUMN_FAMILY: &str = "default";
+
+/// Prefix of SSTables being written by a size-tiered compaction
+const COMPACTION_TMP_PREFIX: &str = "compaction_";
+
+/// Marks the Wal entries of column families other than the default one
+const WAL_FAMILY_PREFIX: char = '@';
+
+/// A keyspace of an LSM Tree with its own MemTable, levels of SSTables and
+/// options. Column families share the Wal of the LSM Tree.
+pub(super) struct ColumnFamily {
+    name: String,
+    pub(super) memtable: Arc<MemTable>,
+    sstable_dir: PathBuf,
+    /// SSTables of every level, newest first
+    pub(super) levels: Vec<Vec<Arc<SSTable>>>,
+    next_file_number: u64,
+    block_cache: Arc<BlockCache>,
+    pub(super) options: Options,
+    /// Comparator of the LSM Tree, ordering the keys of the column family
+    pub(super) comparator: Arc<dyn Comparator>,
+}
+
+impl ColumnFamily {
+    /// Open the column family whose SSTables are stored in `sstable_dir`,
+    /// creating the directory if needed
+    pub fn open(
+        name: &str,
+        sstable_dir: PathBuf,
+        options: Options,
+        comparator: Arc<dyn Comparator>,
+        block_cache: Arc<BlockCache>,
+    ) -> Result<Self, Error> {
+        info!("Opening column family {} in {:?}", name, sstable_dir);
+        fs::create_dir_all(&sstable_dir)?;
+        let memtable = MemTable::new(options.write_buffer_size, Arc::clone(&comparator));
+        let mut family = ColumnFamily {
+            name: name.to_string(),
+            memtable: Arc::new(memtable),
+            sstable_dir,
+            levels: vec![Vec::new()],
+            next_file_number: 0,
+            block_cache,
+            options,
+            comparator,
+        };
+        family.load_levels()?;
+        Ok(family)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Insert a value into the MemTable, given along with its encoding.
+    /// Merge operands stack on the current entry.
+    pub fn insert(&self, key: String, value: &StoredValue, encoded: String) -> Result<(), Error> {
+        if value.is_unresolved() {
+            self.memtable.merge(key, value.clone())
+        } else {
+            self.memtable.insert(key, encoded);
+            Ok(())
+        }
+    }
+
+    /// Flush the MemTable to a new level 0 SSTable. The caller resets the
+    /// Wal once every column family it holds entries of is flushed.
+    pub fn flush_memtable(&mut self) -> Result<(), Error> {
+        let number = self.new_file_number();
+        let sstable_path = self.sstable_path(0, number);
+        self.memtable
+            .flush_to_sstable(&sstable_path, self.options.block_size)?;
+        let sstable = self.load_sstable(&sstable_path)?;
+        self.levels[0].insert(0, sstable);
+
+        // Reset MemTable
+        self.memtable = Arc::new(MemTable::new(
+            self.memtable.max_size,
+            Arc::clone(&self.comparator),
+        ));
+        Ok(())
+    }
+
+    pub fn compact(
+        &mut self,
+        merge_operator: Option<&dyn MergeOperator>,
+        compaction_filter: Option<&dyn CompactionFilter>,
+    ) -> Result<(), Error> {
+        self.drop_deleted_sstables()?;
+        match self.options.compaction_strategy {
+            CompactionStrategy::SizeTiered => {
+                self.compact_size_tiered(merge_operator, compaction_filter)
+            }
+            CompactionStrategy::LevelBased => {
+                self.compact_level_based(merge_operator, compaction_filter)
+            }
+        }
+    }
+
+    /// Merge the SSTables holding keys in `start..end` into a single
+    /// SSTable of the last level, see [`super::LSMTree::compact_range`]
+    pub fn compact_range(
+        &mut self,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        merge_operator: Option<&dyn MergeOperator>,
+        compaction_filter: Option<&dyn CompactionFilter>,
+    ) -> Result<(), Error> {
+        self.drop_deleted_sstables()?;
+
+        let comparator = Arc::clone(&self.comparator);
+        let comparator = comparator.as_ref();
+        let mut selected: Vec<Vec<bool>> = self
+            .levels
+            .iter()
+            .map(|l| {
+                l.iter()
+                    .map(|t| overlaps(comparator, t, (start, end)))
+                    .collect()
+            })
+            .collect();
+        // Widen the selection until it holds every SSTable sharing keys with it
+        loop {
+            let ranges: Vec<(&str, &str)> = self
+                .levels
+                .iter()
+                .flatten()
+                .zip(selected.iter().flatten())
+                .filter(|(_, &s)| s)
+                .filter_map(|(t, _)| t.key_range())
+                .collect();
+            let mut changed = false;
+            for (sstables, flags) in self.levels.iter().zip(selected.iter_mut()) {
+                for (sstable, flag) in sstables.iter().zip(flags.iter_mut()) {
+                    let shares_keys = ranges.iter().any(|&(first, last)| {
+                        let range = (Bound::Included(first), Bound::Included(last));
+                        overlaps(comparator, sstable, range)
+                    });
+                    if !*flag && shares_keys {
+                        *flag = true;
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        // Collected newest first
+        let mut inputs = Vec::new();
+        for (sstables, flags) in self.levels.iter_mut().zip(&selected) {
+            let (taken, kept): (Vec<_>, Vec<_>) = std::mem::take(sstables)
+                .into_iter()
+                .zip(flags)
+                .partition(|(_, &flag)| flag);
+            *sstables = kept.into_iter().map(|(t, _)| t).collect();
+            inputs.extend(taken.into_iter().map(|(t, _)| t));
+        }
+        if inputs.is_empty() {
+            return Ok(());
+        }
+
+        let target = match self.options.compaction_strategy {
+            CompactionStrategy::SizeTiered => 0,
+            CompactionStrategy::LevelBased => self.options.max_levels - 1,
+        };
+        while self.levels.len() <= target {
+            self.levels.push(Vec::new());
+        }
+        let number = self.new_file_number();
+        let output_path = self.sstable_path(target, number);
+        let input_paths: Vec<&Path> = inputs.iter().rev().map(|t| t.path()).collect();
+        let context = CompactionContext {
+            column_family: &self.name,
+            level: target,
+            full_compaction: self.levels.iter().all(|l| l.is_empty()),
+            bottommost: true,
+        };
+        SSTable::merge(
+            &input_paths,
+            &output_path,
+            self.options.block_size,
+            true,
+            merge_operator,
+            compaction_filter.map(|filter| (filter, &context)),
+            &self.comparator,
+        )?;
+
+        // No other SSTable holds the merged keys, the output can go last
+        let output = self.load_sstable(&output_path)?;
+        self.levels[target].push(output);
+        Self::remove_sstables(inputs)
+    }
+
+    /// Whether no SSTable below `level` holds any data
+    fn is_bottommost(&self, level: usize) -> bool {
+        self.levels.iter().skip(level + 1).all(|l| l.is_empty())
+    }
+
+    /// Path of the SSTable with the given level and file number
+    fn sstable_path(&self, level: usize, number: u64) -> PathBuf {
+        self.sstable_dir
+            .join(format!("sstable_{}_{}.txt", level, number))
+    }
+
+    /// Allocate a new SSTable file number
+    fn new_file_number(&mut self) -> u64 {
+        let number = self.next_file_number;
+        self.next_file_number += 1;
+        number
+    }
+
+    fn load_sstable(&self, path: &Path) -> Result<Arc<SSTable>, Error> {
+        let comparator = Arc::clone(&self.comparator);
+        SSTable::load(
+            path,
+            &self.options,
+            comparator,
+            Arc::clone(&self.block_cache),
+        )
+        .map(Arc::new)
+    }
+
+    /// Load the levels of SSTables found in the SSTable directory
+    fn load_levels(&mut self) -> Result<(), Error> {
+        info!("Loading levels of column family {}...", self.name);
+        let mut files: Vec<(usize, u64, PathBuf)> = Vec::new();
+        for entry in fs::read_dir(&self.sstable_dir)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            if name.starts_with(COMPACTION_TMP_PREFIX) || name.ends_with(PARTIAL_SUFFIX) {
+                warn!("Removing leftover partial SSTable {:?}", path);
+                fs::remove_file(&path)?;
+                continue;
+            }
+            let parsed = name
+                .strip_prefix("sstable_")
+                .and_then(|n| n.strip_suffix(".txt"))
+                .and_then(|n| n.split_once('_'))
+                .and_then(|(level, number)| Some((level.parse().ok()?, number.parse().ok()?)));
+            if let Some((level, number)) = parsed {
+                files.push((level, number, path));
+            }
+        }
+
+        // Newest first inside every level
+        files.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+        let mut levels: Vec<Vec<Arc<SSTable>>> = vec![Vec::new()];
+        for (level, number, path) in files {
+            while levels.len() <= level {
+                levels.push(Vec::new());
+            }
+            levels[level].push(self.load_sstable(&path)?);
+            self.next_file_number = self.next_file_number.max(number + 1);
+        }
+        info!(
+            "Loaded {} levels, next file number {}",
+            levels.len(),
+            self.next_file_number
+        );
+        self.levels = levels;
+        Ok(())
+    }
+
+    /// Delete the data and index files of SSTables that were compacted away.
+    /// Snapshots still holding them keep reading from their open files.
+    fn remove_sstables(sstables: Vec<Arc<SSTable>>) -> Result<(), Error> {
+        for sstable in sstables {
+            fs::remove_file(sstable.path())?;
+            let index_path = sstable.path().with_extension("index");
+            if index_path.exists() {
+                fs::remove_file(index_path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the SSTables whose every key is deleted by a range tombstone
+    /// of the MemTable or of a newer SSTable
+    fn drop_deleted_sstables(&mut self) -> Result<(), Error> {
+        let mut range_tombstones = self.memtable.range_tombstones();
+        let mut dropped = Vec::new();
+        // Newest first, so that only older SSTables are matched against a tombstone
+        for sstables in self.levels.iter_mut() {
+            let mut kept = Vec::new();
+            for sstable in std::mem::take(sstables) {
+                let deleted = sstable.key_range().is_some_and(|(first, last)| {
+                    range_tombstones
+                        .iter()
+                        .any(|t| t.spans(self.comparator.as_ref(), first, last))
+                });
+                if deleted {
+                    dropped.push(sstable);
+                } else {
+                    range_tombstones.extend_from_slice(sstable.range_tombstones());
+                    kept.push(sstable);
+                }
+            }
+            *sstables = kept;
+        }
+        if !dropped.is_empty() {
+            info!(
+                "Dropping {} SSTables deleted by range tombstones",
+                dropped.len()
+            );
+            Self::remove_sstables(dropped)?;
+        }
+        Ok(())
+    }
+
+    /// Merge every SSTable of a level into a single SSTable of the next level
+    /// once the level holds too many SSTables. The last level is merged in place.
+    fn compact_level_based(
+        &mut self,
+        merge_operator: Option<&dyn MergeOperator>,
+        compaction_filter: Option<&dyn CompactionFilter>,
+    ) -> Result<(), Error> {
+        let mut level = 0;
+        while level < self.levels.len() {
+            if self.levels[level].len() < self.options.level_compaction_trigger(level) {
+                level += 1;
+                continue;
+            }
+            warn!("Compaction triggered for level {} of {}", level, self.name);
+
+            let target = (level + 1).min(self.options.max_levels - 1);
+            if target >= self.levels.len() {
+                self.levels.push(Vec::new());
+            }
+            let number = self.new_file_number();
+            let output_path = self.sstable_path(target, number);
+            let inputs = std::mem::take(&mut self.levels[level]);
+            let input_paths: Vec<&Path> = inputs.iter().rev().map(|t| t.path()).collect();
+            // Older SSTables of the target level may hold the deleted keys too
+            let drop_tombstones = self.levels[target].is_empty() && self.is_bottommost(target);
+            let context = CompactionContext {
+                column_family: &self.name,
+                level: target,
+                full_compaction: self.levels.iter().all(|l| l.is_empty()),
+                bottommost: drop_tombstones,
+            };
+            SSTable::merge(
+                &input_paths,
+                &output_path,
+                self.options.block_size,
+                drop_tombstones,
+                merge_operator,
+                compaction_filter.map(|filter| (filter, &context)),
+                &self.comparator,
+            )?;
+
+            let output = self.load_sstable(&output_path)?;
+            self.levels[target].insert(0, output);
+            Self::remove_sstables(inputs)?;
+
+            // Recursively compact the next level if needed
+            level = target.max(level + 1);
+        }
+        Ok(())
+    }
+
+    /// Merge runs of adjacent level 0 SSTables of similar size.
+    ///
+    /// The merged SSTable takes the place of the newest SSTable of the run so
+    /// that it keeps its position from newest to oldest.
+    fn compact_size_tiered(
+        &mut self,
+        merge_operator: Option<&dyn MergeOperator>,
+        compaction_filter: Option<&dyn CompactionFilter>,
+    ) -> Result<(), Error> {
+        while let Some((start, end)) = self.find_size_tier() {
+            warn!(
+                "Compaction triggered for SSTables {}..{} of level 0 of {}",
+                start, end, self.name
+            );
+            let number = self.new_file_number();
+            let tmp_path = self
+                .sstable_dir
+                .join(format!("{}{}.tmp", COMPACTION_TMP_PREFIX, number));
+            let input_paths: Vec<&Path> = self.levels[0][start..end]
+                .iter()
+                .rev()
+                .map(|t| t.path())
+                .collect();
+            let drop_tombstones = end == self.levels[0].len() && self.is_bottommost(0);
+            let context = CompactionContext {
+                column_family: &self.name,
+                level: 0,
+                full_compaction: drop_tombstones && start == 0,
+                bottommost: drop_tombstones,
+            };
+            SSTable::merge(
+                &input_paths,
+                &tmp_path,
+                self.options.block_size,
+                drop_tombstones,
+                merge_operator,
+                compaction_filter.map(|filter| (filter, &context)),
+                &self.comparator,
+            )?;
+
+            let mut inputs: Vec<Arc<SSTable>> = self.levels[0].drain(start..end).collect();
+            let newest = inputs.remove(0);
+            let output_path = newest.path().to_path_buf();
+            fs::rename(&tmp_path, &output_path)?;
+            fs::rename(
+                tmp_path.with_extension("index"),
+                output_path.with_extension("index"),
+            )?;
+            let output = self.load_sstable(&output_path)?;
+            self.levels[0].insert(start, output);
+            Self::remove_sstables(inputs)?;
+        }
+        Ok(())
+    }
+
+    /// Find a run of adjacent level 0 SSTables whose sizes are within
+    /// 50% of the run's average size and that is long enough to be merged
+    fn find_size_tier(&self) -> Option<(usize, usize)> {
+        let sstables = &self.levels[0];
+        let trigger = self.options.level0_compaction_trigger;
+        for start in 0..sstables.len() {
+            let mut total: u64 = 0;
+            let mut end = start;
+            while end < sstables.len() {
+                let size = sstables[end].size();
+                if let Some(average) = total.checked_div((end - start) as u64) {
+                    if size < average / 2 || size > average + average / 2 {
+                        break;
+                    }
+                }
+                total += size;
+                end += 1;
+            }
+            if end - start >= trigger {
+                return Some((start, end));
+            }
+        }
+        None
+    }
+}
+
+/// Reject column family names that cannot be used as a directory name or
+/// in the Wal format
+pub(super) fn validate_name(name: &str) -> Result<(), Error> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        && !name.starts_with('.');
+    if !valid {
+        return Err(Error::InvalidArgument(format!(
+            "column family name {:?} must be 1 to 64 ASCII letters, digits, '-', '_' or '.', \
+             not starting with '.'",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// Key identifying `key` of a column family across every column family,
+/// used for row locks
+pub(crate) fn qualified_key(cf: &str, key: &str) -> String {
+    if cf == DEFAULT_COLUMN_FAMILY {
+        key.to_string()
+    } else {
+        format!("{}:{}", cf, key)
+    }
+}
+
+/// Column family and key identified by a [`qualified_key`]
+pub(super) fn split_qualified_key(qualified: &str) -> (&str, &str) {
+    qualified
+        .split_once(':')
+        .unwrap_or((DEFAULT_COLUMN_FAMILY, qualified))
+}
+
+/// Key and value of the Wal entry of `key` in a column family. Entries of
+/// the default column family are logged as is, the others as
+/// `:@<cf>:<key>:<value>`, using the empty key no write may use.
+pub(super) fn wal_entry(cf: &str, key: &str, value: &str) -> (String, String) {
+    if cf == DEFAULT_COLUMN_FAMILY {
+        (key.to_string(), value.to_string())
+    } else {
+        let value = format!("{}{}:{}:{}", WAL_FAMILY_PREFIX, cf, key, value);
+        (String::new(), value)
+    }
+}
+
+/// Column family, key and value of a Wal entry logged by [`wal_entry`]
+pub(super) fn parse_wal_entry<'a>(key: &'a str, value: &'a str) -> (&'a str, &'a str, &'a str) {
+    if key.is_empty() {
+        let family = value
+            .strip_prefix(WAL_FAMILY_PREFIX)
+            .and_then(|rest| rest.split_once(':'))
+            .and_then(|(cf, rest)| Some((cf, rest.split_once(':')?)));
+        if let Some((cf, (key, value))) = family {
+            return (cf, key, value);
+        }
+    }
+    (DEFAULT_COLUMN_FAMILY, key, value)
+}
+
+/// Whether the key range of an SSTable intersects `range` in the order of
+/// `comparator`
+fn overlaps(
+    comparator: &dyn Comparator,
+    sstable: &SSTable,
+    (start, end): (Bound<&str>, Bound<&str>),
+) -> bool {
+    let Some((first, last)) = sstable.key_range() else {
+        return false;
+    };
+    in_range(comparator, (start, Bound::Unbounded), last)
+        && in_range(comparator, (Bound::Unbounded, end), first)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        storage::{test_util::TestDb, Options},
+        Db, WriteBatch,
+    };
+
+    #[test]
+    fn column_families_are_isolated_and_recovered_from_the_shared_wal() {
+        let mut db = TestDb::open("column-families");
+        db.create_column_family("users", Options::default())
+            .unwrap();
+        let users = db.column_family("users").unwrap();
+
+        db.put("key", "default").unwrap();
+        users.put("key", "users").unwrap();
+        let mut batch = WriteBatch::new();
+        batch
+            .put("only-default", "1")
+            .put_cf("users", "only-users", "2");
+        db.batch(batch).unwrap();
+        users.delete("key").unwrap();
+
+        let check = |db: &Db, users: &Db| {
+            assert_eq!(db.get("key").unwrap().as_deref(), Some("default"));
+            assert_eq!(users.get("key").unwrap(), None);
+            assert_eq!(db.get("only-users").unwrap(), None);
+            assert_eq!(users.get("only-users").unwrap().as_deref(), Some("2"));
+            assert_eq!(users.get("only-default").unwrap(), None);
+        };
+        check(&db, &users);
+
+        // Nothing is flushed: every column family is rebuilt from the Wal
+        db.reopen();
+        let users = db.column_family("users").unwrap();
+        assert_eq!(db.list_column_families().unwrap(), ["default", "users"]);
+        check(&db, &users);
+
+        // Flushed column families are read back from their own SSTables,
+        // next to Wal entries written after the flush
+        users.flush().unwrap();
+        db.put("after", "flush").unwrap();
+        users.put("after", "users").unwrap();
+        db.reopen();
+        let users = db.column_family("users").unwrap();
+        check(&db, &users);
+        assert_eq!(db.get("after").unwrap().as_deref(), Some("flush"));
+        assert_eq!(users.get("after").unwrap().as_deref(), Some("users"));
+    }
+}
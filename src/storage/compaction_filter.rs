@@ -0,0 +1,133 @@
+use log::warn;
+
+use super::StoredValue;
+
+/// Decides the fate of the values surviving a compaction.
+///
+/// Filters implement policies the engine knows nothing about, e.g. dropping
+/// records whose embedded timestamp is older than 30 days. The filter sees
+/// every live value written by a compaction, with the merge operands folded
+/// onto it when their base is known, but not tombstones, expired values or
+/// operands whose base lives in older SSTables.
+pub trait CompactionFilter: Send + Sync {
+    /// Name of the filter, used in logs
+    fn name(&self) -> &str;
+
+    /// Keep, remove or rewrite the `value` of `key`
+    fn filter(&self, context: &CompactionContext, key: &str, value: &str) -> FilterDecision;
+}
+
+/// What a [`CompactionFilter`] does with a value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterDecision {
+    /// Keep the value as it is
+    Keep,
+    /// Delete the key. Unless the compaction is the bottommost one, a
+    /// tombstone is written so that older values of the key stay deleted.
+    Remove,
+    /// Replace the value, keeping its version and expiry. An empty value
+    /// deletes the key like [`FilterDecision::Remove`].
+    ChangeValue(String),
+}
+
+/// The compaction a [`CompactionFilter`] is invoked by
+#[derive(Debug, Clone, Copy)]
+pub struct CompactionContext<'a> {
+    /// Column family being compacted
+    pub column_family: &'a str,
+    /// Level the compaction writes its output to
+    pub level: usize,
+    /// Whether every SSTable of the column family is part of the compaction
+    pub full_compaction: bool,
+    /// Whether no older SSTable holds the keys of the compaction, so that
+    /// removed keys are dropped rather than kept as tombstones
+    pub bottommost: bool,
+}
+
+/// Apply `filter` to a value written by a compaction
+pub(super) fn apply(
+    filter: &dyn CompactionFilter,
+    context: &CompactionContext,
+    key: &str,
+    stored: StoredValue,
+) -> StoredValue {
+    if stored.is_tombstone() || !stored.operands.is_empty() {
+        return stored;
+    }
+    match filter.filter(context, key, &stored.value) {
+        FilterDecision::Keep => stored,
+        FilterDecision::Remove => StoredValue::default(),
+        FilterDecision::ChangeValue(value) if value.contains('\n') => {
+            warn!(
+                "Keeping the value of key {}: compaction filter {} produced a value with newlines",
+                key,
+                filter.name()
+            );
+            stored
+        }
+        FilterDecision::ChangeValue(value) if value.is_empty() => StoredValue::default(),
+        FilterDecision::ChangeValue(value) => StoredValue {
+            value,
+            counter: false,
+            ..stored
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ops::Bound,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::storage::{test_util::TestDb, DEFAULT_COLUMN_FAMILY};
+
+    /// Drops `stale` values and upper-cases `shout` ones, recording the
+    /// compactions it runs in
+    #[derive(Default)]
+    struct TestFilter {
+        contexts: Mutex<Vec<(String, bool)>>,
+    }
+
+    impl CompactionFilter for TestFilter {
+        fn name(&self) -> &str {
+            "test"
+        }
+
+        fn filter(&self, context: &CompactionContext, _key: &str, value: &str) -> FilterDecision {
+            let mut contexts = self.contexts.lock().unwrap();
+            contexts.push((context.column_family.to_string(), context.bottommost));
+            match value {
+                "stale" => FilterDecision::Remove,
+                "shout" => FilterDecision::ChangeValue("SHOUT".to_string()),
+                _ => FilterDecision::Keep,
+            }
+        }
+    }
+
+    #[test]
+    fn filter_drops_values_during_compaction() {
+        let db = TestDb::open("compaction-filter");
+        let filter = Arc::new(TestFilter::default());
+        db.set_compaction_filter(filter.clone()).unwrap();
+        db.put("a", "fresh").unwrap();
+        db.put("b", "stale").unwrap();
+        db.put("c", "shout").unwrap();
+        db.flush().unwrap();
+        // Filters only run on compactions
+        assert_eq!(db.get("b").unwrap().as_deref(), Some("stale"));
+
+        db.compact_range(Bound::Unbounded, Bound::Unbounded)
+            .unwrap();
+        assert_eq!(db.get("a").unwrap().as_deref(), Some("fresh"));
+        assert_eq!(db.get("b").unwrap(), None);
+        assert_eq!(db.get("c").unwrap().as_deref(), Some("SHOUT"));
+        let contexts = filter.contexts.lock().unwrap();
+        assert_eq!(contexts.len(), 3);
+        assert!(contexts
+            .iter()
+            .all(|(cf, bottommost)| cf == DEFAULT_COLUMN_FAMILY && *bottommost));
+    }
+}
@@ -0,0 +1,356 @@
+use std::{cmp::Ordering, ops::Bound, sync::Arc};
+
+use crate::error::Error;
+
+/// Orders the keys of an LSM Tree.
+///
+/// The comparator is chosen when the database is created and its name is
+/// persisted in the OPTIONS file: opening the database with a comparator of
+/// another name fails, since its SSTables are sorted by the original one.
+pub trait Comparator: Send + Sync {
+    /// Name of the comparator, persisted along the database
+    fn name(&self) -> &str;
+
+    /// Total order of the keys, in which only identical keys are equal
+    fn compare(&self, a: &str, b: &str) -> Ordering;
+
+    /// Bounds of the keys starting with `prefix`, `None` if they are not
+    /// contiguous in this order, in which case prefix scans read every key
+    fn prefix_bounds(&self, _prefix: &str) -> Option<(Bound<String>, Bound<String>)> {
+        None
+    }
+}
+
+/// Orders keys by their bytes, the default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bytewise;
+
+impl Comparator for Bytewise {
+    fn name(&self) -> &str {
+        "bytewise"
+    }
+
+    fn compare(&self, a: &str, b: &str) -> Ordering {
+        a.cmp(b)
+    }
+
+    fn prefix_bounds(&self, prefix: &str) -> Option<(Bound<String>, Bound<String>)> {
+        let end = prefix_successor(prefix).map_or(Bound::Unbounded, Bound::Excluded);
+        Some((Bound::Included(prefix.to_string()), end))
+    }
+}
+
+/// Orders keys by their bytes, greatest first, e.g. for newest-first
+/// indexes keyed by time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReverseBytewise;
+
+impl Comparator for ReverseBytewise {
+    fn name(&self) -> &str {
+        "reverse-bytewise"
+    }
+
+    fn compare(&self, a: &str, b: &str) -> Ordering {
+        b.cmp(a)
+    }
+
+    fn prefix_bounds(&self, prefix: &str) -> Option<(Bound<String>, Bound<String>)> {
+        let start = prefix_successor(prefix).map_or(Bound::Unbounded, Bound::Excluded);
+        Some((start, Bound::Included(prefix.to_string())))
+    }
+}
+
+/// Orders keys spelling a `u64` in decimal by their numeric value. Keys are
+/// UTF-8 strings, so this is not the byte order of big-endian encoded
+/// integers: `"9"` sorts before `"10"`. Other keys follow the numbers in byte
+/// order, and so do equal numbers spelled with leading zeros.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DecimalU64;
+
+impl Comparator for DecimalU64 {
+    fn name(&self) -> &str {
+        "decimal-u64"
+    }
+
+    fn compare(&self, a: &str, b: &str) -> Ordering {
+        match (a.parse::<u64>(), b.parse::<u64>()) {
+            (Ok(x), Ok(y)) => x.cmp(&y).then_with(|| a.cmp(b)),
+            (Ok(_), Err(_)) => Ordering::Less,
+            (Err(_), Ok(_)) => Ordering::Greater,
+            (Err(_), Err(_)) => a.cmp(b),
+        }
+    }
+}
+
+/// Built-in comparator named `name`: `bytewise`, `reverse-bytewise` or
+/// `decimal-u64`
+pub fn builtin_comparator(name: &str) -> Result<Arc<dyn Comparator>, Error> {
+    match name {
+        "bytewise" => Ok(Arc::new(Bytewise)),
+        "reverse-bytewise" => Ok(Arc::new(ReverseBytewise)),
+        "decimal-u64" => Ok(Arc::new(DecimalU64)),
+        _ => Err(Error::InvalidArgument(format!(
+            "unknown comparator {:?}, expected bytewise, reverse-bytewise or decimal-u64",
+            name
+        ))),
+    }
+}
+
+/// A key ordered by a comparator, for the ordered collections of the engine
+#[derive(Clone)]
+pub(super) struct OrderedKey {
+    key: String,
+    comparator: Arc<dyn Comparator>,
+}
+
+impl OrderedKey {
+    pub fn new(key: String, comparator: &Arc<dyn Comparator>) -> Self {
+        OrderedKey {
+            key,
+            comparator: Arc::clone(comparator),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.key
+    }
+
+    pub fn into_string(self) -> String {
+        self.key
+    }
+}
+
+impl Ord for OrderedKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.comparator.compare(&self.key, &other.key)
+    }
+}
+
+impl PartialOrd for OrderedKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for OrderedKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for OrderedKey {}
+
+/// Bounds of a range of ordered keys, `None` if the range holds no key
+pub(super) fn ordered_range(
+    comparator: &Arc<dyn Comparator>,
+    start: Bound<&str>,
+    end: Bound<&str>,
+) -> Option<(Bound<OrderedKey>, Bound<OrderedKey>)> {
+    if let (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) =
+        (start, end)
+    {
+        let both_included = matches!((start, end), (Bound::Included(_), Bound::Included(_)));
+        match comparator.compare(s, e) {
+            Ordering::Greater => return None,
+            Ordering::Equal if !both_included => return None,
+            _ => {}
+        }
+    }
+    let ordered =
+        |bound: Bound<&str>| bound.map(|key| OrderedKey::new(key.to_string(), comparator));
+    Some((ordered(start), ordered(end)))
+}
+
+/// Whether `key` is in `start..end` in the order of `comparator`
+pub(super) fn in_range(
+    comparator: &dyn Comparator,
+    (start, end): (Bound<&str>, Bound<&str>),
+    key: &str,
+) -> bool {
+    let after_start = match start {
+        Bound::Included(start) => comparator.compare(key, start) != Ordering::Less,
+        Bound::Excluded(start) => comparator.compare(key, start) == Ordering::Greater,
+        Bound::Unbounded => true,
+    };
+    let before_end = match end {
+        Bound::Included(end) => comparator.compare(key, end) != Ordering::Greater,
+        Bound::Excluded(end) => comparator.compare(key, end) == Ordering::Less,
+        Bound::Unbounded => true,
+    };
+    after_start && before_end
+}
+
+/// Get at most `limit` key-value pairs whose key starts with `prefix` and
+/// is in `range` with `scan`, over the bounds of the prefix within `range`
+/// when the comparator knows them and over all of `range` otherwise
+pub(super) fn scan_prefix<F>(
+    comparator: &dyn Comparator,
+    prefix: &str,
+    (start, end): (Bound<&str>, Bound<&str>),
+    limit: usize,
+    scan: F,
+) -> Result<Vec<(String, String)>, Error>
+where
+    F: FnOnce(Bound<&str>, Bound<&str>, usize) -> Result<Vec<(String, String)>, Error>,
+{
+    match comparator.prefix_bounds(prefix) {
+        Some((prefix_start, prefix_end)) => {
+            let prefix_start = prefix_start.as_ref().map(String::as_str);
+            let prefix_end = prefix_end.as_ref().map(String::as_str);
+            scan(
+                tighter_bound(comparator, start, prefix_start, Ordering::Greater),
+                tighter_bound(comparator, end, prefix_end, Ordering::Less),
+                limit,
+            )
+        }
+        None => {
+            let mut entries = scan(start, end, usize::MAX)?;
+            entries.retain(|(key, _)| key.starts_with(prefix));
+            entries.truncate(limit);
+            Ok(entries)
+        }
+    }
+}
+
+/// The tighter of two start bounds if `tighter` is [`Ordering::Greater`],
+/// of two end bounds if it is [`Ordering::Less`]
+fn tighter_bound<'a>(
+    comparator: &dyn Comparator,
+    a: Bound<&'a str>,
+    b: Bound<&'a str>,
+    tighter: Ordering,
+) -> Bound<&'a str> {
+    match (a, b) {
+        (Bound::Unbounded, bound) | (bound, Bound::Unbounded) => bound,
+        (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y)) => {
+            match comparator.compare(x, y) {
+                Ordering::Equal if matches!(a, Bound::Included(_)) => b,
+                Ordering::Equal => a,
+                order if order == tighter => a,
+                _ => b,
+            }
+        }
+    }
+}
+
+/// Smallest string greater than every string starting with `prefix`,
+/// `None` if there is no such string
+fn prefix_successor(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        if let Some(next) = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        storage::{test_util::TestDb, Options},
+        Db,
+    };
+
+    /// Keys of `keys` a prefix scan in `start..end` returns
+    fn prefix_scan(
+        comparator: &dyn Comparator,
+        keys: &[&str],
+        prefix: &str,
+        range: (Bound<&str>, Bound<&str>),
+        limit: usize,
+    ) -> Vec<String> {
+        let entries = scan_prefix(comparator, prefix, range, limit, |start, end, limit| {
+            let mut keys: Vec<&str> = keys
+                .iter()
+                .copied()
+                .filter(|key| in_range(comparator, (start, end), key))
+                .collect();
+            keys.sort_by(|a, b| comparator.compare(a, b));
+            keys.truncate(limit);
+            Ok(keys
+                .into_iter()
+                .map(|key| (key.to_string(), String::new()))
+                .collect())
+        });
+        entries.unwrap().into_iter().map(|(key, _)| key).collect()
+    }
+
+    #[test]
+    fn prefix_scan_within_range() {
+        let keys = ["a", "user:1", "user:2", "user:3", "user:4", "v"];
+        let range = (Bound::Excluded("user:1"), Bound::Included("user:3"));
+        assert_eq!(
+            prefix_scan(&Bytewise, &keys, "user:", range, 10),
+            ["user:2", "user:3"]
+        );
+        assert_eq!(prefix_scan(&Bytewise, &keys, "user:", range, 1), ["user:2"]);
+        let range = (Bound::Included("user:3"), Bound::Unbounded);
+        assert_eq!(
+            prefix_scan(&ReverseBytewise, &keys, "user:", range, 10),
+            ["user:3", "user:2", "user:1"]
+        );
+        let range = (Bound::Included("b"), Bound::Excluded("c"));
+        assert!(prefix_scan(&Bytewise, &keys, "user:", range, 10).is_empty());
+
+        // Prefixes of numbers are not contiguous in numeric order
+        let keys = ["1", "2", "10", "11", "20", "100"];
+        let range = (Bound::Included("5"), Bound::Unbounded);
+        assert_eq!(prefix_scan(&DecimalU64, &keys, "1", range, 2), ["10", "11"]);
+    }
+
+    #[test]
+    fn opening_with_another_comparator_fails() {
+        let mut db = TestDb::with_comparator("comparator-mismatch", Arc::new(DecimalU64));
+        db.put("10", "ten").unwrap();
+        db.close().unwrap();
+
+        let result = Db::open(db.path(), Options::default());
+        assert!(matches!(result, Err(Error::InvalidArgument(_))));
+        let result =
+            Db::open_with_comparator(db.path(), Options::default(), Arc::new(ReverseBytewise));
+        assert!(matches!(result, Err(Error::InvalidArgument(_))));
+        db.reopen();
+        assert_eq!(db.get("10").unwrap().as_deref(), Some("ten"));
+    }
+
+    #[test]
+    fn reverse_order_scans() {
+        let db = TestDb::with_comparator("comparator-reverse", Arc::new(ReverseBytewise));
+        for key in ["a", "c", "e"] {
+            db.put(key, key).unwrap();
+        }
+        db.flush().unwrap();
+        for key in ["b", "d", "f"] {
+            db.put(key, key).unwrap();
+        }
+        let keys = |start: Bound<&str>, end: Bound<&str>| -> Vec<String> {
+            let entries = db.scan(start, end, usize::MAX).unwrap();
+            entries.into_iter().map(|(key, _)| key).collect()
+        };
+        assert_eq!(
+            keys(Bound::Unbounded, Bound::Unbounded),
+            ["f", "e", "d", "c", "b", "a"]
+        );
+        assert_eq!(
+            keys(Bound::Included("e"), Bound::Excluded("b")),
+            ["e", "d", "c"]
+        );
+        assert!(keys(Bound::Included("b"), Bound::Excluded("e")).is_empty());
+
+        // Ranges are deleted in the order of the comparator too
+        db.delete_range("e", "c").unwrap();
+        db.compact_range(Bound::Unbounded, Bound::Unbounded)
+            .unwrap();
+        assert_eq!(
+            keys(Bound::Unbounded, Bound::Unbounded),
+            ["f", "c", "b", "a"]
+        );
+        assert_eq!(
+            db.scan_prefix("c", 10).unwrap(),
+            [("c".to_string(), "c".to_string())]
+        );
+    }
+}
@@ -0,0 +1,322 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    ops::Bound,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::{column_family::split_qualified_key, comparator::in_range, Comparator, StoredValue};
+use crate::common_enums::EvictionPolicy;
+
+/// Keys sampled to pick an LRU or LFU victim, as Redis' `maxmemory-samples`
+const EVICTION_SAMPLES: usize = 5;
+
+/// Bytes accounted for every key on top of its key and value
+const ENTRY_OVERHEAD: usize = 64;
+
+/// LFU counter of a new key, so that it is not evicted before being read
+const LFU_INIT: u8 = 5;
+
+/// The higher the factor, the more accesses it takes to grow the LFU counter
+const LFU_LOG_FACTOR: f64 = 10.0;
+
+/// Milliseconds of inactivity decrementing the LFU counter by one
+const LFU_DECAY_MILLIS: u64 = 60_000;
+
+/// Memory accounting of a database with a memory limit
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryStats {
+    /// Approximate size in bytes of the keys and values
+    pub used_memory: usize,
+    pub max_memory: usize,
+    pub policy: EvictionPolicy,
+    /// Number of keys evicted since the database was opened
+    pub evicted_keys: u64,
+}
+
+/// Access metadata of a key
+struct KeyStats {
+    size: usize,
+    expires_at: Option<u64>,
+    /// Unix time in milliseconds of the last read or write
+    last_access: u64,
+    /// Logarithmic access counter
+    lfu: u8,
+    /// Position of the key in `KeyTracker::keys`
+    slot: usize,
+}
+
+/// Tracks the size and accesses of every live key so that keys can be
+/// evicted once the memory limit is reached. Keys of every column family
+/// are tracked together, by their qualified key, and an evicted key is
+/// deleted from its column family: this is a cache policy, for databases
+/// whose data may be lost.
+///
+/// LRU and LFU victims are approximated as Redis does: the best victim
+/// among a few randomly sampled keys is evicted.
+pub(super) struct KeyTracker {
+    policy: EvictionPolicy,
+    max_memory: usize,
+    used_memory: usize,
+    stats: HashMap<String, KeyStats>,
+    /// Every tracked key, to sample from
+    keys: Vec<String>,
+    /// Keys with an expiry, soonest expiring first
+    volatile: BTreeSet<(u64, String)>,
+    evicted_keys: u64,
+    rng: u64,
+}
+
+impl KeyTracker {
+    pub fn new(policy: EvictionPolicy, max_memory: usize) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        KeyTracker {
+            policy,
+            max_memory,
+            used_memory: 0,
+            stats: HashMap::new(),
+            keys: Vec::new(),
+            volatile: BTreeSet::new(),
+            evicted_keys: 0,
+            // xorshift must not start from 0
+            rng: seed | 1,
+        }
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        MemoryStats {
+            used_memory: self.used_memory,
+            max_memory: self.max_memory,
+            policy: self.policy,
+            evicted_keys: self.evicted_keys,
+        }
+    }
+
+    /// Bytes the memory usage grows by if `value` is written to `key`
+    pub fn growth(&self, key: &str, value: &StoredValue) -> isize {
+        let old = self.stats.get(key).map_or(0, |s| s.size);
+        let new = if value.is_tombstone() {
+            0
+        } else {
+            entry_size(key, value)
+        };
+        new as isize - old as isize
+    }
+
+    /// Whether growing the memory usage by `growth` bytes exceeds the limit
+    pub fn exceeds_limit(&self, growth: usize) -> bool {
+        self.used_memory.saturating_add(growth) > self.max_memory
+    }
+
+    /// Record a write, tombstones untrack their key
+    pub fn record_write(&mut self, key: &str, value: &StoredValue, now: u64) {
+        if value.is_tombstone() {
+            self.remove(key);
+            return;
+        }
+        let size = entry_size(key, value);
+        let mut is_new = false;
+        let stats = self.stats.entry(key.to_string()).or_insert_with(|| {
+            is_new = true;
+            KeyStats {
+                size: 0,
+                expires_at: None,
+                last_access: now,
+                lfu: LFU_INIT,
+                slot: self.keys.len(),
+            }
+        });
+        if is_new {
+            self.keys.push(key.to_string());
+        }
+        self.used_memory -= stats.size;
+        if let Some(at) = stats.expires_at {
+            self.volatile.remove(&(at, key.to_string()));
+        }
+        stats.size = size;
+        stats.expires_at = value.expires_at;
+        self.used_memory += size;
+        if let Some(at) = value.expires_at {
+            self.volatile.insert((at, key.to_string()));
+        }
+        self.touch(key, now);
+    }
+
+    /// Record a read of a live key
+    pub fn touch(&mut self, key: &str, now: u64) {
+        let random = self.next_random();
+        if let Some(stats) = self.stats.get_mut(key) {
+            let lfu = decayed_lfu(stats, now);
+            stats.lfu = incremented_lfu(lfu, random);
+            stats.last_access = now;
+        }
+    }
+
+    /// Stop tracking a key
+    pub fn remove(&mut self, key: &str) {
+        let Some(stats) = self.stats.remove(key) else {
+            return;
+        };
+        self.used_memory -= stats.size;
+        if let Some(at) = stats.expires_at {
+            self.volatile.remove(&(at, key.to_string()));
+        }
+        self.keys.swap_remove(stats.slot);
+        if let Some(moved) = self.keys.get(stats.slot) {
+            if let Some(moved) = self.stats.get_mut(moved) {
+                moved.slot = stats.slot;
+            }
+        }
+    }
+
+    /// Stop tracking the keys of a column family in `start..end`, in the
+    /// order of `comparator`
+    pub fn remove_range(&mut self, comparator: &dyn Comparator, cf: &str, start: &str, end: &str) {
+        let range = (Bound::Included(start), Bound::Excluded(end));
+        self.remove_where(|key_cf, key| key_cf == cf && in_range(comparator, range, key));
+    }
+
+    /// Stop tracking the keys of a column family
+    pub fn remove_family(&mut self, cf: &str) {
+        self.remove_where(|key_cf, _| key_cf == cf);
+    }
+
+    /// Stop tracking the keys whose column family and key match `removed`
+    fn remove_where(&mut self, removed: impl Fn(&str, &str) -> bool) {
+        let removed: Vec<String> = self
+            .keys
+            .iter()
+            .filter(|qualified| {
+                let (cf, key) = split_qualified_key(qualified);
+                removed(cf, key)
+            })
+            .cloned()
+            .collect();
+        for key in removed {
+            self.remove(&key);
+        }
+    }
+
+    /// Record the eviction of a key
+    pub fn evicted(&mut self, key: &str) {
+        self.remove(key);
+        self.evicted_keys += 1;
+    }
+
+    /// Key to evict next according to the policy, `None` if none may be
+    pub fn victim(&mut self, now: u64) -> Option<String> {
+        match self.policy {
+            EvictionPolicy::NoEviction => None,
+            EvictionPolicy::VolatileTtl => self.volatile.first().map(|(_, key)| key.clone()),
+            EvictionPolicy::AllKeysLru | EvictionPolicy::AllKeysLfu => {
+                let lfu = self.policy == EvictionPolicy::AllKeysLfu;
+                let mut best: Option<(usize, (bool, u64))> = None;
+                for _ in 0..EVICTION_SAMPLES.min(self.keys.len()) {
+                    let slot = (self.next_random() % self.keys.len() as u64) as usize;
+                    let stats = &self.stats[&self.keys[slot]];
+                    let expired = stats.expires_at.is_some_and(|at| at <= now);
+                    // Expired keys go first, then the lowest score
+                    let score = if lfu {
+                        decayed_lfu(stats, now) as u64
+                    } else {
+                        stats.last_access
+                    };
+                    let rank = (!expired, score);
+                    if best.is_none_or(|(_, best)| rank < best) {
+                        best = Some((slot, rank));
+                    }
+                }
+                best.map(|(slot, _)| self.keys[slot].clone())
+            }
+        }
+    }
+
+    /// xorshift64*, good enough to sample keys
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+/// Approximate memory used by a key and its value
+fn entry_size(key: &str, value: &StoredValue) -> usize {
+    let operands: usize = value.operands.iter().map(String::len).sum();
+    key.len() + value.value.len() + operands + ENTRY_OVERHEAD
+}
+
+/// LFU counter of a key, decremented for every period it was not accessed
+fn decayed_lfu(stats: &KeyStats, now: u64) -> u8 {
+    let periods = now.saturating_sub(stats.last_access) / LFU_DECAY_MILLIS;
+    stats.lfu.saturating_sub(periods.min(u8::MAX as u64) as u8)
+}
+
+/// Increment an LFU counter with a probability shrinking as it grows, so
+/// that it spans many orders of magnitude of accesses
+fn incremented_lfu(lfu: u8, random: u64) -> u8 {
+    if lfu == u8::MAX {
+        return lfu;
+    }
+    let base = lfu.saturating_sub(LFU_INIT) as f64;
+    let probability = 1.0 / (base * LFU_LOG_FACTOR + 1.0);
+    let draw = (random >> 11) as f64 / (1u64 << 53) as f64;
+    if draw < probability {
+        lfu + 1
+    } else {
+        lfu
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        common_enums::{EvictionPolicy, Expiry},
+        storage::{test_util::TestDb, Options},
+    };
+
+    #[test]
+    fn evicted_keys_of_any_column_family_are_deleted() {
+        let options = Options::builder()
+            .max_memory(300)
+            .eviction_policy(EvictionPolicy::VolatileTtl)
+            .build()
+            .unwrap();
+        let mut db = TestDb::with_options("eviction-families", options);
+        db.create_column_family("sessions", Options::default())
+            .unwrap();
+        let sessions = db.column_family("sessions").unwrap();
+
+        // Each key costs its size, qualified by its column family, plus 64
+        db.put("keep", "v").unwrap();
+        sessions
+            .put_with_expiry("s1", "v", Expiry::In(3_600_000))
+            .unwrap();
+        db.put_with_expiry("d1", "v", Expiry::In(7_200_000))
+            .unwrap();
+        assert_eq!(db.memory_stats().unwrap().unwrap().used_memory, 212);
+
+        // Over the limit: the key expiring first goes, whatever its family
+        db.put("big", &"x".repeat(50)).unwrap();
+        let stats = db.memory_stats().unwrap().unwrap();
+        assert_eq!((stats.used_memory, stats.evicted_keys), (253, 1));
+        assert_eq!(sessions.get("s1").unwrap(), None);
+        for key in ["keep", "d1", "big"] {
+            assert!(db.get(key).unwrap().is_some(), "{} was evicted", key);
+        }
+
+        // Evicted keys are deleted, not only dropped from memory
+        db.reopen();
+        let sessions = db.column_family("sessions").unwrap();
+        assert_eq!(sessions.get("s1").unwrap(), None);
+        assert_eq!(db.memory_stats().unwrap().unwrap().used_memory, 253);
+
+        // Dropping a column family releases its keys
+        db.delete("big").unwrap();
+        sessions.put("s2", "v").unwrap();
+        assert_eq!(db.memory_stats().unwrap().unwrap().used_memory, 212);
+        db.drop_column_family("sessions").unwrap();
+        assert_eq!(db.memory_stats().unwrap().unwrap().used_memory, 136);
+    }
+}
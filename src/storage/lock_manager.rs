@@ -0,0 +1,409 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Bound,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use log::warn;
+
+use super::{column_family::split_qualified_key, comparator::in_range, Comparator};
+use crate::error::Error;
+
+/// How a key is locked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockMode {
+    /// Held by any number of owners at once, keeps the key from being written
+    Shared,
+    /// Held by a single owner, keeps the key from being locked by others
+    Exclusive,
+}
+
+/// Per-key shared and exclusive locks of transactions and batches, and
+/// exclusive locks on ranges of keys for range deletions.
+///
+/// Owners wait for the locks they need up to a timeout. Every waiting owner
+/// is recorded in a wait-for graph, and an owner whose wait would close a
+/// cycle fails right away instead of waiting for the timeout.
+pub(crate) struct LockManager {
+    state: Mutex<LockState>,
+    /// Notified whenever locks are released
+    released: Condvar,
+    next_owner: AtomicU64,
+    /// Orders the keys of the ranges locked
+    comparator: Arc<dyn Comparator>,
+}
+
+#[derive(Default)]
+struct LockState {
+    locks: HashMap<String, KeyLock>,
+    /// Exclusively locked ranges of keys
+    ranges: Vec<RangeLock>,
+    /// Owners every waiting owner waits for
+    waits_for: HashMap<u64, HashSet<u64>>,
+}
+
+/// Keys in `start..end` of a column family locked by `owner`
+struct RangeLock {
+    owner: u64,
+    cf: String,
+    start: String,
+    end: String,
+}
+
+#[derive(Default)]
+struct KeyLock {
+    exclusive: Option<u64>,
+    shared: HashSet<u64>,
+}
+
+impl LockManager {
+    pub fn new(comparator: Arc<dyn Comparator>) -> Self {
+        LockManager {
+            state: Mutex::new(LockState::default()),
+            released: Condvar::new(),
+            next_owner: AtomicU64::new(1),
+            comparator,
+        }
+    }
+
+    /// Allocate an id for a new owner of locks
+    pub fn new_owner(&self) -> u64 {
+        self.next_owner.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Lock `key` for `owner`, waiting at most `timeout` for other owners to
+    /// release it. Fails with [`Error::Busy`] if waiting would deadlock and
+    /// with [`Error::Timeout`] once the timeout elapses. A shared lock held
+    /// by the owner is upgraded to an exclusive one.
+    pub fn lock(
+        &self,
+        owner: u64,
+        key: &str,
+        mode: LockMode,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let comparator = self.comparator.as_ref();
+        self.acquire(
+            owner,
+            &format!("key {:?}", key),
+            timeout,
+            |state| state.blockers(comparator, owner, key, mode),
+            |state| state.grant(owner, key, mode),
+        )
+    }
+
+    /// Exclusively lock the keys in `start..end` of a column family, which
+    /// no other owner may hold a lock on, nor lock until the returned locks
+    /// are dropped
+    pub fn lock_range(
+        self: &Arc<Self>,
+        cf: &str,
+        start: &str,
+        end: &str,
+        timeout: Duration,
+    ) -> Result<BatchLocks, Error> {
+        let locks = BatchLocks {
+            lock_manager: Arc::clone(self),
+            owner: self.new_owner(),
+            keys: Vec::new(),
+        };
+        let owner = locks.owner;
+        let comparator = self.comparator.as_ref();
+        let range = (Bound::Included(start), Bound::Excluded(end));
+        self.acquire(
+            owner,
+            &format!("range {:?}..{:?}", start, end),
+            timeout,
+            |state| {
+                state
+                    .locks
+                    .iter()
+                    .filter(|(key, _)| {
+                        let (key_cf, key) = split_qualified_key(key);
+                        key_cf == cf && in_range(comparator, range, key)
+                    })
+                    .flat_map(|(_, lock)| lock.exclusive.iter().chain(&lock.shared))
+                    .copied()
+                    .filter(|&other| other != owner)
+                    .collect()
+            },
+            |state| {
+                state.ranges.push(RangeLock {
+                    owner,
+                    cf: cf.to_string(),
+                    start: start.to_string(),
+                    end: end.to_string(),
+                })
+            },
+        )?;
+        Ok(locks)
+    }
+
+    /// Wait until `blockers` finds no other owner in the way of `owner`,
+    /// then `grant` the lock on `what`
+    fn acquire(
+        &self,
+        owner: u64,
+        what: &str,
+        timeout: Duration,
+        blockers: impl Fn(&LockState) -> HashSet<u64>,
+        grant: impl FnOnce(&mut LockState),
+    ) -> Result<(), Error> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            let blockers = blockers(&state);
+            if blockers.is_empty() {
+                state.waits_for.remove(&owner);
+                grant(&mut state);
+                return Ok(());
+            }
+            state.waits_for.insert(owner, blockers);
+            if state.deadlocked(owner) {
+                state.waits_for.remove(&owner);
+                warn!("Deadlock detected locking {}", what);
+                return Err(Error::Busy(format!("deadlock detected locking {}", what)));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                state.waits_for.remove(&owner);
+                return Err(Error::Timeout(format!(
+                    "lock on {} not acquired within {:?}",
+                    what, timeout
+                )));
+            }
+            state = self
+                .released
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+
+    /// Exclusively lock the keys written by a batch, in key order so that
+    /// batches do not deadlock each other
+    pub fn lock_batch(
+        self: &Arc<Self>,
+        mut keys: Vec<String>,
+        timeout: Duration,
+    ) -> Result<BatchLocks, Error> {
+        keys.sort_unstable();
+        keys.dedup();
+        let mut locks = BatchLocks {
+            lock_manager: Arc::clone(self),
+            owner: self.new_owner(),
+            keys: Vec::with_capacity(keys.len()),
+        };
+        for key in keys {
+            // The locks taken so far are released if this one fails
+            self.lock(locks.owner, &key, LockMode::Exclusive, timeout)?;
+            locks.keys.push(key);
+        }
+        Ok(locks)
+    }
+
+    /// Release the locks `owner` holds on `keys`, and on ranges of keys
+    pub fn unlock<'a>(&self, owner: u64, keys: impl IntoIterator<Item = &'a str>) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.ranges.retain(|range| range.owner != owner);
+        for key in keys {
+            let Some(lock) = state.locks.get_mut(key) else {
+                continue;
+            };
+            if lock.exclusive == Some(owner) {
+                lock.exclusive = None;
+            }
+            lock.shared.remove(&owner);
+            if lock.exclusive.is_none() && lock.shared.is_empty() {
+                state.locks.remove(key);
+            }
+        }
+        drop(state);
+        self.released.notify_all();
+    }
+}
+
+/// Locks held by a batch while it is written, released when dropped
+pub(crate) struct BatchLocks {
+    lock_manager: Arc<LockManager>,
+    owner: u64,
+    keys: Vec<String>,
+}
+
+impl Drop for BatchLocks {
+    fn drop(&mut self) {
+        self.lock_manager
+            .unlock(self.owner, self.keys.iter().map(String::as_str));
+    }
+}
+
+impl LockState {
+    /// Other owners holding locks on `key`, or on a range holding it, that
+    /// conflict with `mode`
+    fn blockers(
+        &self,
+        comparator: &dyn Comparator,
+        owner: u64,
+        key: &str,
+        mode: LockMode,
+    ) -> HashSet<u64> {
+        let (cf, unqualified) = split_qualified_key(key);
+        let mut blockers: HashSet<u64> = self
+            .ranges
+            .iter()
+            .filter(|range| range.cf == cf)
+            .filter(|range| {
+                let bounds = (
+                    Bound::Included(range.start.as_str()),
+                    Bound::Excluded(range.end.as_str()),
+                );
+                in_range(comparator, bounds, unqualified)
+            })
+            .map(|range| range.owner)
+            .collect();
+        if let Some(lock) = self.locks.get(key) {
+            blockers.extend(lock.exclusive);
+            if mode == LockMode::Exclusive {
+                blockers.extend(&lock.shared);
+            }
+        }
+        blockers.remove(&owner);
+        blockers
+    }
+
+    fn grant(&mut self, owner: u64, key: &str, mode: LockMode) {
+        let lock = self.locks.entry(key.to_string()).or_default();
+        match mode {
+            LockMode::Exclusive => {
+                lock.exclusive = Some(owner);
+                lock.shared.remove(&owner);
+            }
+            // An exclusive lock already covers the shared one
+            LockMode::Shared if lock.exclusive == Some(owner) => {}
+            LockMode::Shared => {
+                lock.shared.insert(owner);
+            }
+        }
+    }
+
+    /// Whether `owner` waits, directly or not, for an owner waiting for it
+    fn deadlocked(&self, owner: u64) -> bool {
+        let mut visited = HashSet::new();
+        let mut pending: Vec<u64> = self.waits_for[&owner].iter().copied().collect();
+        while let Some(next) = pending.pop() {
+            if next == owner {
+                return true;
+            }
+            if visited.insert(next) {
+                pending.extend(self.waits_for.get(&next).into_iter().flatten());
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::storage::{Bytewise, DEFAULT_COLUMN_FAMILY};
+
+    fn lock_manager() -> Arc<LockManager> {
+        Arc::new(LockManager::new(Arc::new(Bytewise)))
+    }
+
+    /// Wait until `owner` is blocked on another owner
+    fn wait_until_blocked(lock_manager: &LockManager, owner: u64) {
+        while !lock_manager
+            .state
+            .lock()
+            .unwrap()
+            .waits_for
+            .contains_key(&owner)
+        {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn lock_cycle_fails_with_busy() {
+        let lock_manager = lock_manager();
+        let (a, b) = (lock_manager.new_owner(), lock_manager.new_owner());
+        let timeout = Duration::from_secs(10);
+        lock_manager
+            .lock(a, "x", LockMode::Exclusive, timeout)
+            .unwrap();
+        lock_manager
+            .lock(b, "y", LockMode::Exclusive, timeout)
+            .unwrap();
+
+        let waiter = thread::spawn({
+            let lock_manager = Arc::clone(&lock_manager);
+            move || lock_manager.lock(a, "y", LockMode::Exclusive, timeout)
+        });
+        wait_until_blocked(&lock_manager, a);
+        let result = lock_manager.lock(b, "x", LockMode::Exclusive, timeout);
+        assert!(matches!(result, Err(Error::Busy(_))), "{:?}", result);
+
+        // The owner breaking the cycle gives up its locks, and the other
+        // one proceeds
+        lock_manager.unlock(b, ["y"]);
+        waiter.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn waiter_times_out() {
+        let lock_manager = lock_manager();
+        let (a, b) = (lock_manager.new_owner(), lock_manager.new_owner());
+        let timeout = Duration::from_millis(50);
+        lock_manager
+            .lock(a, "x", LockMode::Shared, timeout)
+            .unwrap();
+        lock_manager
+            .lock(b, "x", LockMode::Shared, timeout)
+            .unwrap();
+
+        let started = Instant::now();
+        let result = lock_manager.lock(b, "x", LockMode::Exclusive, timeout);
+        assert!(matches!(result, Err(Error::Timeout(_))), "{:?}", result);
+        assert!(started.elapsed() >= timeout);
+
+        lock_manager.unlock(a, ["x"]);
+        lock_manager
+            .lock(b, "x", LockMode::Exclusive, timeout)
+            .unwrap();
+    }
+
+    #[test]
+    fn range_lock_conflicts_with_keys_in_range() {
+        let lock_manager = lock_manager();
+        let timeout = Duration::from_millis(50);
+        let owner = lock_manager.new_owner();
+        lock_manager
+            .lock(owner, "b", LockMode::Shared, timeout)
+            .unwrap();
+
+        let result = lock_manager.lock_range(DEFAULT_COLUMN_FAMILY, "a", "c", timeout);
+        assert!(matches!(result, Err(Error::Timeout(_))));
+        // Other column families and keys past the end are not in the range
+        let range = lock_manager
+            .lock_range(DEFAULT_COLUMN_FAMILY, "c", "d", timeout)
+            .unwrap();
+        let other = lock_manager.new_owner();
+        lock_manager
+            .lock(other, "cf:c", LockMode::Exclusive, timeout)
+            .unwrap();
+        let result = lock_manager.lock(other, "c", LockMode::Exclusive, timeout);
+        assert!(matches!(result, Err(Error::Timeout(_))));
+
+        drop(range);
+        lock_manager
+            .lock(other, "c", LockMode::Exclusive, timeout)
+            .unwrap();
+    }
+}
@@ -1,206 +1,1173 @@
-use super::{SSTable, Wal};
-use crate::common_enums::CompactionStrategy;
-use crate::storage::mem_table::MemTable;
+use super::{
+    builtin_comparator,
+    column_family::{
+        parse_wal_entry, qualified_key, split_qualified_key, validate_name, wal_entry,
+        ColumnFamily, DEFAULT_COLUMN_FAMILY,
+    },
+    comparator,
+    options::OPTIONS_FILE,
+    range_tombstone::RangeTombstone,
+    snapshot::{in_request_order, overlay_levels, read_key, read_keys, scan_levels, sorted_keys},
+    ss_table::sync_dir,
+    transaction::WriteLog,
+    value::{now_millis, StoredValue},
+    BlockCache, CompactionFilter, Comparator, KeyTracker, LockManager, LockMode, MemoryStats,
+    MergeOperator, Options, ReadOptions, Snapshot, Transaction, Wal,
+};
+use crate::common_enums::{CasOutcome, Expected, Versioned};
+use crate::error::Error;
 use log::{info, warn};
-use std::{fs, path::Path, sync::Arc, vec}; // Add logging
+use std::{
+    collections::BTreeMap,
+    fs,
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-#[cfg(feature = "size_tiered")]
-const DEFAULT_COMPACTION_STRATEGY: CompactionStrategy = CompactionStrategy::SizeTiered;
+/// Name of the Wal file inside the DB directory
+const WAL_FILE: &str = "wal.log";
 
-#[cfg(feature = "level_based")]
-const DEFAULT_COMPACTION_STRATEGY: CompactionStrategy = CompactionStrategy::LevelBased;
+/// Name of the directory holding the SSTables inside the DB directory, and
+/// inside the directory of every column family
+const SSTABLE_DIR: &str = "sstables";
+
+/// Name of the directory holding a directory per column family, other than
+/// the default one, inside the DB directory
+const COLUMN_FAMILIES_DIR: &str = "column_families";
+
+/// Marker file written once the LSM Tree was closed cleanly
+const CLEAN_SHUTDOWN_FILE: &str = "CLEAN_SHUTDOWN";
 
 pub struct LSMTree {
+    path: PathBuf,
+    /// Whether the previous process closed the LSM Tree cleanly
+    clean_shutdown: bool,
+    /// Shared by every column family, so that a batch spanning several of
+    /// them is logged atomically
     wal: Arc<Wal>,
-    memtable: Arc<MemTable>,
-    sstable_dir: String,
-    levels: Vec<Vec<SSTable>>,
-    compaction_threshold: usize,
-    compaction_strategy: CompactionStrategy,
+    /// Column families by name, the default one included
+    families: BTreeMap<String, ColumnFamily>,
+    block_cache: Arc<BlockCache>,
+    /// Size and accesses of every live key of every column family, when the
+    /// memory is limited. Keys are tracked by their qualified key.
+    key_tracker: Option<Mutex<KeyTracker>>,
+    /// Version of the latest write
+    last_version: u64,
+    /// Folds the merge operands of reads and compactions
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// Keeps, removes or rewrites the values surviving compactions
+    compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    /// Row locks of transactions and batches
+    lock_manager: Arc<LockManager>,
+    /// Versions of the writes made while transactions are open
+    write_log: WriteLog,
+    /// Orders the keys of every column family
+    comparator: Arc<dyn Comparator>,
+    options: Options,
 }
 
 impl LSMTree {
-    /// Create a new LSM Tree
-    pub fn new(
-        wal_path: &str,
-        sstable_dir: &str,
-        memtable_max_size: usize,
-        compaction_threshold: usize,
-    ) -> Result<Self, std::io::Error> {
-        info!(
-            "Creating new LSMTree with wal_path: {}, sstable_dir: {}",
-            wal_path, sstable_dir
-        );
-        fs::create_dir_all(sstable_dir)?;
-        let levels = Vec::from(vec![Vec::new()]);
-        let wal_path = Path::new(wal_path);
-        let wal = Arc::new(Wal::new(wal_path)?);
-        let memtable = Arc::new(MemTable::new(memtable_max_size));
+    /// Open the LSM Tree stored in `path`, creating it if needed, with the
+    /// built-in comparator named by the options
+    pub fn open<P: AsRef<Path>>(path: P, options: Options) -> Result<Self, Error> {
+        let comparator = builtin_comparator(&options.comparator)?;
+        Self::open_with_comparator(path, options, comparator)
+    }
+
+    /// Open the LSM Tree stored in `path`, creating it if needed, with the
+    /// keys ordered by `comparator`. Fails if the database was created with
+    /// a comparator of another name.
+    pub fn open_with_comparator<P: AsRef<Path>>(
+        path: P,
+        mut options: Options,
+        comparator: Arc<dyn Comparator>,
+    ) -> Result<Self, Error> {
+        let path = path.as_ref();
+        options.comparator = comparator.name().to_string();
+        info!("Opening LSMTree in {:?} with {:?}", path, options);
+        options.validate()?;
+        let existed = path.exists();
+        if !existed {
+            if !options.create_if_missing {
+                return Err(Error::NotFound(format!(
+                    "database directory {:?} does not exist",
+                    path
+                )));
+            }
+            fs::create_dir_all(path)?;
+        }
+        let options_path = path.join(OPTIONS_FILE);
+        if options_path.exists() {
+            let persisted = Options::from_file(&options_path)?;
+            if persisted.comparator != options.comparator {
+                return Err(Error::InvalidArgument(format!(
+                    "database {:?} was created with comparator {:?}, not {:?}",
+                    path, persisted.comparator, options.comparator
+                )));
+            }
+        }
+        options.persist(path)?;
+
+        let marker_path = path.join(CLEAN_SHUTDOWN_FILE);
+        let clean_shutdown = !existed || marker_path.exists();
+        if marker_path.exists() {
+            fs::remove_file(&marker_path)?;
+        } else if existed {
+            warn!("{:?} was not closed cleanly, recovering from the Wal", path);
+        }
 
-        memtable.load_from_wal(wal_path)?;
+        let block_cache = Arc::new(BlockCache::new(options.block_cache_size));
+        let families = Self::open_families(path, &options, &comparator, &block_cache)?;
+        let wal_path = path.join(WAL_FILE);
+        let wal = Arc::new(Wal::new(&wal_path, options.sync_policy)?);
 
+        Wal::replay(&wal_path, |key, value| {
+            let (cf, key, value) = parse_wal_entry(key, value);
+            match families.get(cf) {
+                Some(family) => family.memtable.replay(key, value),
+                // Logged before the column family was dropped
+                None => Ok(()),
+            }
+        })?;
+        // Versions keep growing past every version recovered, however far
+        // ahead of the clock
+        let mut last_version = 0;
+        for family in families.values() {
+            for raw in family.memtable.entries().values() {
+                last_version = last_version.max(StoredValue::decode(raw)?.version);
+            }
+            for tombstone in family.memtable.range_tombstones() {
+                last_version = last_version.max(tombstone.version);
+            }
+            for sstable in family.levels.iter().flatten() {
+                last_version = last_version.max(sstable.max_version());
+            }
+        }
+
+        let lock_manager = Arc::new(LockManager::new(Arc::clone(&comparator)));
         let mut lsm_tree = LSMTree {
+            path: path.to_path_buf(),
+            clean_shutdown,
             wal,
-            memtable,
-            sstable_dir: sstable_dir.to_string(),
-            levels,
-            compaction_threshold,
-            compaction_strategy: DEFAULT_COMPACTION_STRATEGY,
+            families,
+            block_cache,
+            key_tracker: None,
+            last_version,
+            merge_operator: None,
+            compaction_filter: None,
+            lock_manager,
+            write_log: WriteLog::default(),
+            comparator,
+            options,
         };
 
-        lsm_tree.load_levels()?;
+        if lsm_tree.options.max_memory > 0 {
+            lsm_tree.track_keys()?;
+        }
         Ok(lsm_tree)
     }
 
-    /// Write a key-value pair
-    pub fn write(&mut self, key: String, value: String) -> Result<(), std::io::Error> {
-        info!("Writing key: {}, value: {}", key, value);
+    /// Open the default column family and every column family found in the
+    /// column families directory
+    fn open_families(
+        path: &Path,
+        options: &Options,
+        comparator: &Arc<dyn Comparator>,
+        block_cache: &Arc<BlockCache>,
+    ) -> Result<BTreeMap<String, ColumnFamily>, Error> {
+        let mut families = BTreeMap::new();
+        let default = ColumnFamily::open(
+            DEFAULT_COLUMN_FAMILY,
+            path.join(SSTABLE_DIR),
+            options.clone(),
+            Arc::clone(comparator),
+            Arc::clone(block_cache),
+        )?;
+        families.insert(DEFAULT_COLUMN_FAMILY.to_string(), default);
+
+        let families_dir = path.join(COLUMN_FAMILIES_DIR);
+        if !families_dir.exists() {
+            return Ok(families);
+        }
+        for entry in fs::read_dir(&families_dir)? {
+            let dir = entry?.path();
+            let Some(name) = dir.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            if validate_name(name).is_err() || name == DEFAULT_COLUMN_FAMILY {
+                continue;
+            }
+            // The options are persisted last when creating a column family
+            let options_path = dir.join(OPTIONS_FILE);
+            if !options_path.exists() {
+                warn!("Skipping column family {} created only partially", name);
+                continue;
+            }
+            let family_options = options.for_column_family(&Options::from_file(options_path)?);
+            let family = ColumnFamily::open(
+                name,
+                dir.join(SSTABLE_DIR),
+                family_options,
+                Arc::clone(comparator),
+                Arc::clone(block_cache),
+            )?;
+            families.insert(name.to_string(), family);
+        }
+        Ok(families)
+    }
+
+    /// Options the LSM Tree was opened with, which are also the options of
+    /// the default column family
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Comparator ordering the keys of every column family
+    pub fn comparator(&self) -> &Arc<dyn Comparator> {
+        &self.comparator
+    }
+
+    /// Options of a column family
+    pub fn column_family_options(&self, cf: &str) -> Result<&Options, Error> {
+        Ok(&self.family(cf)?.options)
+    }
+
+    /// Names of the column families, the default one included
+    pub fn list_column_families(&self) -> Vec<String> {
+        self.families.keys().cloned().collect()
+    }
+
+    /// Create a column family tuned by `options`, see [`Options`] for the
+    /// options it shares with the database
+    pub fn create_column_family(&mut self, name: &str, options: Options) -> Result<(), Error> {
+        validate_name(name)?;
+        options.validate()?;
+        if self.families.contains_key(name) {
+            return Err(Error::InvalidArgument(format!(
+                "column family {:?} already exists",
+                name
+            )));
+        }
+        info!("Creating column family {}", name);
+        let options = self.options.for_column_family(&options);
+        let families_dir = self.path.join(COLUMN_FAMILIES_DIR);
+        let dir = families_dir.join(name);
+        if dir.exists() {
+            // Left over by a create or a drop that did not complete
+            fs::remove_dir_all(&dir)?;
+        }
+        let family = ColumnFamily::open(
+            name,
+            dir.join(SSTABLE_DIR),
+            options,
+            Arc::clone(&self.comparator),
+            Arc::clone(&self.block_cache),
+        )?;
+        family.options.persist(&dir)?;
+        sync_dir(&dir)?;
+        sync_dir(&families_dir)?;
+        self.families.insert(name.to_string(), family);
+        Ok(())
+    }
+
+    /// Drop a column family along with every key it holds. The MemTables of
+    /// the other column families are flushed, so that the Wal no longer
+    /// holds entries of the dropped one.
+    pub fn drop_column_family(&mut self, name: &str) -> Result<(), Error> {
+        if name == DEFAULT_COLUMN_FAMILY {
+            return Err(Error::InvalidArgument(
+                "the default column family cannot be dropped".to_string(),
+            ));
+        }
+        if self.families.remove(name).is_none() {
+            return Err(unknown_family(name));
+        }
+        info!("Dropping column family {}", name);
+        if let Some(tracker) = self.key_tracker.as_mut() {
+            let tracker = tracker.get_mut().unwrap_or_else(|e| e.into_inner());
+            tracker.remove_family(name);
+        }
+        self.flush_memtables()?;
+        let families_dir = self.path.join(COLUMN_FAMILIES_DIR);
+        // Snapshots still holding its SSTables keep reading from their open files
+        fs::remove_dir_all(families_dir.join(name))?;
+        sync_dir(&families_dir)
+    }
+
+    /// Whether the LSM Tree was closed cleanly before being opened.
+    /// Otherwise its recent writes were recovered from the Wal.
+    pub fn clean_shutdown(&self) -> bool {
+        self.clean_shutdown
+    }
+
+    /// Memory accounting, if the memory is limited
+    pub fn memory_stats(&self) -> Option<MemoryStats> {
+        let tracker = self.key_tracker.as_ref()?;
+        Some(tracker.lock().unwrap_or_else(|e| e.into_inner()).stats())
+    }
+
+    /// Row locks of the transactions and batches of the LSM Tree
+    pub(crate) fn lock_manager(&self) -> &Arc<LockManager> {
+        &self.lock_manager
+    }
+
+    /// Register the operator folding merge operands. Operands written before
+    /// an operator is registered are folded by it too.
+    pub fn set_merge_operator(&mut self, operator: Arc<dyn MergeOperator>) {
+        info!("Using merge operator {}", operator.name());
+        self.merge_operator = Some(operator);
+    }
+
+    /// Register the filter invoked on the values surviving compactions.
+    /// Values already compacted are filtered again by later compactions only.
+    pub fn set_compaction_filter(&mut self, filter: Arc<dyn CompactionFilter>) {
+        info!("Using compaction filter {}", filter.name());
+        self.compaction_filter = Some(filter);
+    }
+
+    /// Load the options persisted in the OPTIONS file of a DB directory
+    pub fn load_options<P: AsRef<Path>>(path: P) -> Result<Options, Error> {
+        Options::from_file(path.as_ref().join(OPTIONS_FILE))
+    }
+
+    fn family(&self, cf: &str) -> Result<&ColumnFamily, Error> {
+        self.families.get(cf).ok_or_else(|| unknown_family(cf))
+    }
+
+    /// Write a key-value pair. An empty value deletes the key.
+    pub fn write(&mut self, cf: &str, key: String, value: String) -> Result<(), Error> {
+        self.write_stored(cf, key, StoredValue::new(value))
+    }
+
+    /// Record a merge operand for a key, folded onto its value by the merge
+    /// operator on reads and compactions
+    pub fn merge(&mut self, cf: &str, key: String, operand: String) -> Result<(), Error> {
+        if self.merge_operator.is_none() {
+            return Err(Error::InvalidArgument(
+                "no merge operator is set".to_string(),
+            ));
+        }
+        self.write_stored(cf, key, StoredValue::operand(operand))
+    }
+
+    /// Add `delta` to the integer value of a key, a missing key counting as
+    /// 0, and return the new value. The key keeps its expiry.
+    pub fn increment(&mut self, cf: &str, key: String, delta: i64) -> Result<i64, Error> {
+        let current = self.read_stored(cf, &key, &ReadOptions::default())?;
+        let (value, expires_at) = match current {
+            Some(current) => {
+                let value = current.as_counter().ok_or_else(|| {
+                    Error::InvalidArgument(format!(
+                        "value {:?} of key {:?} is not an i64",
+                        current.value, key
+                    ))
+                })?;
+                (value, current.expires_at)
+            }
+            None => (0, None),
+        };
+        let value = value
+            .checked_add(delta)
+            .ok_or_else(|| Error::Overflow(format!("incrementing key {:?} overflows i64", key)))?;
+        let stored = StoredValue {
+            counter: true,
+            ..StoredValue::with_expiry(value.to_string(), expires_at)
+        };
+        self.write_stored(cf, key, stored)?;
+        Ok(value)
+    }
+
+    /// Write a value along with its metadata
+    pub(crate) fn write_stored(
+        &mut self,
+        cf: &str,
+        key: String,
+        value: StoredValue,
+    ) -> Result<(), Error> {
+        self.write_versioned(cf, key, value).map(|_| ())
+    }
+
+    /// Write `new`, or delete the key if `None`, only if the current value
+    /// of the key matches `expected`, or the key is absent if `None`
+    pub(crate) fn compare_and_swap(
+        &mut self,
+        cf: &str,
+        key: String,
+        expected: Option<&Expected>,
+        new: Option<StoredValue>,
+    ) -> Result<CasOutcome, Error> {
+        let current = self.read_stored(cf, &key, &ReadOptions::default())?;
+        let matches = match (expected, &current) {
+            (None, None) => true,
+            (Some(Expected::Value(value)), Some(current)) => current.value == *value,
+            (Some(Expected::Version(version)), Some(current)) => current.version == *version,
+            _ => false,
+        };
+        if !matches {
+            let current = current.map(|c| Versioned {
+                value: c.value,
+                version: c.version,
+            });
+            return Ok(CasOutcome::Conflict { current });
+        }
+        let new = new.unwrap_or_default();
+        let deleted = new.is_tombstone();
+        let version = self.write_versioned(cf, key, new)?;
+        Ok(CasOutcome::Swapped {
+            version: (!deleted).then_some(version),
+        })
+    }
+
+    /// Write a value stamped with a new version, returning the version
+    fn write_versioned(
+        &mut self,
+        cf: &str,
+        key: String,
+        mut value: StoredValue,
+    ) -> Result<u64, Error> {
+        self.family(cf)?;
+        // Deletions take a version too, for the conflicts of transactions,
+        // but do not store it
+        let version = self.next_version();
+        if !value.is_tombstone() {
+            value.version = version;
+        }
+        info!("Writing key: {}, value: {:?}", key, value);
+        let encoded = value.encode();
+        Self::validate(&key, &encoded)?;
+        let tracked = qualified_key(cf, &key);
+        if let Some(tracker) = self.key_tracker.as_mut() {
+            let growth = tracker
+                .get_mut()
+                .unwrap_or_else(|e| e.into_inner())
+                .growth(&tracked, &value);
+            self.make_room(growth)?;
+        }
+        self.write_log.record(cf, &key, version);
+        self.append(cf, key, &value, encoded)?;
+        if let Some(tracker) = self.key_tracker.as_mut() {
+            let tracker = tracker.get_mut().unwrap_or_else(|e| e.into_inner());
+            tracker.record_write(&tracked, &value, now_millis());
+        }
+        Ok(version)
+    }
+
+    /// Allocate the version of a write. Versions start from the current time
+    /// in microseconds so that they keep growing across restarts.
+    fn next_version(&mut self) -> u64 {
+        let now = now_millis().saturating_mul(1000);
+        self.last_version = (self.last_version + 1).max(now);
+        self.last_version
+    }
+
+    /// Append a value to the Wal and the MemTable, given along with its encoding
+    fn append(
+        &mut self,
+        cf: &str,
+        key: String,
+        value: &StoredValue,
+        encoded: String,
+    ) -> Result<(), Error> {
         // Append to Wal
-        self.wal.append(&key, &value)?;
-        // Insert into MemTable
-        self.memtable.insert(key.clone(), value);
+        let (wal_key, wal_value) = wal_entry(cf, &key, &encoded);
+        self.wal.append(&wal_key, &wal_value)?;
+        // Insert into MemTable, operands stack on the current entry
+        self.family(cf)?.insert(key, value, encoded)?;
+        self.flush_if_full()
+    }
 
-        // Flush MemTable to SSTable if full
-        if self.memtable.is_full() {
-            warn!("MemTable is full, flushing to SSTable");
-            let sstable_path = Path::new(&self.sstable_dir).join(format!("sstable_{}_{}.txt", 0, self.levels.get(0).map_or(0, |v| v.len()))); 
+    /// Delete every key in `start..end` with a single range tombstone
+    pub fn delete_range(&mut self, cf: &str, start: String, end: String) -> Result<(), Error> {
+        self.family(cf)?;
+        Self::validate(&start, "")?;
+        Self::validate(&end, "")?;
+        if self.comparator.compare(&start, &end) != std::cmp::Ordering::Less {
+            return Err(Error::InvalidArgument(format!(
+                "range start {:?} must be before its end {:?}",
+                start, end
+            )));
+        }
+        let tombstone = RangeTombstone {
+            start,
+            end,
+            version: self.next_version(),
+        };
+        info!("Deleting range {:?} of {}", tombstone, cf);
+        // Logged with an empty key, which no write may use
+        let (wal_key, wal_value) = wal_entry(cf, "", &tombstone.encode());
+        self.wal.append(&wal_key, &wal_value)?;
+        self.write_log.record_range(cf, &tombstone);
+        if let Some(tracker) = self.key_tracker.as_mut() {
+            let tracker = tracker.get_mut().unwrap_or_else(|e| e.into_inner());
+            let comparator = self.comparator.as_ref();
+            tracker.remove_range(comparator, cf, &tombstone.start, &tombstone.end);
+        }
+        self.family(cf)?.memtable.delete_range(tombstone);
+        self.flush_if_full()
+    }
+
+    /// Write several key-value pairs of any column families atomically.
+    /// Every pair is validated before the first one is written, and the
+    /// whole batch is logged at once so that it is recovered whole or not
+    /// at all.
+    pub(crate) fn write_batch(
+        &mut self,
+        entries: Vec<(String, String, StoredValue)>,
+    ) -> Result<(), Error> {
+        // Validated as encoded, header and merge operands included, as
+        // that is what reaches the Wal
+        for (cf, key, value) in &entries {
+            self.family(cf)?;
+            Self::validate(key, &value.encode())?;
+        }
+        // Room is made for the whole batch before any of it is written
+        if let Some(tracker) = self.key_tracker.as_mut() {
+            let tracker = tracker.get_mut().unwrap_or_else(|e| e.into_inner());
+            let growth = entries
+                .iter()
+                .map(|(cf, key, value)| tracker.growth(&qualified_key(cf, key), value))
+                .sum();
+            self.make_room(growth)?;
+        }
 
-            self.memtable.flush_to_sstable(&sstable_path)?;
-            let sstable = SSTable::new(&sstable_path)?;
-            if let Some(level) = self.levels.get_mut(0) {
-                level.push(sstable);
-            } else {
-                self.levels.push(vec![sstable]);
+        let mut logged = Vec::with_capacity(entries.len());
+        let mut stamped = Vec::with_capacity(entries.len());
+        for (cf, key, mut value) in entries {
+            let version = self.next_version();
+            if !value.is_tombstone() {
+                value.version = version;
             }
+            self.write_log.record(&cf, &key, version);
+            info!("Writing key: {}, value: {:?}", key, value);
+            let encoded = value.encode();
+            logged.push(wal_entry(&cf, &key, &encoded));
+            stamped.push((cf, key, value, encoded));
+        }
+        self.wal.append_batch(&logged)?;
 
-            self.memtable = Arc::new(MemTable::new(self.memtable.max_size)); // Reset MemTable
+        let now = now_millis();
+        for (cf, key, value, encoded) in stamped {
+            if let Some(tracker) = self.key_tracker.as_mut() {
+                let tracker = tracker.get_mut().unwrap_or_else(|e| e.into_inner());
+                tracker.record_write(&qualified_key(&cf, &key), &value, now);
+            }
+            self.family(&cf)?.insert(key, &value, encoded)?;
+        }
+        self.flush_if_full()
+    }
 
-            // Reset Wal
-            self.wal.reset()?;
+    /// Evict keys until `growth` more bytes fit in the memory limit.
+    /// Fails with [`Error::OutOfMemory`] if the policy finds nothing to evict.
+    fn make_room(&mut self, growth: isize) -> Result<(), Error> {
+        if growth <= 0 {
+            return Ok(());
+        }
+        let now = now_millis();
+        loop {
+            let Some(tracker) = self.key_tracker.as_mut() else {
+                return Ok(());
+            };
+            let tracker = tracker.get_mut().unwrap_or_else(|e| e.into_inner());
+            if !tracker.exceeds_limit(growth as usize) {
+                return Ok(());
+            }
+            let Some(victim) = tracker.victim(now) else {
+                let stats = tracker.stats();
+                return Err(Error::OutOfMemory(format!(
+                    "{} more bytes exceed max_memory of {} bytes under {}",
+                    growth, stats.max_memory, stats.policy
+                )));
+            };
+            tracker.evicted(&victim);
+            info!("Evicting key {}", victim);
+            // Evicted keys are deleted, not only dropped from memory
+            let (cf, key) = split_qualified_key(&victim);
+            let (cf, key) = (cf.to_string(), key.to_string());
+            let tombstone = StoredValue::default();
+            let version = self.next_version();
+            self.write_log.record(&cf, &key, version);
+            self.append(&cf, key, &tombstone, String::new())?;
+        }
+    }
 
-            // Trigger compaction if too many SSTables
-            if self.levels[0].len() >= self.compaction_threshold {
-                warn!("Compaction triggered");
-                self.compact()?;
+    /// Track every live key, evicting keys if they exceed the memory limit
+    fn track_keys(&mut self) -> Result<(), Error> {
+        let mut tracker = KeyTracker::new(self.options.eviction_policy, self.options.max_memory);
+        let now = now_millis();
+        for (cf, family) in &self.families {
+            let range_tombstones = family.memtable.range_tombstones();
+            let entries = overlay_levels(
+                family.memtable.entries(),
+                &range_tombstones,
+                &family.levels,
+                Bound::Unbounded,
+                Bound::Unbounded,
+                &self.comparator,
+            );
+            for entry in entries {
+                let (key, value) = entry?;
+                // Merge operands are accounted for unfolded
+                tracker.record_write(&qualified_key(cf, &key), &value, now);
+            }
+        }
+        let used_memory = tracker.stats().used_memory;
+        self.key_tracker = Some(Mutex::new(tracker));
+        if used_memory > self.options.max_memory {
+            warn!(
+                "{} bytes of keys exceed max_memory of {} bytes, evicting",
+                used_memory, self.options.max_memory
+            );
+            if let Err(e) = self.make_room(1) {
+                warn!("Could not get under max_memory: {}", e);
             }
         }
         Ok(())
     }
 
+    /// Reject keys and values that cannot be represented in the Wal and SSTable formats
+    pub(super) fn validate(key: &str, value: &str) -> Result<(), Error> {
+        if key.is_empty() {
+            return Err(Error::InvalidArgument("key must not be empty".to_string()));
+        }
+        if key.contains([':', '\n']) {
+            return Err(Error::InvalidArgument(format!(
+                "key {:?} must not contain ':' or newlines",
+                key
+            )));
+        }
+        if value.contains('\n') {
+            return Err(Error::InvalidArgument(
+                "value must not contain newlines".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
     /// Read a key-value pair
-    pub fn read(&self, key: &str) -> Result<Option<String>, std::io::Error> {
+    pub fn read(&self, cf: &str, key: &str) -> Result<Option<String>, Error> {
+        self.read_with_options(cf, key, &ReadOptions::default())
+    }
+
+    /// Read a key-value pair with explicit read options
+    pub fn read_with_options(
+        &self,
+        cf: &str,
+        key: &str,
+        options: &ReadOptions,
+    ) -> Result<Option<String>, Error> {
+        Ok(self
+            .read_stored(cf, key, options)?
+            .map(|stored| stored.value))
+    }
+
+    /// Read the value of a key along with its metadata. Deleted and expired
+    /// keys are absent.
+    pub(crate) fn read_stored(
+        &self,
+        cf: &str,
+        key: &str,
+        options: &ReadOptions,
+    ) -> Result<Option<StoredValue>, Error> {
         info!("Reading key: {}", key);
-        // Check MemTable
-        if let Some(value) = self.memtable.get(key) {
-            info!("Key: {} found in MemTable", key);
-            return Ok(Some(value));
+        let family = self.family(cf)?;
+        // Check MemTable, then SSTables (from newest to oldest)
+        let value = read_key(
+            family.memtable.get(key),
+            &family.memtable.range_tombstones(),
+            &family.levels,
+            key,
+            options,
+            self.merge_operator.as_deref(),
+            self.comparator.as_ref(),
+        )?;
+        match (&self.key_tracker, &value) {
+            (Some(tracker), Some(_)) => {
+                let mut tracker = tracker.lock().unwrap_or_else(|e| e.into_inner());
+                tracker.touch(&qualified_key(cf, key), now_millis());
+            }
+            (_, None) => warn!("Key: {} not found", key),
+            _ => {}
         }
+        Ok(value)
+    }
+
+    /// Read the values of several keys, in the order of `keys`. The keys
+    /// are looked up together: the MemTable once, then every SSTable once
+    /// for the keys still unresolved.
+    pub fn multi_get(&self, cf: &str, keys: &[&str]) -> Result<Vec<Option<String>>, Error> {
+        info!("Reading {} keys", keys.len());
+        let family = self.family(cf)?;
+        let comparator = self.comparator.as_ref();
+        let sorted = sorted_keys(keys, comparator);
+        let values = read_keys(
+            &sorted,
+            family.memtable.get_many(&sorted),
+            &family.memtable.range_tombstones(),
+            &family.levels,
+            &ReadOptions::default(),
+            self.merge_operator.as_deref(),
+            comparator,
+        )?;
+        if let Some(tracker) = &self.key_tracker {
+            let mut tracker = tracker.lock().unwrap_or_else(|e| e.into_inner());
+            let now = now_millis();
+            for (key, _) in sorted.iter().zip(&values).filter(|(_, v)| v.is_some()) {
+                tracker.touch(&qualified_key(cf, key), now);
+            }
+        }
+        Ok(in_request_order(keys, &sorted, &values, comparator))
+    }
+
+    /// Get the key-value pairs with keys in `start..end` in key order,
+    /// returning at most `limit` pairs
+    pub fn scan(
+        &self,
+        cf: &str,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>, Error> {
+        info!("Scanning keys in {:?}..{:?}", start, end);
+        let family = self.family(cf)?;
+        scan_levels(
+            family.memtable.scan(start, end),
+            &family.memtable.range_tombstones(),
+            &family.levels,
+            (start, end),
+            limit,
+            self.merge_operator.as_deref(),
+            &self.comparator,
+        )
+    }
+
+    /// Get the key-value pairs whose key starts with `prefix`
+    pub fn scan_prefix(
+        &self,
+        cf: &str,
+        prefix: &str,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>, Error> {
+        self.scan_prefix_range(cf, prefix, Bound::Unbounded, Bound::Unbounded, limit)
+    }
+
+    /// Get the key-value pairs whose key starts with `prefix` and is in
+    /// `start..end`, in key order
+    pub fn scan_prefix_range(
+        &self,
+        cf: &str,
+        prefix: &str,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>, Error> {
+        let comparator = self.comparator.as_ref();
+        comparator::scan_prefix(
+            comparator,
+            prefix,
+            (start, end),
+            limit,
+            |start, end, limit| self.scan(cf, start, end, limit),
+        )
+    }
 
-        // Check SSTables (from newest to oldest)
-        for (level_index, level) in self.levels.iter().enumerate() {
-            for (sstable_index, sstable) in level.iter().enumerate() {
-                let path = Path::new(&self.sstable_dir).join(format!("sstable_{}_{}.txt", level_index, sstable_index));
+    /// Take a consistent read-only view of a column family
+    pub fn snapshot(&self, cf: &str) -> Result<Snapshot, Error> {
+        let family = self.family(cf)?;
+        Ok(Snapshot::new(
+            family.memtable.entries(),
+            family.memtable.range_tombstones(),
+            family.levels.clone(),
+            self.merge_operator.clone(),
+            Arc::clone(&self.comparator),
+        ))
+    }
+
+    /// Begin an optimistic transaction on a column family, reading from a
+    /// snapshot taken now
+    pub fn begin(&self, cf: &str) -> Result<Transaction, Error> {
+        self.begin_transaction(cf, false)
+    }
 
-                if !sstable.might_contain(key) {
-                    continue;
-                }
-                if let Some(value) = sstable.read(&path, key)? {
-                    info!("Key: {} found in SSTable {:?}", key, &path);
-                    return Ok(Some(value));
-                }
+    /// Begin a pessimistic transaction on a column family, locking every
+    /// key it writes
+    pub fn begin_pessimistic(&self, cf: &str) -> Result<Transaction, Error> {
+        self.begin_transaction(cf, true)
+    }
+
+    fn begin_transaction(&self, cf: &str, pessimistic: bool) -> Result<Transaction, Error> {
+        let lock_timeout = Duration::from_millis(self.options.lock_timeout_ms);
+        let lock_manager = Arc::clone(&self.lock_manager);
+        let snapshot = self.snapshot(cf)?;
+        Ok(Transaction::new(
+            cf,
+            snapshot,
+            self.last_version,
+            self.write_log.open_transactions(),
+            lock_manager,
+            pessimistic,
+            lock_timeout,
+        ))
+    }
+
+    /// Lock a key for the rest of a transaction, then read its latest
+    /// value, or the value the transaction wrote. Waits for the lock while
+    /// borrowing the LSM Tree, see [`crate::Db::get_for_update`] otherwise.
+    pub fn get_for_update(
+        &self,
+        transaction: &mut Transaction,
+        key: &str,
+        mode: LockMode,
+    ) -> Result<Option<String>, Error> {
+        transaction.lock(key, mode)?;
+        if let Some(written) = transaction.written(key) {
+            return Ok(written);
+        }
+        let stored = self.read_stored(transaction.column_family(), key, &ReadOptions::default())?;
+        transaction.record_read(key, stored.clone(), self.last_version);
+        Ok(stored.map(|stored| stored.value))
+    }
+
+    /// Apply the writes of a transaction atomically, unless a key it read
+    /// was written or deleted since it was read, failing with
+    /// [`Error::Conflict`] then. The keys written are locked first, and
+    /// every lock of the transaction is released once it is applied.
+    pub fn commit(&mut self, mut transaction: Transaction) -> Result<(), Error> {
+        transaction.lock_writes()?;
+        let family = self.family(transaction.column_family())?;
+        let cf = transaction.column_family();
+        for (key, (read, read_at)) in transaction.reads() {
+            let current = read_key(
+                family.memtable.get(key),
+                &family.memtable.range_tombstones(),
+                &family.levels,
+                key,
+                &ReadOptions::default(),
+                self.merge_operator.as_deref(),
+                self.comparator.as_ref(),
+            )?;
+            let read_version = read.as_ref().map(|read| read.version);
+            if current.map(|current| current.version) != read_version
+                || self
+                    .write_log
+                    .written_since(self.comparator.as_ref(), cf, key, *read_at)
+            {
+                return Err(Error::Conflict(format!(
+                    "key {:?} was modified since the transaction read it",
+                    key
+                )));
             }
         }
-        warn!("Key: {} not found", key);
-        Ok(None)
+        self.write_log.prune();
+        self.write_batch(transaction.take_writes())
     }
 
-    /// load levels of SSTables (Can be improved significantly)
-    fn load_levels(&mut self) -> Result<(), std::io::Error> {
-        info!("Loading levels...");
-        let mut levels = Vec::new();
-        let mut i = 0;
-        loop {
-            let mut level = Vec::new();
-            for j in 0..self.compaction_threshold {
-                let path = Path::new(&self.sstable_dir).join(format!("sstable_{}_{}.txt", i, j));
-                if !path.exists() {
-                    break;
-                }
-                let sstable = SSTable::load(&path)?;
-                level.push(sstable);
-                i += 1;
-            }
-            if level.is_empty() {
-                warn!("No SSTables found for level {}", i);
-                break;
-            }
-            levels.push(level);
-        }
-        self.levels = levels;
-        Ok(())
+    /// Force the Wal to disk
+    pub fn sync(&self) -> Result<(), Error> {
+        self.wal.sync()
+    }
+
+    /// Optionally flush the MemTables, sync the Wal and record the clean
+    /// shutdown. Nothing may be written once the LSM Tree is closed.
+    pub fn close(&mut self, flush: bool) -> Result<(), Error> {
+        info!("Closing LSMTree in {:?}", self.path);
+        if flush {
+            self.flush()?;
+        }
+        self.wal.sync()?;
+        fs::File::create(self.path.join(CLEAN_SHUTDOWN_FILE))?.sync_all()?;
+        sync_dir(&self.path)
     }
 
-    fn compact(&mut self) -> Result<(), std::io::Error> {
-        match self.compaction_strategy {
-            CompactionStrategy::SizeTiered => self.compact_size_tiered(),
-            CompactionStrategy::LevelBased => self.compact_level_based(),
+    /// Flush the MemTables to new SSTables, if any of them holds an entry
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if self.families.values().all(|f| f.memtable.is_empty()) {
+            return Ok(());
         }
+        self.flush_memtables()
     }
 
-    fn compact_level_based(&mut self) -> Result<(), std::io::Error> {
-        info!("Starting compaction");
+    /// Merge the SSTables of a column family holding keys in `start..end`
+    /// into a single SSTable of the last level, dropping overwritten values,
+    /// tombstones, expired values and SSTables deleted by range tombstones.
+    ///
+    /// SSTables whose key range overlaps the selection are merged too, so
+    /// that every version of a merged key is part of the merge.
+    pub fn compact_range(
+        &mut self,
+        cf: &str,
+        start: Bound<&str>,
+        end: Bound<&str>,
+    ) -> Result<(), Error> {
+        info!("Compacting keys in {:?}..{:?} of {}", start, end, cf);
+        self.family(cf)?;
+        self.flush()?;
+        let family = self
+            .families
+            .get_mut(cf)
+            .ok_or_else(|| unknown_family(cf))?;
+        family.compact_range(
+            start,
+            end,
+            self.merge_operator.as_deref(),
+            self.compaction_filter.as_deref(),
+        )
+    }
 
-        fn compact(lsm_tree: &mut LSMTree, level: usize) -> Result<(), std::io::Error> {
-            if level >= lsm_tree.levels.len() {
-                lsm_tree.levels.push(Vec::new());
+    /// Flush the MemTables once any of them is full
+    fn flush_if_full(&mut self) -> Result<(), Error> {
+        if let Some(family) = self.families.values().find(|f| f.memtable.is_full()) {
+            warn!("MemTable of {} is full, flushing to SSTable", family.name());
+            self.flush_memtables()?;
+        }
+        Ok(())
+    }
+
+    /// Flush every MemTable holding entries to a new level 0 SSTable, then
+    /// reset the Wal and compact the column families
+    fn flush_memtables(&mut self) -> Result<(), Error> {
+        for family in self.families.values_mut() {
+            if !family.memtable.is_empty() {
+                family.flush_memtable()?;
             }
+        }
 
-            if lsm_tree.levels[level].len() >= lsm_tree.compaction_threshold {
-                let mut sstable_paths = Vec::new();
-                for sstable_index in 0..lsm_tree.levels[level].len() {
-                    // Collect SSTable paths for merging
-                    sstable_paths.push(
-                        Path::new(&lsm_tree.sstable_dir)
-                            .join(format!("sstable_{}_{}.txt", level, sstable_index)),
-                    );
-                }
+        // Reset Wal, every entry it holds is now in an SSTable
+        self.wal.reset()?;
 
-                let new_level = level + 1;
-                let output_index = lsm_tree.levels.get(new_level).map_or(0, |v| v.len());
-                let output_path = Path::new(&lsm_tree.sstable_dir)
-                    .join(format!("sstable_{}_{}.txt", new_level, output_index));
+        for family in self.families.values_mut() {
+            family.compact(
+                self.merge_operator.as_deref(),
+                self.compaction_filter.as_deref(),
+            )?;
+        }
+        Ok(())
+    }
+}
 
-                SSTable::merge(
-                    &sstable_paths
-                        .iter()
-                        .map(|p| p.as_path())
-                        .collect::<Vec<&Path>>(),
-                    &output_path,
-                )?;
+fn unknown_family(cf: &str) -> Error {
+    Error::NotFound(format!("column family {:?} does not exist", cf))
+}
 
-                lsm_tree.levels[level].clear();
-                if let Some(level) = lsm_tree.levels.get_mut(new_level) {
-                    level.push(SSTable::load(&output_path)?);
-                } else {
-                    lsm_tree.levels.push(vec![SSTable::load(&output_path)?]);
-                }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::test_util::TestDb;
 
-                // Recursively compact the next level if needed
-                compact(lsm_tree, new_level)?;
-            }
+    #[test]
+    fn batches_are_validated_as_encoded() {
+        let db = TestDb::open("lsm-batch-validation");
+        let mut lsm_tree = db.write_lock().unwrap();
+        let entries = vec![
+            (
+                DEFAULT_COLUMN_FAMILY.to_string(),
+                "a".to_string(),
+                StoredValue::new("1".to_string()),
+            ),
+            (
+                DEFAULT_COLUMN_FAMILY.to_string(),
+                "b".to_string(),
+                StoredValue::operand("x\ny".to_string()),
+            ),
+        ];
+        let result = lsm_tree.write_batch(entries);
+        assert!(
+            matches!(result, Err(Error::InvalidArgument(_))),
+            "{:?}",
+            result
+        );
+        assert_eq!(lsm_tree.read(DEFAULT_COLUMN_FAMILY, "a").unwrap(), None);
+    }
 
-            Ok(())
+    #[test]
+    fn scans_stop_reading_at_their_limit() {
+        let options = Options::builder().block_size(64).build().unwrap();
+        let db = TestDb::with_options("lsm-scan-limit", options);
+        let mut lsm_tree = db.write_lock().unwrap();
+        let cf = DEFAULT_COLUMN_FAMILY;
+        for i in 0..100 {
+            let key = format!("k{:03}", i);
+            lsm_tree.write(cf, key, "old".repeat(8)).unwrap();
         }
+        lsm_tree.flush().unwrap();
+        lsm_tree
+            .write(cf, "k000".to_string(), "new".to_string())
+            .unwrap();
+        lsm_tree
+            .write(cf, "k001".to_string(), String::new())
+            .unwrap();
+
+        // Damage the last block, which a scan of the first keys never reads
+        let sstable_dir = db.path().join(SSTABLE_DIR);
+        let path = fs::read_dir(&sstable_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|e| e == "txt"))
+            .unwrap();
+        let mut data = fs::read(&path).unwrap();
+        *data.last_mut().unwrap() ^= 0xff;
+        fs::write(&path, data).unwrap();
+
+        let all = (Bound::Unbounded, Bound::Unbounded);
+        let first = lsm_tree.scan(cf, all.0, all.1, 3).unwrap();
+        let keys: Vec<&str> = first.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["k000", "k002", "k003"]);
+        assert_eq!(first[0].1, "new");
+        let result = lsm_tree.scan(cf, all.0, all.1, usize::MAX);
+        assert!(matches!(result, Err(Error::Corruption(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn last_version_is_recovered_from_sstables() {
+        let mut db = TestDb::open("lsm-versions");
+        db.put("a", "1").unwrap();
+        db.delete_range("b", "c").unwrap();
+        db.flush().unwrap();
+        let last_version = db.read_lock().unwrap().last_version;
 
-        for level in 0..self.levels.len() {
-            compact(self, level)?;
+        db.reopen();
+        let lsm_tree = db.read_lock().unwrap();
+        assert!(lsm_tree.families[DEFAULT_COLUMN_FAMILY]
+            .memtable
+            .entries()
+            .is_empty());
+        assert_eq!(lsm_tree.last_version, last_version);
+    }
+
+    #[test]
+    fn expired_keys_read_as_missing_wherever_they_are_stored() {
+        use crate::common_enums::{Expiry, TimeToLive};
+
+        let mut db = TestDb::open("lsm-expiry");
+        db.put_with_expiry("flushed", "v", Expiry::In(100)).unwrap();
+        db.put("kept", "v").unwrap();
+        db.flush().unwrap();
+        db.put_with_expiry("buffered", "v", Expiry::In(100))
+            .unwrap();
+        assert!(db.expire("kept", Expiry::In(3_600_000)).unwrap());
+        assert!(!db.expire("missing", Expiry::In(100)).unwrap());
+        assert!(matches!(
+            db.ttl("kept").unwrap(),
+            TimeToLive::Remaining(ms) if ms > 3_590_000 && ms <= 3_600_000
+        ));
+        assert!(db.persist("kept").unwrap());
+        assert!(!db.persist("kept").unwrap());
+        assert_eq!(db.ttl("kept").unwrap(), TimeToLive::Persistent);
+
+        std::thread::sleep(std::time::Duration::from_millis(150));
+        for key in ["flushed", "buffered"] {
+            assert_eq!(db.get(key).unwrap(), None, "{}", key);
+            assert_eq!(db.ttl(key).unwrap(), TimeToLive::Missing, "{}", key);
+            assert!(!db.expire(key, Expiry::In(100)).unwrap(), "{}", key);
         }
+        let entries = db
+            .scan(Bound::Unbounded, Bound::Unbounded, usize::MAX)
+            .unwrap();
+        assert_eq!(entries, [("kept".to_string(), "v".to_string())]);
 
-        Ok(())
+        // Expired values stay gone once compacted and reopened
+        db.flush().unwrap();
+        db.compact_range(Bound::Unbounded, Bound::Unbounded)
+            .unwrap();
+        db.reopen();
+        assert_eq!(db.get("flushed").unwrap(), None);
+        assert_eq!(db.get("buffered").unwrap(), None);
+        assert_eq!(db.ttl("kept").unwrap(), TimeToLive::Persistent);
+    }
+
+    #[test]
+    fn compare_and_swap_outcomes() {
+        use crate::common_enums::{CasOutcome, Expected, Versioned};
+
+        let db = TestDb::open("lsm-cas");
+        let CasOutcome::Swapped {
+            version: Some(first),
+        } = db.put_if_absent("key", "a").unwrap()
+        else {
+            panic!("put_if_absent of a missing key did not swap");
+        };
+        let current = Some(Versioned {
+            value: "a".to_string(),
+            version: first,
+        });
+        assert_eq!(
+            db.put_if_absent("key", "b").unwrap(),
+            CasOutcome::Conflict {
+                current: current.clone()
+            }
+        );
+
+        // Versions are compared once read back from an SSTable too
+        db.flush().unwrap();
+        let by_version = |version| Some(Expected::Version(version));
+        let CasOutcome::Swapped {
+            version: Some(second),
+        } = db
+            .compare_and_swap("key", by_version(first), Some("b"))
+            .unwrap()
+        else {
+            panic!("swap of the current version did not go through");
+        };
+        assert!(second > first);
+        assert_eq!(
+            db.compare_and_swap("key", by_version(first), Some("c"))
+                .unwrap(),
+            CasOutcome::Conflict {
+                current: Some(Versioned {
+                    value: "b".to_string(),
+                    version: second,
+                })
+            }
+        );
+
+        assert!(matches!(
+            db.delete_if_equal("key", "a").unwrap(),
+            CasOutcome::Conflict { .. }
+        ));
+        assert_eq!(
+            db.delete_if_equal("key", "b").unwrap(),
+            CasOutcome::Swapped { version: None }
+        );
+        assert_eq!(db.get("key").unwrap(), None);
+        assert_eq!(
+            db.delete_if_equal("key", "b").unwrap(),
+            CasOutcome::Conflict { current: None }
+        );
     }
 
-    fn compact_size_tiered(&mut self) -> Result<(), std::io::Error> {
-        info!("Starting compaction");
-        todo!()
+    #[test]
+    fn multi_get_reads_every_level_in_request_order() {
+        let db = TestDb::open("lsm-multi-get");
+        for key in ["a", "b", "c", "d"] {
+            db.put(key, "old").unwrap();
+        }
+        db.flush().unwrap();
+        db.put("b", "newer").unwrap();
+        db.put("e", "flushed").unwrap();
+        db.flush().unwrap();
+        db.put("a", "buffered").unwrap();
+        db.delete("b").unwrap();
+        db.delete_range("c", "d").unwrap();
+
+        let keys = ["e", "missing", "a", "b", "c", "d", "a"];
+        let values = db.multi_get(&keys).unwrap();
+        let expected = [
+            Some("flushed"),
+            None,
+            Some("buffered"),
+            None,
+            None,
+            Some("old"),
+            Some("buffered"),
+        ];
+        assert_eq!(
+            values.iter().map(Option::as_deref).collect::<Vec<_>>(),
+            expected
+        );
+        for (key, value) in keys.iter().zip(&values) {
+            assert_eq!(&db.get(key).unwrap(), value, "{}", key);
+        }
+        assert!(db.multi_get(&[]).unwrap().is_empty());
     }
 }
@@ -1,74 +1,176 @@
-use std::{collections::BTreeMap, fs::File, io::{BufRead, BufWriter, Write}, path::Path, sync::RwLock};
+use std::{
+    collections::BTreeMap,
+    ops::Bound,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+};
 
-/// MemTable (in-memory store)
+use super::{
+    comparator::{ordered_range, OrderedKey},
+    range_tombstone::RangeTombstone,
+    Comparator, SSTable, StoredValue,
+};
+use crate::error::Error;
+
+/// MemTable (in-memory store), holding the keys in the order of the comparator
 pub(super) struct MemTable {
-    pub map: RwLock<BTreeMap<String, String>>,
+    pub map: RwLock<BTreeMap<OrderedKey, String>>,
+    pub range_tombstones: RwLock<Vec<RangeTombstone>>,
+    /// Approximate size in bytes of the keys and values held
+    pub size: AtomicUsize,
     pub max_size: usize,
+    comparator: Arc<dyn Comparator>,
 }
 
 impl MemTable {
     /// Create a new MemTable
-    pub fn new(max_size: usize) -> Self {
+    pub fn new(max_size: usize, comparator: Arc<dyn Comparator>) -> Self {
         MemTable {
             map: RwLock::new(BTreeMap::new()),
+            range_tombstones: RwLock::new(Vec::new()),
+            size: AtomicUsize::new(0),
             max_size,
+            comparator,
         }
     }
-    
-    /// Check and load from the Wal
-    pub fn load_from_wal(&self, wal: &Path) -> Result<(), std::io::Error> {
-        let file = File::open(wal)?;
-        let reader = std::io::BufReader::new(file);
-        for line in reader.lines() {
-            let line = line?;
-            if let Some((key, value)) = line.split_once(':') {
-                self.insert(key.to_string(), value.to_string());
-            }
+
+    fn ordered(&self, key: &str) -> OrderedKey {
+        OrderedKey::new(key.to_string(), &self.comparator)
+    }
+
+    /// Apply an entry replayed from the Wal
+    pub fn replay(&self, key: &str, value: &str) -> Result<(), Error> {
+        // Range tombstones are logged with an empty key
+        if key.is_empty() {
+            self.delete_range(RangeTombstone::decode(value)?);
+            return Ok(());
+        }
+        let stored = StoredValue::decode(value)?;
+        if stored.is_unresolved() {
+            self.merge(key.to_string(), stored)?;
+        } else {
+            self.insert(key.to_string(), value.to_string());
         }
         Ok(())
     }
 
-    /// Insert a key-value pair
+    /// Insert a key-value pair. An empty value is a tombstone marking the key
+    /// as deleted, it shadows older values in the SSTables.
     pub fn insert(&self, key: String, value: String) {
-        let mut map = self.map.write().unwrap();
-        if value.is_empty() {
-            map.remove(&key);
-        } else {
-            map.insert(key, value);
+        let mut map = self.map.write().unwrap_or_else(|e| e.into_inner());
+        let len = key.len();
+        self.size.fetch_add(len + value.len(), Ordering::Relaxed);
+        if let Some(old) = map.insert(OrderedKey::new(key, &self.comparator), value) {
+            self.size.fetch_sub(len + old.len(), Ordering::Relaxed);
+        }
+    }
+
+    /// Stack merge operands on the entry of the key. Without an entry they
+    /// stay unresolved, to fold onto the older values of the key in the SSTables.
+    pub fn merge(&self, key: String, operands: StoredValue) -> Result<(), Error> {
+        let older = self
+            .get(&key)
+            .map(|raw| StoredValue::decode(&raw))
+            .transpose()?;
+        let stored = match older {
+            Some(older) => operands.stack_on(Some(older)),
+            None => operands,
+        };
+        self.insert(key, stored.encode());
+        Ok(())
+    }
+
+    /// Record a range tombstone. The entries it deletes are removed, so that
+    /// later merge operands do not stack on them.
+    pub fn delete_range(&self, tombstone: RangeTombstone) {
+        let mut map = self.map.write().unwrap_or_else(|e| e.into_inner());
+        let range = ordered_range(
+            &self.comparator,
+            Bound::Included(&tombstone.start),
+            Bound::Excluded(&tombstone.end),
+        );
+        let deleted: Vec<OrderedKey> = range
+            .map(|range| map.range(range).map(|(k, _)| k.clone()).collect())
+            .unwrap_or_default();
+        for key in deleted {
+            if let Some(old) = map.remove(&key) {
+                self.size
+                    .fetch_sub(key.as_str().len() + old.len(), Ordering::Relaxed);
+            }
         }
+        self.size
+            .fetch_add(tombstone.encode().len(), Ordering::Relaxed);
+        let mut range_tombstones = self
+            .range_tombstones
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        range_tombstones.push(tombstone);
+    }
+
+    /// Copy the range tombstones
+    pub fn range_tombstones(&self) -> Vec<RangeTombstone> {
+        self.range_tombstones
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
     }
 
-    /// Get a value by key
+    /// Get a value by key, tombstones are returned as empty values
     pub fn get(&self, key: &str) -> Option<String> {
-        let map = self.map.read().unwrap();
-        map.get(key).cloned()
+        let map = self.map.read().unwrap_or_else(|e| e.into_inner());
+        map.get(&self.ordered(key)).cloned()
+    }
+
+    /// Get the values of several keys at once, tombstones are returned as
+    /// empty values
+    pub fn get_many(&self, keys: &[&str]) -> Vec<Option<String>> {
+        let map = self.map.read().unwrap_or_else(|e| e.into_inner());
+        keys.iter()
+            .map(|key| map.get(&self.ordered(key)).cloned())
+            .collect()
+    }
+
+    /// Get the entries with keys in `start..end`, tombstones included
+    pub fn scan(&self, start: Bound<&str>, end: Bound<&str>) -> BTreeMap<OrderedKey, String> {
+        let Some(range) = ordered_range(&self.comparator, start, end) else {
+            return BTreeMap::new();
+        };
+        let map = self.map.read().unwrap_or_else(|e| e.into_inner());
+        map.range(range)
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    /// Copy every entry, tombstones included
+    pub fn entries(&self) -> BTreeMap<OrderedKey, String> {
+        self.map.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Check if the MemTable holds no entry nor range tombstone
+    pub fn is_empty(&self) -> bool {
+        self.map
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_empty()
+            && self.range_tombstones().is_empty()
     }
 
     /// Check if the MemTable is full
     pub fn is_full(&self) -> bool {
-        let map = self.map.read().unwrap();
-        map.len() >= self.max_size
+        self.size.load(Ordering::Relaxed) >= self.max_size
     }
 
     /// Flush MemTable to an SSTable
-    pub fn flush_to_sstable(&self, path: &Path) -> Result<(), std::io::Error> {
-        let map = self.map.read().unwrap();
-        let ss_table_file = File::create(path)?;
-        let mut ss_table_writer = BufWriter::new(ss_table_file);
-        let index_file = File::create(path.with_extension("index"))?;
-        let mut index_writer = BufWriter::new(index_file);
-        let mut offset = 0;
-
-        for (key, value) in map.iter() {
-            let ss_table_line = format!("{}:{}\n", key, value);
-            let index_line = format!("{}:{}\n", key, offset);
-            ss_table_writer.write_all(ss_table_line.as_bytes())?;
-            index_writer.write_all(index_line.as_bytes())?;
-            offset += ss_table_line.len() as u64;
-        }
-        ss_table_writer.flush()?;
-        index_writer.flush()?;
-        
-        Ok(())
+    pub fn flush_to_sstable(&self, path: &Path, block_size: usize) -> Result<(), Error> {
+        let map = self.map.read().unwrap_or_else(|e| e.into_inner());
+        SSTable::write(
+            path,
+            map.iter().map(|(k, v)| (k.as_str().to_string(), v.clone())),
+            &self.range_tombstones(),
+            block_size,
+        )
     }
-}
\ No newline at end of file
+}
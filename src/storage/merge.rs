@@ -0,0 +1,237 @@
+use std::sync::Arc;
+
+use log::warn;
+
+use super::StoredValue;
+use crate::error::Error;
+
+/// Folds merge operands onto the value of a key.
+///
+/// Merges let a write describe a change to a value, e.g. "add 5", without
+/// reading it first. The operands are stored as they are written, and
+/// folded onto the value they apply to by reads and compaction.
+pub trait MergeOperator: Send + Sync {
+    /// Name of the operator, used in logs and errors
+    fn name(&self) -> &str;
+
+    /// Fold `operands`, oldest first, onto the `existing` value of `key`,
+    /// `None` if the key is missing. An empty result deletes the key.
+    fn full_merge(
+        &self,
+        key: &str,
+        existing: Option<&str>,
+        operands: &[String],
+    ) -> Result<String, Error>;
+
+    /// Combine two consecutive operands into a single one with the same
+    /// effect, `None` if they cannot be combined
+    fn partial_merge(&self, _key: &str, _older: &str, _newer: &str) -> Option<String> {
+        None
+    }
+}
+
+/// Adds `i64` operands to an `i64` value, a missing key counting as 0
+#[derive(Debug, Clone, Copy, Default)]
+pub struct I64Add;
+
+impl MergeOperator for I64Add {
+    fn name(&self) -> &str {
+        "i64-add"
+    }
+
+    fn full_merge(
+        &self,
+        key: &str,
+        existing: Option<&str>,
+        operands: &[String],
+    ) -> Result<String, Error> {
+        let mut sum = existing.map_or(Ok(0), |v| parse_i64(key, v))?;
+        for operand in operands {
+            sum = sum
+                .checked_add(parse_i64(key, operand)?)
+                .ok_or_else(|| Error::Overflow(format!("value of key {:?} overflows i64", key)))?;
+        }
+        Ok(sum.to_string())
+    }
+
+    fn partial_merge(&self, key: &str, older: &str, newer: &str) -> Option<String> {
+        let sum = parse_i64(key, older)
+            .ok()?
+            .checked_add(parse_i64(key, newer).ok()?)?;
+        Some(sum.to_string())
+    }
+}
+
+/// Appends operands to a string value, separated by a delimiter
+#[derive(Debug, Clone, Default)]
+pub struct StringAppend {
+    delimiter: String,
+}
+
+impl StringAppend {
+    pub fn new(delimiter: &str) -> Self {
+        StringAppend {
+            delimiter: delimiter.to_string(),
+        }
+    }
+}
+
+impl MergeOperator for StringAppend {
+    fn name(&self) -> &str {
+        "string-append"
+    }
+
+    fn full_merge(
+        &self,
+        _key: &str,
+        existing: Option<&str>,
+        operands: &[String],
+    ) -> Result<String, Error> {
+        let parts: Vec<&str> = existing
+            .into_iter()
+            .chain(operands.iter().map(String::as_str))
+            .collect();
+        Ok(parts.join(&self.delimiter))
+    }
+
+    fn partial_merge(&self, _key: &str, older: &str, newer: &str) -> Option<String> {
+        Some(format!("{}{}{}", older, self.delimiter, newer))
+    }
+}
+
+/// Keeps the greatest of the `i64` value and operands
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Max;
+
+impl MergeOperator for Max {
+    fn name(&self) -> &str {
+        "max"
+    }
+
+    fn full_merge(
+        &self,
+        key: &str,
+        existing: Option<&str>,
+        operands: &[String],
+    ) -> Result<String, Error> {
+        let mut max = existing.map(|v| parse_i64(key, v)).transpose()?;
+        for operand in operands {
+            let operand = parse_i64(key, operand)?;
+            max = Some(max.map_or(operand, |max| max.max(operand)));
+        }
+        Ok(max.map(|max| max.to_string()).unwrap_or_default())
+    }
+
+    fn partial_merge(&self, key: &str, older: &str, newer: &str) -> Option<String> {
+        let max = parse_i64(key, older).ok()?.max(parse_i64(key, newer).ok()?);
+        Some(max.to_string())
+    }
+}
+
+/// Built-in merge operator named `name`: `i64-add`, `string-append` or `max`
+pub fn builtin_merge_operator(name: &str) -> Result<Arc<dyn MergeOperator>, Error> {
+    match name {
+        "i64-add" => Ok(Arc::new(I64Add)),
+        "string-append" => Ok(Arc::new(StringAppend::default())),
+        "max" => Ok(Arc::new(Max)),
+        _ => Err(Error::InvalidArgument(format!(
+            "unknown merge operator {:?}, expected i64-add, string-append or max",
+            name
+        ))),
+    }
+}
+
+/// Parse the `i64` value of a key, in its decimal text encoding
+pub(super) fn parse_i64(key: &str, value: &str) -> Result<i64, Error> {
+    value.parse().map_err(|_| {
+        Error::InvalidArgument(format!("value {:?} of key {:?} is not an i64", value, key))
+    })
+}
+
+/// Fold the operands of a value onto its base, which must be known
+pub(super) fn fold(
+    key: &str,
+    stored: StoredValue,
+    operator: Option<&dyn MergeOperator>,
+) -> Result<StoredValue, Error> {
+    if stored.operands.is_empty() {
+        return Ok(stored);
+    }
+    debug_assert!(stored.has_base);
+    let operator = operator.ok_or_else(|| {
+        Error::InvalidArgument(format!(
+            "key {:?} holds merge operands but no merge operator is set",
+            key
+        ))
+    })?;
+    let base = (!stored.value.is_empty()).then_some(stored.value.as_str());
+    let value = operator.full_merge(key, base, &stored.operands)?;
+    if value.contains('\n') {
+        return Err(Error::InvalidArgument(format!(
+            "merge operator {} produced a value with newlines",
+            operator.name()
+        )));
+    }
+    Ok(StoredValue {
+        value,
+        expires_at: stored.expires_at,
+        version: stored.version,
+        ..Default::default()
+    })
+}
+
+/// Collapse the operands of a value as far as `operator` allows: fully onto
+/// their base when it is known, pairwise otherwise. Operands failing to
+/// fold are kept as they are, for reads to report the error.
+pub(super) fn collapse(
+    key: &str,
+    stored: StoredValue,
+    operator: &dyn MergeOperator,
+) -> StoredValue {
+    if stored.operands.is_empty() {
+        return stored;
+    }
+    if stored.has_base {
+        return match fold(key, stored.clone(), Some(operator)) {
+            Ok(folded) => folded,
+            Err(e) => {
+                warn!("Keeping the merge operands of key {}: {}", key, e);
+                stored
+            }
+        };
+    }
+    let mut operands: Vec<String> = Vec::with_capacity(stored.operands.len());
+    for operand in stored.operands {
+        let merged = operands
+            .last()
+            .and_then(|last| operator.partial_merge(key, last, &operand))
+            .filter(|merged| !merged.contains('\n'));
+        match merged {
+            Some(merged) => *operands.last_mut().unwrap() = merged,
+            None => operands.push(operand),
+        }
+    }
+    StoredValue { operands, ..stored }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn i64_add_reports_overflow() {
+        let operands = ["1".to_string(), "2".to_string()];
+        assert_eq!(I64Add.full_merge("k", Some("-3"), &operands).unwrap(), "0");
+
+        let result = I64Add.full_merge("k", Some(&i64::MAX.to_string()), &operands);
+        assert!(matches!(result, Err(Error::Overflow(_))), "{:?}", result);
+        // Operands overflowing among themselves are left for the full merge
+        assert_eq!(I64Add.partial_merge("k", &i64::MAX.to_string(), "1"), None);
+        let result = I64Add.full_merge("k", Some("x"), &operands);
+        assert!(
+            matches!(result, Err(Error::InvalidArgument(_))),
+            "{:?}",
+            result
+        );
+    }
+}
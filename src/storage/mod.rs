@@ -1,10 +1,38 @@
+mod block_cache;
 mod bloom_filter;
+mod column_family;
+mod compaction_filter;
+mod comparator;
+mod eviction;
+mod lock_manager;
+mod lsm_tree;
+mod mem_table;
+mod merge;
+mod options;
+mod range_tombstone;
+mod snapshot;
 mod ss_table;
+#[cfg(test)]
+pub(crate) mod test_util;
+mod transaction;
+mod value;
 mod wal;
-mod mem_table;
-mod lsm_tree;
 
+use block_cache::BlockCache;
+use bloom_filter::BloomFilter;
+pub(crate) use column_family::qualified_key;
+pub use column_family::DEFAULT_COLUMN_FAMILY;
+pub use compaction_filter::{CompactionContext, CompactionFilter, FilterDecision};
+pub use comparator::{builtin_comparator, Bytewise, Comparator, DecimalU64, ReverseBytewise};
+use eviction::KeyTracker;
+pub use eviction::MemoryStats;
+pub use lock_manager::LockMode;
+pub(crate) use lock_manager::{BatchLocks, LockManager};
 pub use lsm_tree::LSMTree;
+pub use merge::{builtin_merge_operator, I64Add, Max, MergeOperator, StringAppend};
+pub use options::{CloseOptions, Options, OptionsBuilder, ReadOptions};
+pub use snapshot::Snapshot;
 use ss_table::SSTable;
+pub use transaction::Transaction;
+pub(crate) use value::{now_millis, StoredValue};
 use wal::Wal;
-use bloom_filter::BloomFilter;
\ No newline at end of file
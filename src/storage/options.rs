@@ -0,0 +1,339 @@
+use std::{fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::common_enums::{CompactionStrategy, EvictionPolicy, SyncPolicy};
+use crate::error::Error;
+
+#[cfg(feature = "size_tiered")]
+const DEFAULT_COMPACTION_STRATEGY: CompactionStrategy = CompactionStrategy::SizeTiered;
+
+#[cfg(not(feature = "size_tiered"))]
+const DEFAULT_COMPACTION_STRATEGY: CompactionStrategy = CompactionStrategy::LevelBased;
+
+/// Name of the file the options are persisted to inside the DB directory
+pub(super) const OPTIONS_FILE: &str = "OPTIONS";
+
+/// Tunables of an LSM Tree
+///
+/// Options can be built with [`Options::builder`], loaded from a TOML file
+/// with [`Options::from_file`], or both: fields missing from the file keep
+/// their default value.
+///
+/// A column family is tuned by its own `write_buffer_size`, compaction and
+/// SSTable options, and shares the others with the database.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    /// Create the DB directory if it does not exist
+    pub create_if_missing: bool,
+    /// Approximate size in bytes of the MemTable before it is flushed
+    pub write_buffer_size: usize,
+    /// Number of SSTables in level 0 that triggers a compaction
+    pub level0_compaction_trigger: usize,
+    /// Growth factor of the compaction trigger from one level to the next
+    pub level_size_multiplier: usize,
+    /// Maximum number of levels
+    pub max_levels: usize,
+    /// Bloom filter bits per key
+    pub bloom_bits_per_key: usize,
+    /// Target size in bytes of an SSTable data block
+    pub block_size: usize,
+    /// Capacity in bytes of the block cache, 0 disables it
+    pub block_cache_size: usize,
+    /// When the Wal is synced to disk
+    pub sync_policy: SyncPolicy,
+    /// How SSTables are compacted
+    pub compaction_strategy: CompactionStrategy,
+    /// Approximate size in bytes the keys and values of every column family
+    /// may take before keys are evicted, 0 for no limit
+    pub max_memory: usize,
+    /// Keys evicted once `max_memory` is reached. Evicted keys are deleted.
+    pub eviction_policy: EvictionPolicy,
+    /// Milliseconds a transaction or batch waits for a row lock
+    pub lock_timeout_ms: u64,
+    /// Name of the comparator ordering the keys, fixed when the database is
+    /// created: `bytewise`, `reverse-bytewise`, `decimal-u64` or the name of a
+    /// custom comparator
+    pub comparator: String,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            create_if_missing: true,
+            write_buffer_size: 4 * 1024 * 1024,
+            level0_compaction_trigger: 4,
+            level_size_multiplier: 10,
+            max_levels: 7,
+            bloom_bits_per_key: 10,
+            block_size: 4096,
+            block_cache_size: 8 * 1024 * 1024,
+            sync_policy: SyncPolicy::Never,
+            compaction_strategy: DEFAULT_COMPACTION_STRATEGY,
+            max_memory: 0,
+            eviction_policy: EvictionPolicy::NoEviction,
+            lock_timeout_ms: 1000,
+            comparator: "bytewise".to_string(),
+        }
+    }
+}
+
+impl Options {
+    /// Start building options from the defaults
+    pub fn builder() -> OptionsBuilder {
+        Options::default().to_builder()
+    }
+
+    /// Start building options from these options
+    pub fn to_builder(&self) -> OptionsBuilder {
+        OptionsBuilder {
+            options: self.clone(),
+        }
+    }
+
+    /// Parse options from a TOML string
+    pub fn from_toml(toml: &str) -> Result<Self, Error> {
+        let options: Options = toml::from_str(toml)
+            .map_err(|e| Error::InvalidArgument(format!("invalid options: {}", e)))?;
+        options.validate()?;
+        Ok(options)
+    }
+
+    /// Load options from a TOML file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::from_toml(&fs::read_to_string(path)?)
+    }
+
+    /// Serialize options to TOML
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("options are always representable as TOML")
+    }
+
+    /// Check that every tunable is within its accepted range
+    pub fn validate(&self) -> Result<(), Error> {
+        fn check(ok: bool, msg: &str) -> Result<(), Error> {
+            if ok {
+                Ok(())
+            } else {
+                Err(Error::InvalidArgument(msg.to_string()))
+            }
+        }
+
+        check(
+            self.write_buffer_size > 0,
+            "write_buffer_size must be positive",
+        )?;
+        check(
+            self.level0_compaction_trigger >= 2,
+            "level0_compaction_trigger must be at least 2",
+        )?;
+        check(
+            self.level_size_multiplier >= 1,
+            "level_size_multiplier must be at least 1",
+        )?;
+        check(self.max_levels >= 1, "max_levels must be at least 1")?;
+        check(
+            (1..=64).contains(&self.bloom_bits_per_key),
+            "bloom_bits_per_key must be between 1 and 64",
+        )?;
+        check(
+            (64..=u32::MAX as usize / 2).contains(&self.block_size),
+            "block_size must be between 64 bytes and 2 GiB",
+        )?;
+        check(!self.comparator.is_empty(), "comparator must not be empty")?;
+        Ok(())
+    }
+
+    /// Persist the options to the OPTIONS file of a DB directory
+    pub(super) fn persist(&self, db_dir: &Path) -> Result<(), Error> {
+        let tmp_path = db_dir.join(format!("{}.tmp", OPTIONS_FILE));
+        fs::write(&tmp_path, self.to_toml())?;
+        fs::rename(&tmp_path, db_dir.join(OPTIONS_FILE))?;
+        Ok(())
+    }
+
+    /// Options of a column family created or opened with `family`: its
+    /// MemTable, compaction and SSTable options, and the database-wide ones
+    /// of these options
+    pub(super) fn for_column_family(&self, family: &Options) -> Options {
+        Options {
+            write_buffer_size: family.write_buffer_size,
+            level0_compaction_trigger: family.level0_compaction_trigger,
+            level_size_multiplier: family.level_size_multiplier,
+            max_levels: family.max_levels,
+            bloom_bits_per_key: family.bloom_bits_per_key,
+            block_size: family.block_size,
+            compaction_strategy: family.compaction_strategy,
+            ..self.clone()
+        }
+    }
+
+    /// Number of SSTables in a level that triggers its compaction
+    pub(super) fn level_compaction_trigger(&self, level: usize) -> usize {
+        let multiplier = self
+            .level_size_multiplier
+            .saturating_pow(level.min(u32::MAX as usize) as u32);
+        self.level0_compaction_trigger.saturating_mul(multiplier)
+    }
+}
+
+/// Builder for [`Options`]
+#[derive(Debug, Clone)]
+pub struct OptionsBuilder {
+    options: Options,
+}
+
+impl OptionsBuilder {
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> Self {
+        self.options.create_if_missing = create_if_missing;
+        self
+    }
+
+    pub fn write_buffer_size(mut self, bytes: usize) -> Self {
+        self.options.write_buffer_size = bytes;
+        self
+    }
+
+    pub fn level0_compaction_trigger(mut self, sstables: usize) -> Self {
+        self.options.level0_compaction_trigger = sstables;
+        self
+    }
+
+    pub fn level_size_multiplier(mut self, multiplier: usize) -> Self {
+        self.options.level_size_multiplier = multiplier;
+        self
+    }
+
+    pub fn max_levels(mut self, levels: usize) -> Self {
+        self.options.max_levels = levels;
+        self
+    }
+
+    pub fn bloom_bits_per_key(mut self, bits: usize) -> Self {
+        self.options.bloom_bits_per_key = bits;
+        self
+    }
+
+    pub fn block_size(mut self, bytes: usize) -> Self {
+        self.options.block_size = bytes;
+        self
+    }
+
+    pub fn block_cache_size(mut self, bytes: usize) -> Self {
+        self.options.block_cache_size = bytes;
+        self
+    }
+
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.options.sync_policy = sync_policy;
+        self
+    }
+
+    pub fn compaction_strategy(mut self, strategy: CompactionStrategy) -> Self {
+        self.options.compaction_strategy = strategy;
+        self
+    }
+
+    pub fn max_memory(mut self, bytes: usize) -> Self {
+        self.options.max_memory = bytes;
+        self
+    }
+
+    pub fn eviction_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.options.eviction_policy = policy;
+        self
+    }
+
+    pub fn lock_timeout_ms(mut self, millis: u64) -> Self {
+        self.options.lock_timeout_ms = millis;
+        self
+    }
+
+    /// Select a built-in comparator by name, see [`super::builtin_comparator`]
+    pub fn comparator(mut self, name: &str) -> Self {
+        self.options.comparator = name.to_string();
+        self
+    }
+
+    /// Validate and return the options
+    pub fn build(self) -> Result<Options, Error> {
+        self.options.validate()?;
+        Ok(self.options)
+    }
+}
+
+/// Options controlling a single read
+#[derive(Debug, Clone)]
+pub struct ReadOptions {
+    /// Verify the checksum of every SSTable block read from disk
+    pub verify_checksums: bool,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        ReadOptions {
+            verify_checksums: true,
+        }
+    }
+}
+
+/// Options controlling how a database is closed
+#[derive(Debug, Clone, Default)]
+pub struct CloseOptions {
+    /// Flush the MemTable to an SSTable, so that the next open does not
+    /// replay the Wal
+    pub flush_memtable: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_missing_from_toml_keep_their_default() {
+        let options =
+            Options::from_toml("write_buffer_size = 1024\nsync_policy = \"Always\"").unwrap();
+        assert_eq!(
+            options,
+            Options::builder()
+                .write_buffer_size(1024)
+                .sync_policy(SyncPolicy::Always)
+                .build()
+                .unwrap()
+        );
+        assert_eq!(Options::from_toml("").unwrap(), Options::default());
+
+        let options = Options::builder()
+            .max_memory(1 << 20)
+            .eviction_policy(EvictionPolicy::AllKeysLru)
+            .comparator("decimal-u64")
+            .build()
+            .unwrap();
+        assert_eq!(Options::from_toml(&options.to_toml()).unwrap(), options);
+    }
+
+    #[test]
+    fn invalid_options_are_rejected() {
+        for toml in [
+            "write_buffer_size = 0",
+            "level0_compaction_trigger = 1",
+            "bloom_bits_per_key = 65",
+            "block_size = 10",
+            "comparator = \"\"",
+            "unknown_option = 1",
+            "sync_policy = \"sometimes\"",
+            "write_buffer_size = \"big\"",
+        ] {
+            let result = Options::from_toml(toml);
+            assert!(
+                matches!(result, Err(Error::InvalidArgument(_))),
+                "{}: {:?}",
+                toml,
+                result
+            );
+        }
+        let result = Options::builder().max_levels(0).build();
+        assert!(matches!(result, Err(Error::InvalidArgument(_))));
+    }
+}
@@ -0,0 +1,107 @@
+use std::cmp::Ordering;
+
+use super::Comparator;
+use crate::error::Error;
+
+/// Deletes every key in `start..end`, in the order of the comparator,
+/// written before `version`.
+///
+/// Stored in the Wal and in the meta-block of SSTables as
+/// `version len:start end`, e.g. `42 4:usr/usr0` for `usr/..usr0`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RangeTombstone {
+    pub start: String,
+    pub end: String,
+    /// Version of the deletion, values of older versions are deleted
+    pub version: u64,
+}
+
+impl RangeTombstone {
+    /// Whether the tombstone deletes the value of `key` stored by `version`
+    pub fn covers(&self, comparator: &dyn Comparator, key: &str, version: u64) -> bool {
+        version < self.version
+            && comparator.compare(&self.start, key) != Ordering::Greater
+            && comparator.compare(key, &self.end) == Ordering::Less
+    }
+
+    /// Whether every key in `first..=last` is in the range of the tombstone
+    pub fn spans(&self, comparator: &dyn Comparator, first: &str, last: &str) -> bool {
+        comparator.compare(&self.start, first) != Ordering::Greater
+            && comparator.compare(last, &self.end) == Ordering::Less
+    }
+
+    pub fn encode(&self) -> String {
+        format!(
+            "{} {}:{}{}",
+            self.version,
+            self.start.len(),
+            self.start,
+            self.end
+        )
+    }
+
+    pub fn decode(raw: &str) -> Result<Self, Error> {
+        let decoded = raw.split_once(' ').and_then(|(version, rest)| {
+            let (len, rest) = rest.split_once(':')?;
+            let len = len.parse::<usize>().ok()?;
+            Some(RangeTombstone {
+                start: rest.get(..len)?.to_string(),
+                end: rest.get(len..)?.to_string(),
+                version: version.parse().ok()?,
+            })
+        });
+        decoded.ok_or_else(|| Error::Corruption(format!("invalid range tombstone {:?}", raw)))
+    }
+}
+
+/// Whether any of `tombstones` deletes the value of `key` stored by `version`
+pub(super) fn covered<'a>(
+    tombstones: impl IntoIterator<Item = &'a RangeTombstone>,
+    comparator: &dyn Comparator,
+    key: &str,
+    version: u64,
+) -> bool {
+    tombstones
+        .into_iter()
+        .any(|t| t.covers(comparator, key, version))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use crate::{storage::test_util::TestDb, Db};
+
+    fn keys(db: &Db) -> Vec<String> {
+        let entries = db
+            .scan(Bound::Unbounded, Bound::Unbounded, usize::MAX)
+            .unwrap();
+        entries.into_iter().map(|(key, _)| key).collect()
+    }
+
+    #[test]
+    fn range_delete_survives_flush_and_compaction() {
+        let mut db = TestDb::open("range-delete");
+        for key in ["a", "b", "c", "d", "e"] {
+            db.put(key, "old").unwrap();
+        }
+        db.flush().unwrap();
+        db.delete_range("b", "d").unwrap();
+        db.put("c", "new").unwrap();
+        let expected = ["a", "c", "d", "e"];
+        assert_eq!(keys(&db), expected);
+
+        db.flush().unwrap();
+        assert_eq!(keys(&db), expected);
+        assert_eq!(db.get("b").unwrap(), None);
+
+        db.compact_range(Bound::Unbounded, Bound::Unbounded)
+            .unwrap();
+        assert_eq!(keys(&db), expected);
+        assert_eq!(db.get("c").unwrap().as_deref(), Some("new"));
+
+        db.reopen();
+        assert_eq!(keys(&db), expected);
+        assert_eq!(db.get("b").unwrap(), None);
+    }
+}
@@ -0,0 +1,425 @@
+use std::{cmp::Ordering, collections::BTreeMap, ops::Bound, sync::Arc};
+
+use super::{
+    comparator::{self, ordered_range, OrderedKey},
+    merge::fold,
+    range_tombstone::{covered, RangeTombstone},
+    value::{now_millis, StoredValue},
+    Comparator, MergeOperator, ReadOptions, SSTable,
+};
+use crate::error::Error;
+
+/// A consistent, read-only view of an LSM Tree at the time it was taken.
+///
+/// Writes made after the snapshot are not visible through it. The snapshot
+/// holds on to the SSTables it reads from, so compaction does not disturb it.
+pub struct Snapshot {
+    /// Copy of the MemTable, tombstones included
+    memtable: BTreeMap<OrderedKey, String>,
+    /// Range tombstones of the MemTable
+    range_tombstones: Vec<RangeTombstone>,
+    /// SSTables of every level, newest first
+    levels: Vec<Vec<Arc<SSTable>>>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    comparator: Arc<dyn Comparator>,
+}
+
+impl Snapshot {
+    pub(super) fn new(
+        memtable: BTreeMap<OrderedKey, String>,
+        range_tombstones: Vec<RangeTombstone>,
+        levels: Vec<Vec<Arc<SSTable>>>,
+        merge_operator: Option<Arc<dyn MergeOperator>>,
+        comparator: Arc<dyn Comparator>,
+    ) -> Self {
+        Snapshot {
+            memtable,
+            range_tombstones,
+            levels,
+            merge_operator,
+            comparator,
+        }
+    }
+
+    /// MemTable entry of a key, tombstones included
+    fn memtable_entry(&self, key: &str) -> Option<String> {
+        let key = OrderedKey::new(key.to_string(), &self.comparator);
+        self.memtable.get(&key).cloned()
+    }
+
+    /// Read the value of a key
+    pub fn get(&self, key: &str) -> Result<Option<String>, Error> {
+        self.get_with_options(key, &ReadOptions::default())
+    }
+
+    /// Read the value of a key with explicit read options
+    pub fn get_with_options(
+        &self,
+        key: &str,
+        options: &ReadOptions,
+    ) -> Result<Option<String>, Error> {
+        let stored = self.read_stored(key, options)?;
+        Ok(stored.map(|stored| stored.value))
+    }
+
+    /// Read the value of a key along with its metadata
+    pub(super) fn get_stored(&self, key: &str) -> Result<Option<StoredValue>, Error> {
+        self.read_stored(key, &ReadOptions::default())
+    }
+
+    fn read_stored(&self, key: &str, options: &ReadOptions) -> Result<Option<StoredValue>, Error> {
+        read_key(
+            self.memtable_entry(key),
+            &self.range_tombstones,
+            &self.levels,
+            key,
+            options,
+            self.merge_operator.as_deref(),
+            self.comparator.as_ref(),
+        )
+    }
+
+    /// Read the values of several keys, in the order of `keys`
+    pub fn multi_get(&self, keys: &[&str]) -> Result<Vec<Option<String>>, Error> {
+        let comparator = self.comparator.as_ref();
+        let sorted = sorted_keys(keys, comparator);
+        let entries = sorted.iter().map(|key| self.memtable_entry(key)).collect();
+        let values = read_keys(
+            &sorted,
+            entries,
+            &self.range_tombstones,
+            &self.levels,
+            &ReadOptions::default(),
+            self.merge_operator.as_deref(),
+            comparator,
+        )?;
+        Ok(in_request_order(keys, &sorted, &values, comparator))
+    }
+
+    /// Get at most `limit` key-value pairs with keys in `start..end`, in key order
+    pub fn scan(
+        &self,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>, Error> {
+        let Some(range) = ordered_range(&self.comparator, start, end) else {
+            return Ok(Vec::new());
+        };
+        let memtable = self
+            .memtable
+            .range(range)
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        scan_levels(
+            memtable,
+            &self.range_tombstones,
+            &self.levels,
+            (start, end),
+            limit,
+            self.merge_operator.as_deref(),
+            &self.comparator,
+        )
+    }
+
+    /// Get at most `limit` key-value pairs whose key starts with `prefix`
+    pub fn scan_prefix(&self, prefix: &str, limit: usize) -> Result<Vec<(String, String)>, Error> {
+        let unbounded = (Bound::Unbounded, Bound::Unbounded);
+        comparator::scan_prefix(
+            self.comparator.as_ref(),
+            prefix,
+            unbounded,
+            limit,
+            |start, end, limit| self.scan(start, end, limit),
+        )
+    }
+}
+
+/// Read the live value of a key from its MemTable entry, if any, and the
+/// SSTables ordered newest first, given the range tombstones of the
+/// MemTable. Merge operands are stacked on the older entries of the key
+/// until their base is found, then folded onto it.
+pub(super) fn read_key(
+    memtable_entry: Option<String>,
+    range_tombstones: &[RangeTombstone],
+    levels: &[Vec<Arc<SSTable>>],
+    key: &str,
+    options: &ReadOptions,
+    merge_operator: Option<&dyn MergeOperator>,
+    comparator: &dyn Comparator,
+) -> Result<Option<StoredValue>, Error> {
+    let now = now_millis();
+    // Values older than the latest range deletion of the key are deleted
+    let deleted_before = range_tombstones
+        .iter()
+        .chain(levels.iter().flatten().flat_map(|t| t.range_tombstones()))
+        .filter(|t| t.covers(comparator, key, 0))
+        .map(|t| t.version)
+        .max()
+        .unwrap_or(0);
+    let decode = |raw: &str| -> Result<StoredValue, Error> {
+        let stored = StoredValue::decode(raw)?.without_expired(now);
+        if stored.version < deleted_before {
+            return Ok(StoredValue::default());
+        }
+        Ok(stored)
+    };
+    let mut sstables = levels.iter().flatten().filter(|t| t.might_contain(key));
+    let mut next_older = || -> Result<Option<StoredValue>, Error> {
+        for sstable in sstables.by_ref() {
+            if let Some(raw) = sstable.read(key, options)? {
+                return decode(&raw).map(Some);
+            }
+        }
+        Ok(None)
+    };
+    let mut stored = match memtable_entry {
+        Some(raw) => decode(&raw)?,
+        None => match next_older()? {
+            Some(stored) => stored,
+            None => return Ok(None),
+        },
+    };
+    while stored.is_unresolved() {
+        stored = stored.stack_on(next_older()?);
+    }
+    let stored = fold(key, stored, merge_operator)?;
+    Ok(Some(stored).filter(|s| !s.is_tombstone()))
+}
+
+/// Read the live values of sorted, deduplicated keys like [`read_key`],
+/// probing every SSTable once for all the keys not resolved by newer
+/// entries
+pub(super) fn read_keys(
+    keys: &[&str],
+    memtable_entries: Vec<Option<String>>,
+    range_tombstones: &[RangeTombstone],
+    levels: &[Vec<Arc<SSTable>>],
+    options: &ReadOptions,
+    merge_operator: Option<&dyn MergeOperator>,
+    comparator: &dyn Comparator,
+) -> Result<Vec<Option<StoredValue>>, Error> {
+    let now = now_millis();
+    let range_tombstones: Vec<&RangeTombstone> = range_tombstones
+        .iter()
+        .chain(levels.iter().flatten().flat_map(|t| t.range_tombstones()))
+        .collect();
+    let decode = |key: &str, raw: &str| -> Result<StoredValue, Error> {
+        let stored = StoredValue::decode(raw)?.without_expired(now);
+        if covered(
+            range_tombstones.iter().copied(),
+            comparator,
+            key,
+            stored.version,
+        ) {
+            return Ok(StoredValue::default());
+        }
+        Ok(stored)
+    };
+    let mut found = Vec::with_capacity(keys.len());
+    for (key, raw) in keys.iter().zip(memtable_entries) {
+        found.push(raw.map(|raw| decode(key, &raw)).transpose()?);
+    }
+    for sstable in levels.iter().flatten() {
+        let pending: Vec<usize> = (0..keys.len())
+            .filter(|&i| found[i].as_ref().is_none_or(StoredValue::is_unresolved))
+            .collect();
+        if pending.is_empty() {
+            break;
+        }
+        let pending_keys: Vec<&str> = pending.iter().map(|&i| keys[i]).collect();
+        for (i, raw) in pending
+            .into_iter()
+            .zip(sstable.read_many(&pending_keys, options)?)
+        {
+            let Some(raw) = raw else {
+                continue;
+            };
+            let older = decode(keys[i], &raw)?;
+            found[i] = Some(match found[i].take() {
+                Some(newer) => newer.stack_on(Some(older)),
+                None => older,
+            });
+        }
+    }
+    keys.iter()
+        .zip(found)
+        .map(|(key, stored)| {
+            let Some(stored) = stored else {
+                return Ok(None);
+            };
+            let stored = fold(key, stored.stack_on(None), merge_operator)?;
+            Ok(Some(stored).filter(|s| !s.is_tombstone()))
+        })
+        .collect()
+}
+
+/// Keys sorted by `comparator` and deduplicated, for [`read_keys`]
+pub(super) fn sorted_keys<'a>(keys: &[&'a str], comparator: &dyn Comparator) -> Vec<&'a str> {
+    let mut sorted = keys.to_vec();
+    sorted.sort_unstable_by(|a, b| comparator.compare(a, b));
+    sorted.dedup();
+    sorted
+}
+
+/// Values of `keys` in order, given the values of the `sorted` keys
+pub(super) fn in_request_order(
+    keys: &[&str],
+    sorted: &[&str],
+    values: &[Option<StoredValue>],
+    comparator: &dyn Comparator,
+) -> Vec<Option<String>> {
+    keys.iter()
+        .map(|key| {
+            let i = sorted
+                .binary_search_by(|k| comparator.compare(k, key))
+                .ok()?;
+            values[i].as_ref().map(|stored| stored.value.clone())
+        })
+        .collect()
+}
+
+/// Complete the MemTable entries of a scan with the SSTables, newest first,
+/// and drop the deleted and expired keys. Stops reading once `limit` live
+/// keys were found.
+pub(super) fn scan_levels(
+    entries: BTreeMap<OrderedKey, String>,
+    range_tombstones: &[RangeTombstone],
+    levels: &[Vec<Arc<SSTable>>],
+    (start, end): (Bound<&str>, Bound<&str>),
+    limit: usize,
+    merge_operator: Option<&dyn MergeOperator>,
+    comparator: &Arc<dyn Comparator>,
+) -> Result<Vec<(String, String)>, Error> {
+    let mut live = Vec::new();
+    for entry in overlay_levels(entries, range_tombstones, levels, start, end, comparator) {
+        if live.len() == limit {
+            break;
+        }
+        let (key, stored) = entry?;
+        let stored = fold(&key, stored, merge_operator)?;
+        if !stored.is_tombstone() {
+            live.push((key, stored.value));
+        }
+    }
+    Ok(live)
+}
+
+/// Complete the MemTable entries with keys in `start..end` with the newest
+/// stored value of each key in the SSTables, tombstones included, in key
+/// order. Expired values and values deleted by the range tombstones of the
+/// MemTable or the SSTables are turned into tombstones, and merge operands
+/// are stacked on the older entries of their key.
+pub(super) fn overlay_levels<'a>(
+    entries: BTreeMap<OrderedKey, String>,
+    range_tombstones: &'a [RangeTombstone],
+    levels: &'a [Vec<Arc<SSTable>>],
+    start: Bound<&'a str>,
+    end: Bound<&'a str>,
+    comparator: &'a Arc<dyn Comparator>,
+) -> LevelsScan<'a> {
+    let options = ReadOptions::default();
+    let memtable = entries
+        .into_iter()
+        .map(|(key, raw)| Ok((key.into_string(), raw)));
+    let mut sources: Vec<Source<'a>> = vec![Box::new(memtable)];
+    for sstable in levels.iter().flatten() {
+        sources.push(Box::new(sstable.scan(start, end, &options)));
+    }
+    LevelsScan {
+        heads: vec![None; sources.len()],
+        sources,
+        started: false,
+        range_tombstones: range_tombstones
+            .iter()
+            .chain(levels.iter().flatten().flat_map(|t| t.range_tombstones()))
+            .collect(),
+        comparator: comparator.as_ref(),
+        now: now_millis(),
+    }
+}
+
+/// Entries of the MemTable or of an SSTable, in key order
+type Source<'a> = Box<dyn Iterator<Item = Result<(String, String), Error>> + 'a>;
+
+/// Iterator of [`overlay_levels`], merging its sources in key order without
+/// reading past the next entry of each of them
+pub(super) struct LevelsScan<'a> {
+    /// The MemTable entries first, then the SSTables newest first
+    sources: Vec<Source<'a>>,
+    /// Next entry of every source, `None` once it is exhausted
+    heads: Vec<Option<(String, String)>>,
+    /// Whether the heads were read
+    started: bool,
+    range_tombstones: Vec<&'a RangeTombstone>,
+    comparator: &'a dyn Comparator,
+    now: u64,
+}
+
+impl LevelsScan<'_> {
+    fn advance(&mut self, source: usize) -> Result<(), Error> {
+        self.heads[source] = self.sources[source].next().transpose()?;
+        Ok(())
+    }
+
+    fn decode(&self, key: &str, raw: &str) -> Result<StoredValue, Error> {
+        let stored = StoredValue::decode(raw)?.without_expired(self.now);
+        if covered(
+            self.range_tombstones.iter().copied(),
+            self.comparator,
+            key,
+            stored.version,
+        ) {
+            return Ok(StoredValue::default());
+        }
+        Ok(stored)
+    }
+
+    fn next_entry(&mut self) -> Result<Option<(String, StoredValue)>, Error> {
+        if !self.started {
+            for source in 0..self.sources.len() {
+                self.advance(source)?;
+            }
+            self.started = true;
+        }
+        let comparator = self.comparator;
+        let Some(key) = self
+            .heads
+            .iter()
+            .flatten()
+            .map(|(key, _)| key)
+            .min_by(|a, b| comparator.compare(a, b))
+            .cloned()
+        else {
+            return Ok(None);
+        };
+        // Newer sources come first and shadow older ones
+        let mut stored: Option<StoredValue> = None;
+        for source in 0..self.heads.len() {
+            let Some((_, raw)) =
+                self.heads[source].take_if(|(k, _)| comparator.compare(k, &key) == Ordering::Equal)
+            else {
+                continue;
+            };
+            self.advance(source)?;
+            stored = Some(match stored {
+                None => self.decode(&key, &raw)?,
+                Some(newer) if newer.is_unresolved() => {
+                    newer.stack_on(Some(self.decode(&key, &raw)?))
+                }
+                Some(newer) => newer,
+            });
+        }
+        // Operands left unresolved fold onto a missing key
+        let stored = stored.unwrap_or_default().stack_on(None);
+        Ok(Some((key, stored)))
+    }
+}
+
+impl Iterator for LevelsScan<'_> {
+    type Item = Result<(String, StoredValue), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
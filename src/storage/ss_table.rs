@@ -1,72 +1,345 @@
 use std::{
-    collections::BTreeMap,
-    fs::File,
-    io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write},
-    path::Path,
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File},
+    io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write},
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
 };
 
-use super::BloomFilter;
+use super::{
+    compaction_filter::{self, CompactionContext},
+    comparator::{in_range, OrderedKey},
+    merge::collapse,
+    range_tombstone::{covered, RangeTombstone},
+    value::{now_millis, StoredValue},
+    BlockCache, BloomFilter, CompactionFilter, Comparator, MergeOperator, Options, ReadOptions,
+};
+use crate::error::Error;
 use log::{info, warn};
 
+/// Size of the block header: payload length (u32 LE) followed by CRC32C (u32 LE)
+const BLOCK_HEADER_SIZE: usize = 8;
+
+/// Magic bytes opening every SSTable data file
+const MAGIC: &[u8; 8] = b"RACHESST";
+
+/// Version of the data file format following the magic bytes
+const FORMAT_VERSION: u32 = 1;
+
+/// Size of the file header: the magic bytes followed by the format version (u32 LE)
+const FILE_HEADER_SIZE: usize = MAGIC.len() + 4;
+
+/// Entries and range tombstones of an SSTable file
+type Contents = (Vec<(String, String)>, Vec<RangeTombstone>);
+
 /// SSTable operations
+///
+/// The data file is a header holding magic bytes and the format version,
+/// followed by a sequence of blocks. Each block is a header holding the
+/// payload length and the CRC32C of the payload, followed by the payload
+/// itself, made of `key:value\n` lines. The range tombstones are kept in a
+/// last meta-block of `:tombstone\n` lines, keys are never empty. Entries
+/// are sorted by the comparator of the LSM Tree.
 pub(super) struct SSTable {
     pub(crate) bloom_filter: BloomFilter,
-    index: BTreeMap<String, u64>,
+    /// Every key along with the offset of the block holding it, in the
+    /// order of the data file
+    index: Vec<(String, u64)>,
+    comparator: Arc<dyn Comparator>,
+    range_tombstones: Vec<RangeTombstone>,
+    /// Greatest version of the values and range tombstones
+    max_version: u64,
+    path: PathBuf,
+    /// Kept open so that the SSTable stays readable once compaction has
+    /// removed or replaced its file
+    file: Mutex<File>,
+    /// Size of the data file in bytes
+    size: u64,
+    /// Identifies the SSTable in the block cache
+    file_id: u64,
+    block_cache: Arc<BlockCache>,
 }
 
 impl SSTable {
-    /// Create a new SSTable with a Bloom filter
-    pub fn new(path: &Path) -> Result<Self, std::io::Error> {
-        info!("Creating new SSTable from path: {:?}", path);
-        let mut bloom_filter = BloomFilter::new(1000);
-        let mut index = BTreeMap::new();
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
-        let mut offset = 0;
-        let mut index_file = BufWriter::new(File::create(path.with_extension("index"))?);
-
-        for line in reader.lines() {
-            let line = line?;
-            if let Some((key, _)) = line.split_once(':') {
-                bloom_filter.insert(key);
-                index.insert(key.to_string(), offset);
-                index_file.write_all(format!("{}:{}\n", key, offset).as_bytes())?;
+    /// Write sorted entries and range tombstones to a new SSTable file and its index
+    pub fn write<I>(
+        path: &Path,
+        entries: I,
+        range_tombstones: &[RangeTombstone],
+        block_size: usize,
+    ) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        info!("Writing SSTable to path: {:?}", path);
+        // Written aside and renamed into place, so that an interrupted write
+        // never leaves a truncated SSTable behind
+        let index_path = path.with_extension("index");
+        let partial_path = partial(path);
+        let partial_index_path = partial(&index_path);
+        let mut writer = BufWriter::new(File::create(&partial_path)?);
+        let mut index_writer = BufWriter::new(File::create(&partial_index_path)?);
+        let mut block = Vec::with_capacity(block_size);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        let mut block_offset = FILE_HEADER_SIZE as u64;
+
+        for (key, value) in entries {
+            block.extend_from_slice(format!("{}:{}\n", key, value).as_bytes());
+            index_writer.write_all(format!("{}:{}\n", key, block_offset).as_bytes())?;
+            if block.len() >= block_size {
+                block_offset += Self::write_block(&mut writer, &block)?;
+                block.clear();
             }
-            offset += line.len() as u64 + 1; // +1 for newline character
         }
-        index_file.flush()?;
-        info!("SSTable created successfully with {} entries", index.len());
+        if !block.is_empty() {
+            Self::write_block(&mut writer, &block)?;
+        }
+        if !range_tombstones.is_empty() {
+            let meta_block: String = range_tombstones
+                .iter()
+                .map(|t| format!(":{}\n", t.encode()))
+                .collect();
+            Self::write_block(&mut writer, meta_block.as_bytes())?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        index_writer.flush()?;
+        index_writer.get_ref().sync_all()?;
+        fs::rename(&partial_index_path, &index_path)?;
+        fs::rename(&partial_path, path)?;
+        if let Some(dir) = path.parent() {
+            sync_dir(dir)?;
+        }
+        Ok(())
+    }
 
-        Ok(SSTable {
-            bloom_filter,
-            index,
-        })
+    /// Write a single block, returning the number of bytes written
+    fn write_block(writer: &mut impl Write, payload: &[u8]) -> Result<u64, Error> {
+        writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+        writer.write_all(&crc32c::crc32c(payload).to_le_bytes())?;
+        writer.write_all(payload)?;
+        Ok((BLOCK_HEADER_SIZE + payload.len()) as u64)
+    }
+
+    /// Check the file header of an SSTable, returning the offset of its
+    /// first block, `None` if the file has no header. Files written before
+    /// the header was introduced start with a `key:value` line instead.
+    fn read_header(reader: &mut impl Read, path: &Path) -> Result<Option<u64>, Error> {
+        let mut header = Vec::with_capacity(FILE_HEADER_SIZE);
+        reader
+            .take(FILE_HEADER_SIZE as u64)
+            .read_to_end(&mut header)?;
+        if header.len() < FILE_HEADER_SIZE || &header[..MAGIC.len()] != MAGIC {
+            return Ok(None);
+        }
+        let version = u32::from_le_bytes(header[MAGIC.len()..].try_into().unwrap());
+        if version != FORMAT_VERSION {
+            return Err(Error::Corruption(format!(
+                "{:?} has SSTable format version {}, expected {}",
+                path, version, FORMAT_VERSION
+            )));
+        }
+        Ok(Some(FILE_HEADER_SIZE as u64))
+    }
+
+    /// Rewrite in the current format an SSTable written before the file
+    /// header was introduced: plain `key:value` lines, without checksums.
+    /// Files holding anything else are reported as corrupted rather than
+    /// rewritten.
+    fn upgrade_legacy(
+        path: &Path,
+        options: &Options,
+        comparator: &dyn Comparator,
+    ) -> Result<(), Error> {
+        let no_header = || {
+            Error::Corruption(format!(
+                "{:?} has neither an SSTable header nor the text format of older versions",
+                path
+            ))
+        };
+        let text = String::from_utf8(fs::read(path)?).map_err(|_| no_header())?;
+        let mut entries = Vec::new();
+        for line in text.lines() {
+            let (key, value) = line.split_once(':').ok_or_else(no_header)?;
+            entries.push((key.to_string(), value.to_string()));
+        }
+        warn!(
+            "Upgrading SSTable {:?} written by an older version of rache, {} entries",
+            path,
+            entries.len()
+        );
+        entries.sort_by(|a, b| comparator.compare(&a.0, &b.0));
+        Self::write(path, entries, &[], options.block_size)
     }
 
-    /// Load an existing SSTable and its Bloom filter
-    pub(crate) fn load(path: &Path) -> Result<Self, std::io::Error> {
+    /// Read the block starting at the reader's position.
+    /// Returns `None` at the end of the file.
+    fn read_block(reader: &mut impl Read, verify_checksum: bool) -> Result<Option<Vec<u8>>, Error> {
+        let mut header = [0u8; BLOCK_HEADER_SIZE];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let expected = u32::from_le_bytes(header[4..8].try_into().unwrap());
+
+        let mut payload = vec![0u8; len];
+        reader
+            .read_exact(&mut payload)
+            .map_err(|e| match e.kind() {
+                ErrorKind::UnexpectedEof => Error::Corruption("truncated block".to_string()),
+                _ => e.into(),
+            })?;
+        if verify_checksum {
+            let actual = crc32c::crc32c(&payload);
+            if actual != expected {
+                return Err(Error::Corruption(format!(
+                    "block checksum mismatch: expected {:#010x}, got {:#010x}",
+                    expected, actual
+                )));
+            }
+        }
+        Ok(Some(payload))
+    }
+
+    /// Split a block payload into its entries
+    fn parse_block(payload: &[u8]) -> Result<Vec<(&str, &str)>, Error> {
+        let text = std::str::from_utf8(payload)
+            .map_err(|e| Error::Corruption(format!("invalid UTF-8 in block: {}", e)))?;
+        Ok(text
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .collect())
+    }
+
+    /// Read every entry and range tombstone of an SSTable file, verifying each block
+    fn read_entries(path: &Path) -> Result<Contents, Error> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut entries = Vec::new();
+        let mut range_tombstones = Vec::new();
+        // Loading upgrades the files of older versions first
+        let mut offset = Self::read_header(&mut reader, path)?
+            .ok_or_else(|| Error::Corruption(format!("{:?} has no SSTable header", path)))?;
+        while let Some(payload) =
+            Self::read_block(&mut reader, true).map_err(|e| annotate(e, path, offset))?
+        {
+            for (key, value) in Self::parse_block(&payload)? {
+                if key.is_empty() {
+                    range_tombstones.push(RangeTombstone::decode(value)?);
+                } else {
+                    entries.push((key.to_string(), value.to_string()));
+                }
+            }
+            offset += (BLOCK_HEADER_SIZE + payload.len()) as u64;
+        }
+        Ok((entries, range_tombstones))
+    }
+
+    /// Load an existing SSTable and its Bloom filter, verifying every block
+    pub(crate) fn load(
+        path: &Path,
+        options: &Options,
+        comparator: Arc<dyn Comparator>,
+        block_cache: Arc<BlockCache>,
+    ) -> Result<Self, Error> {
         info!("Loading SSTable from path: {:?}", path);
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
-        let mut bloom_filter = BloomFilter::new(1000);
-        let mut index = BTreeMap::new();
-        let mut offset = 0;
-
-        for line in reader.lines() {
-            let line = line?;
-            if let Some((key, _)) = line.split_once(':') {
-                bloom_filter.insert(key);
-                index.insert(key.to_string(), offset);
+        let mut reader = BufReader::new(File::open(path)?);
+        let Some(mut offset) = Self::read_header(&mut reader, path)? else {
+            drop(reader);
+            Self::upgrade_legacy(path, options, comparator.as_ref())?;
+            return Self::load(path, options, comparator, block_cache);
+        };
+        let mut index = Vec::new();
+        let mut range_tombstones = Vec::new();
+        let mut max_version = 0;
+
+        while let Some(payload) =
+            Self::read_block(&mut reader, true).map_err(|e| annotate(e, path, offset))?
+        {
+            for (key, value) in Self::parse_block(&payload)? {
+                if key.is_empty() {
+                    let tombstone = RangeTombstone::decode(value)?;
+                    max_version = max_version.max(tombstone.version);
+                    range_tombstones.push(tombstone);
+                } else {
+                    max_version = max_version.max(StoredValue::decode(value)?.version);
+                    index.push((key.to_string(), offset));
+                }
             }
-            offset += line.len() as u64 + 1; // +1 for newline character
+            offset += (BLOCK_HEADER_SIZE + payload.len()) as u64;
+        }
+
+        let mut bloom_filter =
+            BloomFilter::with_bits_per_key(index.len(), options.bloom_bits_per_key);
+        for (key, _) in &index {
+            bloom_filter.insert(key);
         }
         info!("SSTable loaded successfully with {} entries", index.len());
 
         Ok(SSTable {
             bloom_filter,
             index,
+            comparator,
+            range_tombstones,
+            max_version,
+            path: path.to_path_buf(),
+            file: Mutex::new(reader.into_inner()),
+            size: offset,
+            file_id: block_cache.new_file_id(),
+            block_cache,
         })
     }
+
+    /// Path of the SSTable data file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Size of the data file in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Smallest and largest keys of the SSTable, widened to the bounds of
+    /// its range tombstones
+    pub fn key_range(&self) -> Option<(&str, &str)> {
+        let keys = self.index.first().into_iter().chain(self.index.last());
+        let bounds = self
+            .range_tombstones
+            .iter()
+            .flat_map(|t| [&t.start, &t.end])
+            .chain(keys.map(|(key, _)| key))
+            .map(String::as_str);
+        let first = bounds
+            .clone()
+            .min_by(|a, b| self.comparator.compare(a, b))?;
+        let last = bounds.max_by(|a, b| self.comparator.compare(a, b))?;
+        Some((first, last))
+    }
+
+    /// Offset of the block holding `key`
+    fn offset_of(&self, key: &str) -> Option<u64> {
+        let i = self
+            .index
+            .binary_search_by(|(k, _)| self.comparator.compare(k, key))
+            .ok()?;
+        Some(self.index[i].1)
+    }
+
+    /// Greatest version of the values and range tombstones of the SSTable
+    pub fn max_version(&self) -> u64 {
+        self.max_version
+    }
+
+    /// Range tombstones stored in the meta-block
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.range_tombstones
+    }
+
     /// Check if a key might exist using the Bloom filter
     pub fn might_contain(&self, key: &str) -> bool {
         let result = self.bloom_filter.might_contain(key);
@@ -74,60 +347,332 @@ impl SSTable {
         result
     }
 
-    /// Read a key from an SSTable
-    pub fn read(&self, path: &Path, key: &str) -> Result<Option<String>, std::io::Error> {
-        info!("Reading key '{}' from SSTable at path: {:?}", key, path);
-        let file = File::open(path)?;
-        let mut reader = BufReader::new(file);
-
-        if let Some(&offset) = self.index.get(key) {
-            reader.seek(SeekFrom::Start(offset))?;
-            let mut line = String::new();
-            reader.read_line(&mut line)?;
-            if let Some((_, v)) = line.split_once(':') {
-                info!("Key '{}' found with value: {}", key, v);
-                return Ok(Some(v.to_string()));
+    /// Read a key from an SSTable, tombstones are returned as empty values
+    pub fn read(&self, key: &str, options: &ReadOptions) -> Result<Option<String>, Error> {
+        info!(
+            "Reading key '{}' from SSTable at path: {:?}",
+            key, self.path
+        );
+        if let Some(offset) = self.offset_of(key) {
+            let payload = self.block_at(offset, options)?;
+            for (k, v) in Self::parse_block(&payload)? {
+                if k == key {
+                    info!("Key '{}' found with value: {}", key, v);
+                    return Ok(Some(v.to_string()));
+                }
             }
         }
         warn!("Key '{}' not found in SSTable", key);
         Ok(None)
     }
 
-    /// Merge multiple SSTables into one
-    pub fn merge(sstable_paths: &[&Path], output_path: &Path) -> Result<(), std::io::Error> {
-        info!("Merging SSTables into new SSTable at path: {:?}", output_path);
-        let file = File::create(output_path)?;
-        let index_file = File::create(output_path.with_extension("index"))?;
-        let mut writer = BufWriter::new(file);
-        let mut index_writer = BufWriter::new(index_file);
-        let mut entries: BTreeMap<String, String> = BTreeMap::new();
+    /// Read several sorted keys, loading every block holding some of them
+    /// once. Tombstones are returned as empty values.
+    pub fn read_many(
+        &self,
+        keys: &[&str],
+        options: &ReadOptions,
+    ) -> Result<Vec<Option<String>>, Error> {
+        info!(
+            "Reading {} keys from SSTable at path: {:?}",
+            keys.len(),
+            self.path
+        );
+        let mut values = vec![None; keys.len()];
+        let located: Vec<(usize, u64)> = keys
+            .iter()
+            .enumerate()
+            .filter(|(_, key)| self.bloom_filter.might_contain(key))
+            .filter_map(|(i, key)| Some((i, self.offset_of(key)?)))
+            .collect();
+        // Sorted keys held by the same block are adjacent
+        for group in located.chunk_by(|a, b| a.1 == b.1) {
+            let payload = self.block_at(group[0].1, options)?;
+            let entries: HashMap<&str, &str> = Self::parse_block(&payload)?.into_iter().collect();
+            for &(i, _) in group {
+                values[i] = entries.get(keys[i]).map(|value| value.to_string());
+            }
+        }
+        Ok(values)
+    }
+
+    /// Get the block at `offset`, from the block cache if possible
+    fn block_at(&self, offset: u64, options: &ReadOptions) -> Result<Arc<Vec<u8>>, Error> {
+        if let Some(block) = self.block_cache.get(self.file_id, offset) {
+            return Ok(block);
+        }
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        let mut reader = BufReader::new(&mut *file);
+        reader.seek(SeekFrom::Start(offset))?;
+        let payload = Self::read_block(&mut reader, options.verify_checksums)
+            .map_err(|e| annotate(e, &self.path, offset))?
+            .ok_or_else(|| {
+                Error::Corruption(format!(
+                    "{:?}: missing block at offset {}",
+                    self.path, offset
+                ))
+            })?;
+        let payload = Arc::new(payload);
+        // Only verified blocks may be served to readers asking for verification
+        if options.verify_checksums {
+            self.block_cache
+                .insert(self.file_id, offset, Arc::clone(&payload));
+        }
+        Ok(payload)
+    }
+
+    /// Iterate over the entries with keys in `start..end`, tombstones
+    /// included, reading a block only once the entries before it were
+    /// consumed
+    pub fn scan<'a>(
+        &'a self,
+        start: Bound<&'a str>,
+        end: Bound<&'a str>,
+        options: &ReadOptions,
+    ) -> impl Iterator<Item = Result<(String, String), Error>> + 'a {
+        let comparator = self.comparator.as_ref();
+        let options = options.clone();
+        let first = self.index.partition_point(|(k, _)| match start {
+            Bound::Included(start) => comparator.compare(k, start) == Ordering::Less,
+            Bound::Excluded(start) => comparator.compare(k, start) != Ordering::Greater,
+            Bound::Unbounded => false,
+        });
+        let mut last_offset = None;
+        self.index[first..]
+            .iter()
+            .take_while(move |(k, _)| in_range(comparator, (Bound::Unbounded, end), k))
+            .filter_map(move |&(_, offset)| {
+                (last_offset.replace(offset) != Some(offset)).then_some(offset)
+            })
+            .flat_map(move |offset| {
+                let entries = self.block_at(offset, &options).and_then(|payload| {
+                    Ok(Self::parse_block(&payload)?
+                        .into_iter()
+                        .filter(|(k, _)| in_range(comparator, (start, end), k))
+                        .map(|(k, v)| Ok((k.to_string(), v.to_string())))
+                        .collect())
+                });
+                entries.unwrap_or_else(|e| vec![Err(e)])
+            })
+    }
 
-        // Read all SSTables
+    /// Merge multiple SSTables into one.
+    /// Tables are given from oldest to newest: newer values win, and the
+    /// output is sorted by `comparator`.
+    /// Expired values and values deleted by range tombstones become
+    /// tombstones, which are dropped along with the range tombstones when no
+    /// older SSTable may hold the deleted keys. Merge operands are collapsed
+    /// by `merge_operator`, folded onto their base once it is known. The
+    /// values left are then kept, removed or rewritten by `compaction_filter`.
+    pub fn merge(
+        sstable_paths: &[&Path],
+        output_path: &Path,
+        block_size: usize,
+        drop_tombstones: bool,
+        merge_operator: Option<&dyn MergeOperator>,
+        compaction_filter: Option<(&dyn CompactionFilter, &CompactionContext)>,
+        comparator: &Arc<dyn Comparator>,
+    ) -> Result<(), Error> {
+        info!(
+            "Merging SSTables into new SSTable at path: {:?}",
+            output_path
+        );
+        let now = now_millis();
+        let mut inputs = Vec::new();
+        let mut range_tombstones = Vec::new();
         for path in sstable_paths {
             info!("Reading SSTable from path: {:?}", path);
-            let file = File::open(path)?;
-            let reader = BufReader::new(file);
-            for line in reader.lines() {
-                let line = line?;
-                if let Some((key, value)) = line.split_once(':') {
-                    entries.insert(key.to_string(), value.to_string());
-                }
+            let (entries, tombstones) = Self::read_entries(path)?;
+            inputs.push(entries);
+            range_tombstones.extend(tombstones);
+        }
+
+        let mut entries: BTreeMap<OrderedKey, StoredValue> = BTreeMap::new();
+        // Keys whose newest value is deleted by a range tombstone
+        let mut range_deleted = HashSet::new();
+        // Stack merge operands on older entries
+        for (key, raw) in inputs.into_iter().flatten() {
+            let mut value = StoredValue::decode(&raw)?.without_expired(now);
+            if covered(&range_tombstones, comparator.as_ref(), &key, value.version) {
+                value = StoredValue::default();
+                range_deleted.insert(key.clone());
+            } else {
+                range_deleted.remove(&key);
             }
+            let key = OrderedKey::new(key, comparator);
+            let older = entries.remove(&key);
+            let value = match older {
+                Some(older) => value.stack_on(Some(older)),
+                None => value,
+            };
+            entries.insert(key, value);
+        }
+
+        for (key, value) in entries.iter_mut() {
+            // Without older SSTables, operands fold onto a missing key
+            if drop_tombstones && value.is_unresolved() {
+                *value = std::mem::take(value).stack_on(None);
+            }
+            if let Some(operator) = merge_operator {
+                *value = collapse(key.as_str(), std::mem::take(value), operator);
+            }
+            if let Some((filter, context)) = compaction_filter {
+                let key = key.as_str();
+                *value = compaction_filter::apply(filter, context, key, std::mem::take(value));
+            }
+        }
+        if drop_tombstones {
+            entries.retain(|_, value| !value.is_tombstone());
+            range_tombstones.clear();
+        } else {
+            // The range tombstones written along delete the older values
+            entries.retain(|key, _| !range_deleted.contains(key.as_str()));
         }
 
         // Write merged entries to the new SSTable
-        let mut offset = 0;
-        for (key, value) in entries {
-            let ss_table_line = format!("{}:{}\n", key, value);
-            let index_line = format!("{}:{}\n", key, offset);
-            writer.write_all(ss_table_line.as_bytes())?;
-            index_writer.write_all(index_line.as_bytes())?;
+        let entries = entries
+            .into_iter()
+            .map(|(key, value)| (key.into_string(), value.encode()));
+        Self::write(output_path, entries, &range_tombstones, block_size)
+    }
+}
+
+/// Suffix of SSTable files being written
+pub(super) const PARTIAL_SUFFIX: &str = ".partial";
+
+fn partial(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(PARTIAL_SUFFIX);
+    PathBuf::from(name)
+}
 
-            offset += ss_table_line.len() as u64;
+/// Make the creation, renaming and removal of files in a directory durable
+pub(super) fn sync_dir(dir: &Path) -> Result<(), Error> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Attach the file and block offset to a corruption error
+fn annotate(e: Error, path: &Path, offset: u64) -> Error {
+    match e {
+        Error::Corruption(msg) => {
+            Error::Corruption(format!("{:?} at offset {}: {}", path, offset, msg))
         }
-        writer.flush()?;
-        index_writer.flush()?;
+        e => e,
+    }
+}
 
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{test_util::TempDir, Bytewise};
+
+    fn load(path: &Path) -> Result<SSTable, Error> {
+        let block_cache = Arc::new(BlockCache::new(0));
+        SSTable::load(path, &Options::default(), Arc::new(Bytewise), block_cache)
+    }
+
+    #[test]
+    fn corrupted_block_is_detected_on_read() {
+        let dir = TempDir::new("sstable-corruption");
+        fs::create_dir_all(dir.path()).unwrap();
+        let path = dir.path().join("0.sst");
+        let entries = (0..100).map(|i| (format!("key{:03}", i), format!("value{}", i)));
+        SSTable::write(&path, entries, &[], 256).unwrap();
+        let sstable = load(&path).unwrap();
+        let options = ReadOptions::default();
+        assert_eq!(
+            sstable.read("key050", &options).unwrap().as_deref(),
+            Some("value50")
+        );
+
+        // Flip a byte of the payload of the block holding key050, in place
+        // so that the open file sees it
+        let offset = sstable.offset_of("key050").unwrap() + BLOCK_HEADER_SIZE as u64;
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let mut byte = [0u8];
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.read_exact(&mut byte).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&[byte[0] ^ 0xff]).unwrap();
+        file.sync_all().unwrap();
+
+        let result = sstable.read("key050", &options);
+        assert!(matches!(result, Err(Error::Corruption(_))), "{:?}", result);
+        // Untouched blocks stay readable
+        assert_eq!(
+            sstable.read("key000", &options).unwrap().as_deref(),
+            Some("value0")
+        );
+        // And the SSTable is no longer loadable
+        assert!(matches!(load(&path), Err(Error::Corruption(_))));
+    }
+
+    #[test]
+    fn legacy_sstables_are_upgraded() {
+        let dir = TempDir::new("sstable-legacy");
+        fs::create_dir_all(dir.path()).unwrap();
+        let path = dir.path().join("baseline.sst");
+        fs::write(&path, "apple:red\nbanana:yellow:ripe\ncherry:\n").unwrap();
+
+        let sstable = load(&path).unwrap();
+        let options = ReadOptions::default();
+        assert_eq!(
+            sstable.read("apple", &options).unwrap().as_deref(),
+            Some("red")
+        );
+        assert_eq!(
+            sstable.read("banana", &options).unwrap().as_deref(),
+            Some("yellow:ripe")
+        );
+        assert_eq!(
+            sstable.read("cherry", &options).unwrap().as_deref(),
+            Some("")
+        );
+        assert_eq!(sstable.read("durian", &options).unwrap(), None);
+        // Rewritten in the current format, index included
+        assert!(fs::read(&path).unwrap().starts_with(MAGIC));
+        assert!(path.with_extension("index").exists());
+        assert_eq!(SSTable::read_entries(&path).unwrap().0.len(), 3);
+    }
+
+    #[test]
+    fn databases_of_older_versions_open() {
+        let dir = TempDir::new("sstable-legacy-db");
+        let sstable_dir = dir.path().join("sstables");
+        fs::create_dir_all(&sstable_dir).unwrap();
+        fs::write(sstable_dir.join("sstable_0_0.txt"), "a:1\nb:2\n").unwrap();
+
+        let db = crate::Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.get("a").unwrap().as_deref(), Some("1"));
+        db.put("c", "3").unwrap();
+        db.flush().unwrap();
+        db.compact_range(Bound::Unbounded, Bound::Unbounded)
+            .unwrap();
+        assert_eq!(db.get("b").unwrap().as_deref(), Some("2"));
+        assert_eq!(db.get("c").unwrap().as_deref(), Some("3"));
+    }
+
+    #[test]
+    fn unknown_sstable_formats_are_rejected() {
+        let dir = TempDir::new("sstable-header");
+        fs::create_dir_all(dir.path()).unwrap();
+        let path = dir.path().join("unknown.sst");
+        let garbage = [b"RACHESSX".as_slice(), &[1, 0, 0, 0, 0xff, 0xfe]].concat();
+        fs::write(&path, &garbage).unwrap();
+        assert!(matches!(load(&path), Err(Error::Corruption(_))));
+        // Left untouched
+        assert_eq!(fs::read(&path).unwrap(), garbage);
+        fs::write(&path, "no separator\n").unwrap();
+        assert!(matches!(load(&path), Err(Error::Corruption(_))));
+
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        fs::write(&path, header).unwrap();
+        assert!(matches!(load(&path), Err(Error::Corruption(_))));
     }
 }
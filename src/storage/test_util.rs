@@ -0,0 +1,108 @@
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use super::{Comparator, Options};
+use crate::Db;
+
+/// A fresh directory under the system temporary directory, removed on drop
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "rache-{}-{}-{}",
+            name,
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A database opened in a fresh [`TempDir`], which tests write to, then
+/// reopen to check what was recovered. Dereferences to the open [`Db`].
+pub(crate) struct TestDb {
+    db: Db,
+    options: Options,
+    comparator: Option<Arc<dyn Comparator>>,
+    /// Declared last so that it is removed once the database is dropped
+    dir: TempDir,
+}
+
+impl TestDb {
+    /// Open a database with the default options
+    pub fn open(name: &str) -> Self {
+        Self::with_options(name, Options::default())
+    }
+
+    pub fn with_options(name: &str, options: Options) -> Self {
+        Self::new(name, options, None)
+    }
+
+    /// Open a database with the default options, its keys ordered by `comparator`
+    pub fn with_comparator(name: &str, comparator: Arc<dyn Comparator>) -> Self {
+        Self::new(name, Options::default(), Some(comparator))
+    }
+
+    fn new(name: &str, options: Options, comparator: Option<Arc<dyn Comparator>>) -> Self {
+        let dir = TempDir::new(name);
+        let db = Self::open_in(&dir, &options, &comparator);
+        TestDb {
+            db,
+            options,
+            comparator,
+            dir,
+        }
+    }
+
+    fn open_in(dir: &TempDir, options: &Options, comparator: &Option<Arc<dyn Comparator>>) -> Db {
+        let options = options.clone();
+        match comparator {
+            Some(comparator) => {
+                Db::open_with_comparator(dir.path(), options, Arc::clone(comparator))
+            }
+            None => Db::open(dir.path(), options),
+        }
+        .unwrap()
+    }
+
+    /// Directory of the database
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Close the database, leaving its MemTable to be recovered from the
+    /// Wal, and open it again with the same options and comparator. Handles
+    /// on the previous instance, column families included, are closed.
+    pub fn reopen(&mut self) {
+        self.db.close().unwrap();
+        self.db = Self::open_in(&self.dir, &self.options, &self.comparator);
+    }
+}
+
+impl Deref for TestDb {
+    type Target = Db;
+
+    fn deref(&self) -> &Db {
+        &self.db
+    }
+}
@@ -0,0 +1,363 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use super::{
+    column_family::qualified_key, lock_manager::LockManager, range_tombstone::RangeTombstone,
+    Comparator, LSMTree, LockMode, Snapshot, StoredValue,
+};
+use crate::error::Error;
+
+/// A transaction on a column family, begun with [`LSMTree::begin`] or
+/// [`LSMTree::begin_pessimistic`].
+///
+/// Reads see a snapshot taken when the transaction began, along with the
+/// transaction's own writes. Writes are buffered until the transaction is
+/// committed with [`LSMTree::commit`], which fails with
+/// [`Error::Conflict`] if a key read by the transaction was written since
+/// it was read, deletions included. Dropping the transaction rolls it back.
+///
+/// Keys can be locked by [`LSMTree::get_for_update`], which reads their
+/// latest value instead of the snapshot one. A pessimistic transaction also
+/// locks every key it writes, as it writes it. Locks are held until the
+/// transaction is committed or rolled back.
+pub struct Transaction {
+    /// Column family the transaction reads and writes
+    cf: String,
+    snapshot: Snapshot,
+    /// Version of the latest write when the snapshot was taken
+    version: u64,
+    /// Every key read, the value it was read with, `None` if it was absent,
+    /// and the version of the latest write when it was read
+    reads: BTreeMap<String, (Option<StoredValue>, u64)>,
+    /// Begin versions of the open transactions, this one included
+    open: OpenTransactions,
+    /// Buffered writes, tombstones for deletions
+    writes: BTreeMap<String, StoredValue>,
+    lock_manager: Arc<LockManager>,
+    /// Id of the transaction in the lock manager
+    owner: u64,
+    /// Keys locked by the transaction, qualified by their column family
+    locked: BTreeMap<String, LockMode>,
+    /// Whether writes lock their key right away
+    pessimistic: bool,
+    lock_timeout: Duration,
+}
+
+impl Transaction {
+    pub(super) fn new(
+        cf: &str,
+        snapshot: Snapshot,
+        version: u64,
+        open: OpenTransactions,
+        lock_manager: Arc<LockManager>,
+        pessimistic: bool,
+        lock_timeout: Duration,
+    ) -> Self {
+        *open
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(version)
+            .or_default() += 1;
+        Transaction {
+            cf: cf.to_string(),
+            snapshot,
+            version,
+            reads: BTreeMap::new(),
+            open,
+            writes: BTreeMap::new(),
+            owner: lock_manager.new_owner(),
+            lock_manager,
+            locked: BTreeMap::new(),
+            pessimistic,
+            lock_timeout,
+        }
+    }
+
+    /// Read the value of a key, as written by the transaction or else as
+    /// of its snapshot
+    pub fn get(&mut self, key: &str) -> Result<Option<String>, Error> {
+        if let Some(written) = self.written(key) {
+            return Ok(written);
+        }
+        // Later reads of a key return the value of its first read
+        if let Some((read, _)) = self.reads.get(key) {
+            return Ok(read.as_ref().map(|stored| stored.value.clone()));
+        }
+        let stored = self.snapshot.get_stored(key)?;
+        self.record_read(key, stored.clone(), self.version);
+        Ok(stored.map(|stored| stored.value))
+    }
+
+    /// Set the value of a key on commit. An empty value deletes the key.
+    /// A pessimistic transaction first waits for an exclusive lock on the key.
+    pub fn put(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        LSMTree::validate(key, value)?;
+        if self.pessimistic {
+            self.lock(key, LockMode::Exclusive)?;
+        }
+        let value = StoredValue::new(value.to_string());
+        self.writes.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    /// Delete a key on commit
+    pub fn delete(&mut self, key: &str) -> Result<(), Error> {
+        self.put(key, "")
+    }
+
+    /// Whether the transaction read or wrote nothing
+    pub fn is_empty(&self) -> bool {
+        self.reads.is_empty() && self.writes.is_empty()
+    }
+
+    /// Column family the transaction reads and writes
+    pub fn column_family(&self) -> &str {
+        &self.cf
+    }
+
+    /// Whether the transaction locks the keys it writes
+    pub fn is_pessimistic(&self) -> bool {
+        self.pessimistic
+    }
+
+    /// Lock a key for the rest of the transaction. Fails with
+    /// [`Error::Busy`] on deadlock and with [`Error::Timeout`] once the
+    /// lock timeout elapses.
+    pub(crate) fn lock(&mut self, key: &str, mode: LockMode) -> Result<(), Error> {
+        let key = qualified_key(&self.cf, key);
+        if self.locked.get(&key).is_some_and(|held| *held >= mode) {
+            return Ok(());
+        }
+        self.lock_manager
+            .lock(self.owner, &key, mode, self.lock_timeout)?;
+        self.locked.insert(key, mode);
+        Ok(())
+    }
+
+    /// Lock every key written, so that the commit does not overwrite a key
+    /// locked by another transaction
+    pub(crate) fn lock_writes(&mut self) -> Result<(), Error> {
+        let keys: Vec<String> = self.writes.keys().cloned().collect();
+        for key in keys {
+            self.lock(&key, LockMode::Exclusive)?;
+        }
+        Ok(())
+    }
+
+    /// The value written by the transaction for a key, if any
+    pub(super) fn written(&self, key: &str) -> Option<Option<String>> {
+        let written = self.writes.get(key)?;
+        Some((!written.is_tombstone()).then(|| written.value.clone()))
+    }
+
+    /// Record the value a key was read with, as of the write of `version`
+    pub(super) fn record_read(&mut self, key: &str, stored: Option<StoredValue>, version: u64) {
+        self.reads.insert(key.to_string(), (stored, version));
+    }
+
+    /// Keys read by the transaction, the values they were read with and the
+    /// version of the latest write when they were read
+    pub(super) fn reads(&self) -> &BTreeMap<String, (Option<StoredValue>, u64)> {
+        &self.reads
+    }
+
+    /// Take the buffered writes, in key order, along with their column family
+    pub(super) fn take_writes(&mut self) -> Vec<(String, String, StoredValue)> {
+        std::mem::take(&mut self.writes)
+            .into_iter()
+            .map(|(key, value)| (self.cf.clone(), key, value))
+            .collect()
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if !self.locked.is_empty() {
+            self.lock_manager
+                .unlock(self.owner, self.locked.keys().map(String::as_str));
+        }
+        let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = open.get_mut(&self.version) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.version);
+            }
+        }
+    }
+}
+
+/// Number of open transactions by the version they began at
+pub(super) type OpenTransactions = Arc<Mutex<BTreeMap<u64, usize>>>;
+
+/// Versions of the writes made while transactions are open, so that a
+/// commit tells whether a key it read was written since. Deletions store no
+/// version along with their key, so the stored values alone cannot tell.
+#[derive(Default)]
+pub(super) struct WriteLog {
+    open: OpenTransactions,
+    /// Version of the latest write of every key, qualified by its column
+    /// family
+    keys: HashMap<String, u64>,
+    /// Range deletions, along with their column family
+    ranges: Vec<(String, RangeTombstone)>,
+}
+
+impl WriteLog {
+    /// Begin versions of the open transactions, shared with the transactions
+    pub fn open_transactions(&self) -> OpenTransactions {
+        Arc::clone(&self.open)
+    }
+
+    /// Oldest begin version of the open transactions, `None` if there are none
+    fn oldest(&self) -> Option<u64> {
+        let open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        open.keys().next().copied()
+    }
+
+    /// Record the write of a key by `version`
+    pub fn record(&mut self, cf: &str, key: &str, version: u64) {
+        if self.oldest().is_some() {
+            self.keys.insert(qualified_key(cf, key), version);
+        } else {
+            self.prune();
+        }
+    }
+
+    /// Record a range deletion
+    pub fn record_range(&mut self, cf: &str, tombstone: &RangeTombstone) {
+        if self.oldest().is_some() {
+            self.ranges.push((cf.to_string(), tombstone.clone()));
+        } else {
+            self.prune();
+        }
+    }
+
+    /// Whether a key was written by a version after `version`
+    pub fn written_since(
+        &self,
+        comparator: &dyn Comparator,
+        cf: &str,
+        key: &str,
+        version: u64,
+    ) -> bool {
+        self.keys
+            .get(&qualified_key(cf, key))
+            .is_some_and(|written| *written > version)
+            || self
+                .ranges
+                .iter()
+                .any(|(range_cf, range)| range_cf == cf && range.covers(comparator, key, version))
+    }
+
+    /// Forget the writes no open transaction may have read before
+    pub fn prune(&mut self) {
+        match self.oldest() {
+            Some(oldest) => {
+                self.keys.retain(|_, version| *version > oldest);
+                self.ranges.retain(|(_, range)| range.version > oldest);
+            }
+            None => {
+                self.keys.clear();
+                self.ranges.clear();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        storage::{test_util::TestDb, Options},
+        Error,
+    };
+
+    fn open(name: &str) -> TestDb {
+        let options = Options::builder().lock_timeout_ms(50).build().unwrap();
+        TestDb::with_options(name, options)
+    }
+
+    #[test]
+    fn read_conflict_fails_commit() {
+        let db = open("transaction-conflict");
+        db.put("balance", "10").unwrap();
+
+        let mut transaction = db.begin().unwrap();
+        assert_eq!(transaction.get("balance").unwrap().as_deref(), Some("10"));
+        assert_eq!(transaction.get("missing").unwrap(), None);
+        transaction.put("balance", "5").unwrap();
+        db.put("balance", "20").unwrap();
+        assert!(matches!(db.commit(transaction), Err(Error::Conflict(_))));
+        assert_eq!(db.get("balance").unwrap().as_deref(), Some("20"));
+
+        // Creating a key read as missing conflicts too
+        let mut transaction = db.begin().unwrap();
+        assert_eq!(transaction.get("missing").unwrap(), None);
+        transaction.put("other", "1").unwrap();
+        db.put("missing", "now").unwrap();
+        assert!(matches!(db.commit(transaction), Err(Error::Conflict(_))));
+        assert_eq!(db.get("other").unwrap(), None);
+
+        // Without concurrent writes the commit goes through
+        let mut transaction = db.begin().unwrap();
+        transaction.get("balance").unwrap();
+        transaction.put("balance", "15").unwrap();
+        db.commit(transaction).unwrap();
+        assert_eq!(db.get("balance").unwrap().as_deref(), Some("15"));
+    }
+
+    #[test]
+    fn deletions_since_a_read_fail_commit() {
+        let db = open("transaction-deletions");
+
+        // Deleted again before the commit, the key reads as missing as it did
+        let mut transaction = db.begin().unwrap();
+        assert_eq!(transaction.get("key").unwrap(), None);
+        transaction.put("other", "1").unwrap();
+        db.put("key", "value").unwrap();
+        db.delete("key").unwrap();
+        assert!(matches!(db.commit(transaction), Err(Error::Conflict(_))));
+        assert_eq!(db.get("other").unwrap(), None);
+
+        // And so does a range deletion
+        let mut transaction = db.begin().unwrap();
+        assert_eq!(transaction.get("key").unwrap(), None);
+        transaction.put("other", "1").unwrap();
+        db.put("key", "value").unwrap();
+        db.delete_range("a", "z").unwrap();
+        assert!(matches!(db.commit(transaction), Err(Error::Conflict(_))));
+
+        // Writes before the transaction began do not conflict
+        db.put("key", "value").unwrap();
+        db.delete("key").unwrap();
+        let mut transaction = db.begin().unwrap();
+        assert_eq!(transaction.get("key").unwrap(), None);
+        transaction.put("other", "1").unwrap();
+        db.put("unrelated", "1").unwrap();
+        db.commit(transaction).unwrap();
+        assert_eq!(db.get("other").unwrap().as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn dropped_transaction_releases_its_locks() {
+        let db = open("transaction-drop");
+        let mut first = db.begin_pessimistic().unwrap();
+        first.put("key", "first").unwrap();
+
+        let mut second = db.begin_pessimistic().unwrap();
+        assert!(matches!(
+            second.put("key", "second"),
+            Err(Error::Timeout(_))
+        ));
+        assert!(matches!(db.put("key", "db"), Err(Error::Timeout(_))));
+        assert!(matches!(db.delete_range("a", "z"), Err(Error::Timeout(_))));
+
+        drop(first);
+        second.put("key", "second").unwrap();
+        db.commit(second).unwrap();
+        db.put("other", "db").unwrap();
+        assert_eq!(db.get("key").unwrap().as_deref(), Some("second"));
+    }
+}
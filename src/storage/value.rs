@@ -0,0 +1,289 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::Error;
+
+/// Opens and closes the header of a stored value
+const HEADER_MARKER: char = '\0';
+
+/// Tag of the header field holding the expiry
+const EXPIRES_AT_FIELD: char = 'x';
+
+/// Tag of the header field holding the version
+const VERSION_FIELD: char = 'v';
+
+/// Tag of the header field holding the number of merge operands
+const OPERANDS_FIELD: char = 'm';
+
+/// Tag of the header field marking a counter, which has no number
+const COUNTER_FIELD: char = 'i';
+
+/// Tag of the header field holding the flags of the client
+const FLAGS_FIELD: char = 'f';
+
+/// A value and its metadata, as stored in the MemTable, the Wal and the SSTables.
+///
+/// Values without metadata are stored as is. Other values, and values
+/// starting with the marker, are prefixed by a `\0fields\0` header made of
+/// space separated fields, e.g. `\0v42 x1700000000000\0value` for a value
+/// written by version 42 and expiring at that Unix time in milliseconds. An
+/// empty stored value is a tombstone.
+///
+/// Merge operands not folded yet are stored as `len:item` strings, after
+/// the base value they fold onto if it is known, e.g. `\0m2\01:a2:bc` for
+/// the operands `a` then `bc` of a key whose base is in older entries.
+/// Counters are marked by an `i` field, e.g. `\0i\0-12`, and the flags of
+/// memcached items are held by an `f` field.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub(crate) struct StoredValue {
+    pub value: String,
+    /// Unix time in milliseconds after which the value is gone
+    pub expires_at: Option<u64>,
+    /// Version of the write that stored the value, 0 for values written
+    /// before versions were recorded
+    pub version: u64,
+    /// Merge operands not folded yet, oldest first
+    pub operands: Vec<String>,
+    /// Whether the operands fold onto `value`, empty for a missing key,
+    /// rather than onto the older entries of the key
+    pub has_base: bool,
+    /// Whether the value is a counter written by an increment, an `i64`
+    /// in canonical decimal form
+    pub counter: bool,
+    /// Opaque flags of the client that wrote the value, as the flags of
+    /// memcached items
+    pub flags: u32,
+}
+
+impl StoredValue {
+    pub fn new(value: String) -> Self {
+        Self::with_expiry(value, None)
+    }
+
+    pub fn with_expiry(value: String, expires_at: Option<u64>) -> Self {
+        StoredValue {
+            value,
+            expires_at,
+            ..Default::default()
+        }
+    }
+
+    /// A merge operand folding onto the older entries of its key
+    pub fn operand(operand: String) -> Self {
+        StoredValue {
+            operands: vec![operand],
+            ..Default::default()
+        }
+    }
+
+    /// Whether the value marks its key as deleted
+    pub fn is_tombstone(&self) -> bool {
+        self.value.is_empty() && self.operands.is_empty()
+    }
+
+    /// Whether the value holds merge operands whose base is in older entries
+    pub fn is_unresolved(&self) -> bool {
+        !self.operands.is_empty() && !self.has_base
+    }
+
+    /// The `i64` held by the value: a counter, or a value written as the
+    /// canonical decimal form of an `i64`. `None` for other values.
+    pub fn as_counter(&self) -> Option<i64> {
+        let number = self.value.parse::<i64>().ok()?;
+        (self.counter || number.to_string() == self.value).then_some(number)
+    }
+
+    /// Whether the value expired at `now`, in Unix milliseconds
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+
+    /// Turn a value expired at `now` into a tombstone. Operands folding onto
+    /// an expired value fold onto a missing key instead.
+    pub fn without_expired(mut self, now: u64) -> Self {
+        if self.is_expired(now) {
+            self.value.clear();
+            self.expires_at = None;
+        }
+        self
+    }
+
+    /// Put the operands of this value on top of the `older` entry of the
+    /// key, `None` if the key has no older entry. Values without operands,
+    /// or whose base is known, shadow the older entry.
+    pub fn stack_on(mut self, older: Option<StoredValue>) -> Self {
+        if !self.is_unresolved() {
+            return self;
+        }
+        match older {
+            Some(mut older) if !older.operands.is_empty() => {
+                older.operands.append(&mut self.operands);
+                older.version = self.version;
+                older
+            }
+            older => {
+                let older = older.unwrap_or_default();
+                self.value = older.value;
+                self.expires_at = older.expires_at;
+                self.has_base = true;
+                self
+            }
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        if self.is_tombstone() {
+            return String::new();
+        }
+        let mut fields = Vec::new();
+        if self.version != 0 {
+            fields.push(format!("{}{}", VERSION_FIELD, self.version));
+        }
+        if let Some(at) = self.expires_at {
+            fields.push(format!("{}{}", EXPIRES_AT_FIELD, at));
+        }
+        if self.counter {
+            fields.push(COUNTER_FIELD.to_string());
+        }
+        if self.flags != 0 {
+            fields.push(format!("{}{}", FLAGS_FIELD, self.flags));
+        }
+        if self.operands.is_empty() {
+            if fields.is_empty() && !self.value.starts_with(HEADER_MARKER) {
+                return self.value.clone();
+            }
+            return format!("{0}{1}{0}{2}", HEADER_MARKER, fields.join(" "), self.value);
+        }
+        fields.push(format!("{}{}", OPERANDS_FIELD, self.operands.len()));
+        let mut encoded = format!("{0}{1}{0}", HEADER_MARKER, fields.join(" "));
+        let base = self.has_base.then_some(&self.value);
+        for item in base.into_iter().chain(&self.operands) {
+            encoded.push_str(&format!("{}:{}", item.len(), item));
+        }
+        encoded
+    }
+
+    pub fn decode(raw: &str) -> Result<Self, Error> {
+        let Some(rest) = raw.strip_prefix(HEADER_MARKER) else {
+            return Ok(StoredValue::new(raw.to_string()));
+        };
+        let (header, value) = rest
+            .split_once(HEADER_MARKER)
+            .ok_or_else(|| Error::Corruption("unterminated value header".to_string()))?;
+        let mut stored = StoredValue::new(value.to_string());
+        let mut operands = 0;
+        for field in header.split(' ').filter(|f| !f.is_empty()) {
+            let mut chars = field.chars();
+            let tag = chars.next();
+            let number = || {
+                chars.as_str().parse::<u64>().map_err(|_| {
+                    Error::Corruption(format!("invalid field {:?} in value header", field))
+                })
+            };
+            match tag {
+                Some(EXPIRES_AT_FIELD) => stored.expires_at = Some(number()?),
+                Some(VERSION_FIELD) => stored.version = number()?,
+                Some(OPERANDS_FIELD) => operands = number()? as usize,
+                Some(COUNTER_FIELD) if chars.as_str().is_empty() => stored.counter = true,
+                Some(FLAGS_FIELD) => stored.flags = number()? as u32,
+                _ => {
+                    return Err(Error::Corruption(format!(
+                        "unknown field {:?} in value header",
+                        field
+                    )))
+                }
+            }
+        }
+        if operands > 0 {
+            let mut items = decode_items(value)?;
+            if items.len() == operands + 1 {
+                stored.value = items.remove(0);
+                stored.has_base = true;
+            } else if items.len() == operands {
+                stored.value.clear();
+            } else {
+                return Err(Error::Corruption(format!(
+                    "expected {} merge operands, found {} items",
+                    operands,
+                    items.len()
+                )));
+            }
+            stored.operands = items;
+        }
+        Ok(stored)
+    }
+}
+
+/// Split concatenated `len:item` strings
+fn decode_items(mut encoded: &str) -> Result<Vec<String>, Error> {
+    let mut items = Vec::new();
+    while !encoded.is_empty() {
+        let item = encoded.split_once(':').and_then(|(len, rest)| {
+            let len = len.parse::<usize>().ok()?;
+            Some((rest.get(..len)?, rest.get(len..)?))
+        });
+        let Some((item, rest)) = item else {
+            return Err(Error::Corruption("invalid merge operand".to_string()));
+        };
+        items.push(item.to_string());
+        encoded = rest;
+    }
+    Ok(items)
+}
+
+/// Current Unix time in milliseconds
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_round_trip() {
+        let counter = StoredValue {
+            counter: true,
+            version: 3,
+            ..StoredValue::new("-12".to_string())
+        };
+        let encoded = counter.encode();
+        assert_eq!(encoded, "\0v3 i\0-12");
+        assert_eq!(StoredValue::decode(&encoded).unwrap(), counter);
+        assert_eq!(counter.as_counter(), Some(-12));
+    }
+
+    #[test]
+    fn only_canonical_text_is_a_counter() {
+        let counter = |value: &str| StoredValue::new(value.to_string()).as_counter();
+        assert_eq!(counter("42"), Some(42));
+        assert_eq!(counter("-7"), Some(-7));
+        for value in [
+            "+1",
+            "007",
+            " 1",
+            "1 ",
+            "-0",
+            "1.0",
+            "x",
+            "99999999999999999999",
+        ] {
+            assert_eq!(counter(value), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn expired_values_become_tombstones() {
+        let value = StoredValue::with_expiry("v".to_string(), Some(1_000));
+        let encoded = value.encode();
+        assert_eq!(encoded, "\0x1000\0v");
+        assert_eq!(StoredValue::decode(&encoded).unwrap(), value);
+
+        assert!(!value.is_expired(999));
+        assert_eq!(value.clone().without_expired(999), value);
+        assert!(value.is_expired(1_000));
+        assert!(value.without_expired(1_000).is_tombstone());
+        assert!(!StoredValue::new("v".to_string()).is_expired(u64::MAX));
+    }
+}
@@ -1,37 +1,193 @@
 use std::{
     fs::{File, OpenOptions},
-    io::{BufWriter, Write},
+    io::{BufRead, BufReader, BufWriter, Write},
     path::Path,
     sync::Mutex,
 };
 
+use log::warn;
+
+use crate::common_enums::SyncPolicy;
+use crate::error::Error;
+
+/// Logged, with an empty key, before the entries of a batch
+const BATCH_BEGIN: &str = "#begin";
+
+/// Logged, with an empty key, once every entry of a batch is
+const BATCH_COMMIT: &str = "#commit";
+
 /// Write-Ahead Log (Wal)
 pub struct Wal {
     file: Mutex<BufWriter<File>>,
+    sync_policy: SyncPolicy,
 }
 
 impl Wal {
     /// Create a new Wal
-    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, std::io::Error> {
+    pub fn new<P: AsRef<Path>>(path: P, sync_policy: SyncPolicy) -> Result<Self, Error> {
         let file = OpenOptions::new().create(true).append(true).open(&path)?;
         Ok(Wal {
             file: Mutex::new(BufWriter::new(file)),
+            sync_policy,
         })
     }
 
     /// Append a log entry
-    pub fn append(&self, key: &str, value: &str) -> Result<(), std::io::Error> {
-        let mut file = self.file.lock().unwrap();
+    pub fn append(&self, key: &str, value: &str) -> Result<(), Error> {
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
         writeln!(file, "{}:{}", key, value)?;
         file.flush()?;
+        if self.sync_policy == SyncPolicy::Always {
+            file.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Append the entries of a batch, which is replayed only if every entry
+    /// of it made it to the log
+    pub fn append_batch(&self, entries: &[(String, String)]) -> Result<(), Error> {
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        writeln!(file, ":{}", BATCH_BEGIN)?;
+        for (key, value) in entries {
+            writeln!(file, "{}:{}", key, value)?;
+        }
+        writeln!(file, ":{}", BATCH_COMMIT)?;
+        file.flush()?;
+        if self.sync_policy == SyncPolicy::Always {
+            file.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Call `apply` with the key and value of every entry of the log at
+    /// `path`, in order. The entries of a batch are held back until its
+    /// commit marker is read, and dropped if the log ends before it.
+    pub fn replay<P, F>(path: P, mut apply: F) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+        F: FnMut(&str, &str) -> Result<(), Error>,
+    {
+        let reader = BufReader::new(File::open(path)?);
+        let mut batch: Option<Vec<String>> = None;
+        for line in reader.lines() {
+            let line = line?;
+            match line.strip_prefix(':') {
+                Some(BATCH_BEGIN) => {
+                    if batch.replace(Vec::new()).is_some() {
+                        warn!("Dropping a batch torn from the Wal");
+                    }
+                    continue;
+                }
+                Some(BATCH_COMMIT) => {
+                    for line in batch.take().unwrap_or_default() {
+                        if let Some((key, value)) = line.split_once(':') {
+                            apply(key, value)?;
+                        }
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            if let Some(batch) = batch.as_mut() {
+                batch.push(line);
+            } else if let Some((key, value)) = line.split_once(':') {
+                apply(key, value)?;
+            }
+        }
+        if let Some(batch) = batch {
+            warn!(
+                "Dropping {} entries of a batch torn from the Wal",
+                batch.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// Force the entries appended so far to disk
+    pub fn sync(&self) -> Result<(), Error> {
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        file.flush()?;
+        file.get_ref().sync_data()?;
         Ok(())
     }
 
     /// reset the wal
-    pub fn reset(&self) -> Result<(), std::io::Error> {
-        let mut file = self.file.lock().unwrap();
+    pub fn reset(&self) -> Result<(), Error> {
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
         file.get_mut().set_len(0)?;
         file.flush()?;
+        if self.sync_policy == SyncPolicy::Always {
+            file.get_ref().sync_data()?;
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::storage::test_util::TempDir;
+
+    fn replayed(path: &Path) -> Vec<(String, String)> {
+        let mut entries = Vec::new();
+        Wal::replay(path, |key, value| {
+            entries.push((key.to_string(), value.to_string()));
+            Ok(())
+        })
+        .unwrap();
+        entries
+    }
+
+    fn entry(key: &str, value: &str) -> (String, String) {
+        (key.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn torn_batch_is_dropped_on_replay() {
+        let dir = TempDir::new("wal-torn");
+        fs::create_dir_all(dir.path()).unwrap();
+        let path = dir.path().join("wal.log");
+        let wal = Wal::new(&path, SyncPolicy::Never).unwrap();
+        wal.append("a", "1").unwrap();
+        wal.append_batch(&[entry("b", "2"), entry("c", "3")])
+            .unwrap();
+        wal.append_batch(&[entry("d", "4"), entry("e", "5")])
+            .unwrap();
+        drop(wal);
+        assert_eq!(
+            replayed(&path),
+            [
+                entry("a", "1"),
+                entry("b", "2"),
+                entry("c", "3"),
+                entry("d", "4"),
+                entry("e", "5")
+            ]
+        );
+
+        // Cut the log in the middle of the last batch, before its commit
+        let log = fs::read_to_string(&path).unwrap();
+        let torn = log.find("e:5").unwrap();
+        fs::write(&path, &log[..torn]).unwrap();
+        assert_eq!(
+            replayed(&path),
+            [entry("a", "1"), entry("b", "2"), entry("c", "3")]
+        );
+
+        // A batch begun after a torn one is replayed on its own
+        let wal = Wal::new(&path, SyncPolicy::Never).unwrap();
+        wal.append_batch(&[entry("f", "6")]).unwrap();
+        drop(wal);
+        assert_eq!(
+            replayed(&path),
+            [
+                entry("a", "1"),
+                entry("b", "2"),
+                entry("c", "3"),
+                entry("f", "6")
+            ]
+        );
+    }
+}